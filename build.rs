@@ -1,10 +1,22 @@
 use {
   ico::{IconDir, IconDirEntry, IconImage, ResourceType},
-  std::{env, fs::File, io},
+  std::{
+    env,
+    fs::File,
+    io,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+  },
   winresource::WindowsResource,
 };
 
 fn main() -> io::Result<()> {
+  // any rerun-if-changed replaces the default of rerunning on every change in the package
+  println!("cargo:rerun-if-changed=build.rs");
+  println!("cargo:rerun-if-changed=assets/icon.png");
+  emit_build_info();
+
   if env::var_os("CARGO_CFG_WINDOWS").is_some() {
     // parse the icon file and generate the icon
     let icon = generate_icon("assets/icon.png");
@@ -15,6 +27,60 @@ fn main() -> io::Result<()> {
   Ok(())
 }
 
+// named like the variables of `vergen`, read back with `env!` for `--version`
+fn emit_build_info() {
+  let timestamp = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|time| time.as_secs())
+    .unwrap_or_default();
+  println!(
+    "cargo:rustc-env=VERGEN_BUILD_TIMESTAMP={}",
+    format_utc(timestamp)
+  );
+
+  // source archives have no git, the build still goes on
+  let sha = Command::new("git")
+    .args(["rev-parse", "--short", "HEAD"])
+    .output()
+    .ok()
+    .filter(|output| output.status.success())
+    .and_then(|output| String::from_utf8(output.stdout).ok())
+    .map(|sha| sha.trim().to_string())
+    .unwrap_or_else(|| "unknown".to_string());
+  println!("cargo:rustc-env=VERGEN_GIT_SHA={}", sha);
+
+  // a new commit or checkout changes the sha. a path that doesn't exist would rerun every build
+  for path in [".git/HEAD", ".git/refs"] {
+    if Path::new(path).exists() {
+      println!("cargo:rerun-if-changed={}", path);
+    }
+  }
+}
+
+// RFC 3339 in UTC, converting days to a date as in http://howardhinnant.github.io/date_algorithms.html
+fn format_utc(secs: u64) -> String {
+  let days = (secs / 86400) as i64 + 719468;
+  let secs = secs % 86400;
+  let era = days.div_euclid(146097);
+  let day_of_era = days - era * 146097;
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month + 2) / 5 + 1;
+  let month = if month < 10 { month + 3 } else { month - 9 };
+  let year = year_of_era + era * 400 + (month <= 2) as i64;
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+    year,
+    month,
+    day,
+    secs / 3600,
+    secs / 60 % 60,
+    secs % 60
+  )
+}
+
 fn generate_icon(from: &str) -> &'static str {
   let icon = "assets/.favicon.ico";
 
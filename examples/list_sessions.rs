@@ -0,0 +1,34 @@
+use sound_priority::winmix::WinMix;
+
+// List the audio sessions of the default output device.
+fn main() {
+  let winmix = WinMix::default();
+  let device = winmix.get_default().expect("failed to get default device");
+
+  let name = device.get_name().unwrap_or_else(|_| "unknown".to_string());
+  let form_factor = device
+    .get_form_factor()
+    .map(|form_factor| format!("{:?}", form_factor))
+    .unwrap_or_else(|_| "unknown".to_string());
+  let master = device.master().and_then(|master| master.get_volume());
+  match master {
+    Ok(volume) => println!("{} [{}] (master {:.0}%)", name, form_factor, volume * 100.0),
+    Err(_) => println!("{} [{}]", name, form_factor),
+  }
+
+  let sessions = device.get_sessions().expect("failed to get sessions");
+  for session in sessions.iter() {
+    let volume = session.volume.get_volume().unwrap_or_default();
+    let peak = session.volume.get_peak().unwrap_or_default();
+    let muted = session.volume.get_mute().unwrap_or_default();
+
+    println!(
+      "{:>6}  {:<24} volume {:>3.0}%  peak {:.2}{}",
+      session.pid,
+      session.name,
+      volume * 100.0,
+      peak,
+      if muted { "  (muted)" } else { "" }
+    );
+  }
+}
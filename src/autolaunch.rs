@@ -0,0 +1,212 @@
+use std::{ffi::c_void, io, path::Path};
+
+use windows::{
+  core::HSTRING,
+  Win32::{
+    Foundation::{ERROR_FILE_NOT_FOUND, WIN32_ERROR},
+    System::Registry::{
+      RegCloseKey, RegGetValueW, RegOpenKeyExW, RegSetKeyValueW, HKEY, HKEY_CURRENT_USER, KEY_READ,
+      KEY_SET_VALUE, REG_SZ, RRF_RT_REG_SZ,
+    },
+  },
+};
+
+// where `auto_launch` registers the app
+const RUN_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+
+/// Point the startup entry `name` at `exe`, keeping the arguments it was registered with.
+///
+/// The entry is always rewritten, so this also checks that it is writable.
+pub fn repair(name: &str, exe: &Path) -> io::Result<()> {
+  let exe = exe.to_string_lossy();
+  let args = match registered_command(name) {
+    Ok(Some(command)) => {
+      let (path, args) = split_command(&command);
+      if !same_path(path, &exe) {
+        log::info!(
+          "[autolaunch] repaired startup entry from \"{}\" to \"{}\"",
+          path,
+          exe
+        );
+      }
+      args.to_string()
+    }
+    Ok(None) => String::new(),
+    Err(err) => {
+      log::warn!("[autolaunch] failed to read startup entry: {}", err);
+      String::new()
+    }
+  };
+
+  let mut command = format!("\"{}\"", exe);
+  if !args.is_empty() {
+    command.push(' ');
+    command.push_str(&args);
+  }
+  write_command(name, &command)
+}
+
+/// Check that startup entries can be read and written, without writing one.
+pub fn probe() -> io::Result<()> {
+  let mut key = HKEY::default();
+  unsafe {
+    check(RegOpenKeyExW(
+      HKEY_CURRENT_USER,
+      &HSTRING::from(RUN_KEY),
+      0,
+      KEY_READ | KEY_SET_VALUE,
+      &mut key,
+    ))?;
+    let _ = RegCloseKey(key);
+  }
+  Ok(())
+}
+
+// the command line registered for `name`, `None` when there is no entry
+fn registered_command(name: &str) -> io::Result<Option<String>> {
+  let key = HSTRING::from(RUN_KEY);
+  let name = HSTRING::from(name);
+  unsafe {
+    let mut size = 0_u32;
+    let result = RegGetValueW(
+      HKEY_CURRENT_USER,
+      &key,
+      &name,
+      RRF_RT_REG_SZ,
+      None,
+      None,
+      Some(&mut size),
+    );
+    if result == ERROR_FILE_NOT_FOUND {
+      return Ok(None);
+    }
+    check(result)?;
+
+    let mut buffer = vec![0_u16; (size as usize).div_ceil(2)];
+    let result = RegGetValueW(
+      HKEY_CURRENT_USER,
+      &key,
+      &name,
+      RRF_RT_REG_SZ,
+      None,
+      Some(buffer.as_mut_ptr() as *mut c_void),
+      Some(&mut size),
+    );
+    check(result)?;
+
+    let command = String::from_utf16_lossy(&buffer);
+    Ok(Some(command.trim_end_matches('\0').to_string()))
+  }
+}
+
+fn write_command(name: &str, command: &str) -> io::Result<()> {
+  let data = command.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+  let result = unsafe {
+    RegSetKeyValueW(
+      HKEY_CURRENT_USER,
+      &HSTRING::from(RUN_KEY),
+      &HSTRING::from(name),
+      REG_SZ.0,
+      Some(data.as_ptr() as *const c_void),
+      (data.len() * 2) as u32,
+    )
+  };
+  check(result)
+}
+
+fn check(result: WIN32_ERROR) -> io::Result<()> {
+  if result.is_ok() {
+    Ok(())
+  } else {
+    Err(io::Error::from_raw_os_error(result.0 as i32))
+  }
+}
+
+/// Split a registered command line into the executable and its arguments.
+///
+/// `auto_launch` writes the path unquoted, so an unquoted command is split after `.exe`.
+pub fn split_command(command: &str) -> (&str, &str) {
+  let command = command.trim();
+  if let Some(rest) = command.strip_prefix('"') {
+    return match rest.split_once('"') {
+      Some((path, args)) => (path, args.trim()),
+      None => (rest, ""),
+    };
+  }
+
+  let extension = command
+    .as_bytes()
+    .windows(4)
+    .position(|window| window.eq_ignore_ascii_case(b".exe"));
+  match extension {
+    Some(index) => {
+      let (path, args) = command.split_at(index + ".exe".len());
+      (path, args.trim())
+    }
+    None => (command, ""),
+  }
+}
+
+/// Whether two paths name the same file, Windows paths are case-insensitive.
+pub fn same_path(a: &str, b: &str) -> bool {
+  let normalize = |path: &str| {
+    path
+      .trim()
+      .trim_matches('"')
+      .replace('/', "\\")
+      .to_lowercase()
+  };
+  normalize(a) == normalize(b)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn split_quoted() {
+    assert_eq!(
+      split_command(r#""C:\Program Files\Sound Priority\sound-priority.exe" --headless"#),
+      (
+        r"C:\Program Files\Sound Priority\sound-priority.exe",
+        "--headless"
+      )
+    );
+    assert_eq!(
+      split_command(r#"  "C:\Apps\sound-priority.exe"  "#),
+      (r"C:\Apps\sound-priority.exe", "")
+    );
+    // an unclosed quote keeps the rest as the path
+    assert_eq!(
+      split_command(r#""C:\Apps\sound-priority.exe"#),
+      (r"C:\Apps\sound-priority.exe", "")
+    );
+  }
+
+  #[test]
+  fn split_unquoted_after_exe() {
+    assert_eq!(
+      split_command(r"C:\Program Files\Sound Priority\sound-priority.EXE --headless --verbose"),
+      (
+        r"C:\Program Files\Sound Priority\sound-priority.EXE",
+        "--headless --verbose"
+      )
+    );
+    assert_eq!(
+      split_command(r"C:\Apps\sound-priority"),
+      (r"C:\Apps\sound-priority", "")
+    );
+  }
+
+  #[test]
+  fn same_path_ignores_case_slashes_and_quotes() {
+    assert!(same_path(
+      r"C:\Program Files\Sound Priority\sound-priority.exe",
+      r#" "c:/program files/sound priority/Sound-Priority.EXE" "#
+    ));
+    assert!(!same_path(
+      r"C:\Apps\sound-priority.exe",
+      r"D:\Apps\sound-priority.exe"
+    ));
+  }
+}
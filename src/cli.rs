@@ -0,0 +1,384 @@
+use std::{fs, io::Write, time::Duration};
+
+use serde::Serialize;
+use single_instance::SingleInstance;
+
+use windows::Win32::{
+  System::Console::{AllocConsole, AttachConsole, GetConsoleWindow, ATTACH_PARENT_PROCESS},
+  UI::WindowsAndMessaging::{DeleteMenu, GetSystemMenu, MF_BYCOMMAND, SC_CLOSE},
+};
+
+use crate::{
+  config::{matches_name, Config},
+  pipe,
+  task::{self, Installed},
+  winmix::{device::UserFilter, session::Session, volume::MuteControl, WinMix},
+  APP_NAME,
+};
+
+// how long `--status` and `--set` wait for the running instance
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What `--status` prints.
+#[derive(Debug, Default, Serialize)]
+struct Status {
+  running: bool,
+  paused: bool,
+  ducked: bool,
+  /// The output device the daemon monitors.
+  device: Option<String>,
+  trigger: Option<String>,
+}
+
+/// Run a one-shot command from the command line, returning its exit code.
+///
+/// Returns `None` when the arguments aren't a one-shot command and the app should start.
+pub fn run(args: &[String]) -> Option<i32> {
+  if has_flag(args, "--version") {
+    return Some(print(None, &version()));
+  }
+  if let Some(assignment) = flag_value(args, "--set") {
+    return Some(set_config_field(assignment));
+  }
+  if has_flag(args, "--status") {
+    return Some(status());
+  }
+  if has_flag(args, "--install-task") {
+    return Some(install_task(has_flag(args, "--highest")));
+  }
+  if has_flag(args, "--uninstall-task") {
+    return Some(uninstall_task());
+  }
+  if has_flag(args, "--list-sessions") {
+    return Some(list_sessions(
+      flag_value(args, "--device"),
+      flag_value(args, "--output"),
+    ));
+  }
+  if let Some(name) = flag_value(args, "--set-volume") {
+    return Some(set_volume(name, flag_value_at(args, "--set-volume", 2)));
+  }
+  if let Some(name) = flag_value(args, "--mute") {
+    return Some(set_mute(name, flag_value_at(args, "--mute", 2)));
+  }
+  None
+}
+
+/// The version and the build it came from, for bug reports.
+pub fn version() -> String {
+  format!(
+    "{} {} ({}, built {})",
+    APP_NAME,
+    env!("CARGO_PKG_VERSION"),
+    env!("VERGEN_GIT_SHA"),
+    env!("VERGEN_BUILD_TIMESTAMP")
+  )
+}
+
+// `--status`, exits with 0 when running, 1 when paused, 2 when ducked, 3 when not running and 4
+// when running but not answering
+fn status() -> i32 {
+  let reply = pipe::request(r#"{"command":"query-status"}"#, STATUS_TIMEOUT);
+  let Some(reply) = reply.as_ref().and_then(|reply| reply.get("status")) else {
+    let running = is_running();
+    let status = Status {
+      running,
+      ..Default::default()
+    };
+    print(None, &serde_json::to_string(&status).unwrap());
+    return if running { 4 } else { 3 };
+  };
+
+  let status = Status {
+    running: true,
+    // muted output and Focus Assist pause the daemon too
+    paused: reply["paused"].as_bool().unwrap_or_default() || !reply["paused_by"].is_null(),
+    ducked: reply["status"] == "Reduce",
+    device: reply["device"].as_str().map(str::to_string),
+    trigger: reply["trigger"].as_str().map(str::to_string),
+  };
+  let code = match (status.paused, status.ducked) {
+    (true, _) => 1,
+    (false, true) => 2,
+    (false, false) => 0,
+  };
+  print(None, &serde_json::to_string(&status).unwrap());
+  code
+}
+
+// `--set <field>=<value>`, change the config of the running instance
+fn set_config_field(assignment: &str) -> i32 {
+  let Some((field, value)) = assignment.split_once('=') else {
+    print_error("usage: --set <field>=<value>");
+    return 2;
+  };
+  let field = field.trim();
+  // checked here, so a typo is reported without a running instance
+  let value = match Config::default().quick_apply(field, value) {
+    Ok(config) => serde_json::to_value(config).expect("failed to serialize config")[field].take(),
+    Err(err) => {
+      print_error(&err.to_string());
+      return 2;
+    }
+  };
+  let request =
+    serde_json::json!({ "command": "set-config-field", "field": field, "value": value });
+  let Some(reply) = pipe::request(&request.to_string(), STATUS_TIMEOUT) else {
+    return not_answering();
+  };
+  if reply["ok"].as_bool().unwrap_or_default() {
+    return print(None, &format!("set {} to {}", field, value));
+  }
+  let error = reply["error"].as_str().unwrap_or("unknown error");
+  print_error(&format!("failed to set {}: {}", field, error));
+  1
+}
+
+// `--install-task [--highest]`, start headless at logon through the Task Scheduler
+fn install_task(highest: bool) -> i32 {
+  let exe = match std::env::current_exe() {
+    Ok(exe) => exe,
+    Err(err) => {
+      print_error(&format!("failed to find the exe: {}", err));
+      return 1;
+    }
+  };
+  match task::install(&exe, highest) {
+    Ok(installed) => {
+      let action = match installed {
+        Installed::Created => "created",
+        Installed::Updated => "updated",
+      };
+      print(
+        None,
+        &format!(
+          "{} the logon task \"{}\" running {} --headless",
+          action,
+          APP_NAME,
+          exe.display()
+        ),
+      )
+    }
+    Err(err) => {
+      print_error(&format!("failed to install the logon task: {}", err));
+      1
+    }
+  }
+}
+
+// `--uninstall-task`
+fn uninstall_task() -> i32 {
+  match task::uninstall() {
+    Ok(true) => print(None, &format!("removed the logon task \"{}\"", APP_NAME)),
+    Ok(false) => print(None, "there is no logon task to remove"),
+    Err(err) => {
+      print_error(&format!("failed to remove the logon task: {}", err));
+      1
+    }
+  }
+}
+
+// `--set-volume <app> <0.0-1.0>`
+fn set_volume(name: &str, level: Option<&str>) -> i32 {
+  let level = level.and_then(|level| level.parse::<f32>().ok());
+  let Some(level) = level.filter(|level| level.is_finite() && (0.0..=1.0).contains(level)) else {
+    print_error("usage: --set-volume <app> <0.0-1.0>");
+    return 2;
+  };
+  apply_to_app(name, |session| {
+    session.volume.set_volume(level)?;
+    Ok(format!(
+      "{} ({}) volume {:.0}%",
+      session.name,
+      session.pid,
+      level * 100.0
+    ))
+  })
+}
+
+// `--mute <app> on|off|toggle`
+fn set_mute(name: &str, state: Option<&str>) -> i32 {
+  let mute = match state {
+    Some("on") => Some(true),
+    Some("off") => Some(false),
+    Some("toggle") => None,
+    _ => {
+      print_error("usage: --mute <app> on|off|toggle");
+      return 2;
+    }
+  };
+  apply_to_app(name, |session| {
+    let muted = match mute {
+      Some(mute) => session.volume.set_mute(mute).map(|_| mute)?,
+      None => session.volume.set_mute_toggle()?,
+    };
+    let state = if muted { "muted" } else { "unmuted" };
+    Ok(format!("{} ({}) {}", session.name, session.pid, state))
+  })
+}
+
+// run `apply` on every session of the default device matching `name`, like the daemon matches targets
+fn apply_to_app(
+  name: &str,
+  apply: impl Fn(&Session) -> Result<String, windows_result::Error>,
+) -> i32 {
+  let winmix = WinMix::default();
+  let device = match winmix.get_default() {
+    Ok(device) => device,
+    Err(err) => {
+      print_error(&format!("failed to open the default device: {}", err));
+      return 1;
+    }
+  };
+
+  let sessions = device.get_sessions_matching(|session| matches_name(&session.name, name));
+  if sessions.is_empty() {
+    print_error(&format!("no session matches {}", name));
+    return 1;
+  }
+
+  let mut code = 0;
+  for session in sessions.iter() {
+    match apply(session) {
+      Ok(message) => {
+        print(None, &message);
+      }
+      Err(err) => {
+        print_error(&format!("failed to change {}: {}", session.name, err));
+        code = 1;
+      }
+    }
+  }
+  code
+}
+
+// print the state of the default device, or the one given by `--device`, as JSON
+fn list_sessions(device: Option<&str>, output: Option<&str>) -> i32 {
+  let winmix = WinMix::default();
+  let device = match device {
+    Some(id) => winmix.get_device(id),
+    None => winmix.get_default(),
+  };
+  let info = device.and_then(|device| device.describe());
+
+  match info {
+    Ok(mut info) => {
+      // the daemon doesn't see them either
+      if Config::load().unwrap_or_default().same_user_only {
+        let mut users = UserFilter::current();
+        info
+          .sessions
+          .retain(|session| users.is_same_user(session.pid));
+      }
+      let json = serde_json::to_string_pretty(&info).expect("failed to serialize sessions");
+      print(output, &json)
+    }
+    Err(err) => {
+      print_error(&format!("failed to read sessions: {}", err));
+      1
+    }
+  }
+}
+
+// whether an instance holds the single instance lock, for telling a missing instance apart
+// from one that doesn't answer
+fn is_running() -> bool {
+  SingleInstance::new(APP_NAME).is_ok_and(|instance| !instance.is_single())
+}
+
+// the exit code when the pipe got no reply, 3 when nothing is running
+fn not_answering() -> i32 {
+  if is_running() {
+    print_error("Sound Priority is running but not answering");
+    1
+  } else {
+    print_error("Sound Priority is not running");
+    3
+  }
+}
+
+/// Whether to run without the tray icon, see `Config::headless`.
+pub fn is_headless(args: &[String]) -> bool {
+  has_flag(args, "--headless")
+}
+
+/// Whether to log to a console as well, at debug level.
+pub fn is_verbose(args: &[String]) -> bool {
+  has_flag(args, "--verbose")
+}
+
+/// Open a console window of its own for `--verbose`, unless there already is one.
+///
+/// Closing a console ends every process on it, so the window gets no close button. It goes away
+/// with the app.
+pub fn open_console() {
+  unsafe {
+    if AllocConsole().is_err() {
+      return;
+    }
+    let window = GetConsoleWindow();
+    if window.0 != 0 {
+      let _ = DeleteMenu(GetSystemMenu(window, false), SC_CLOSE, MF_BYCOMMAND);
+    }
+  }
+}
+
+fn has_flag(args: &[String], flag: &str) -> bool {
+  args.iter().any(|arg| arg == flag)
+}
+
+// the argument following `flag`
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+  flag_value_at(args, flag, 1)
+}
+
+// the argument `offset` places after `flag`
+fn flag_value_at<'a>(args: &'a [String], flag: &str, offset: usize) -> Option<&'a str> {
+  let index = args.iter().position(|arg| arg == flag)?;
+  args.get(index + offset).map(|value| value.as_str())
+}
+
+// write to `output`, or to the terminal the app was started from
+fn print(output: Option<&str>, text: &str) -> i32 {
+  if let Some(path) = output {
+    return match fs::write(path, text) {
+      Ok(()) => 0,
+      Err(err) => {
+        print_error(&format!("failed to write {}: {}", path, err));
+        1
+      }
+    };
+  }
+
+  attach_console();
+  let mut stdout = std::io::stdout();
+  match writeln!(stdout, "{}", text) {
+    Ok(()) => 0,
+    Err(_) => 1,
+  }
+}
+
+fn print_error(message: &str) {
+  attach_console();
+  eprintln!("{}", message);
+}
+
+// release builds have no console of their own
+fn attach_console() {
+  unsafe {
+    let _ = AttachConsole(ATTACH_PARENT_PROCESS);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn set_volume_rejects_levels_out_of_range() {
+    for level in ["1.5", "-0.1", "NaN", "inf", "loud"] {
+      assert_eq!(set_volume("app", Some(level)), 2, "{}", level);
+    }
+    assert_eq!(set_volume("app", None), 2);
+  }
+}
@@ -0,0 +1,68 @@
+use std::{
+  sync::{Arc, Mutex},
+  thread,
+  time::{Duration, Instant},
+};
+
+/// The time source driving the daemon loop.
+pub trait Clock: Send + 'static {
+  /// Time elapsed since the clock was created.
+  fn now(&self) -> Duration;
+  /// Wait for `duration` to pass.
+  fn sleep(&self, duration: Duration);
+}
+
+/// The wall clock, sleeping the thread for real.
+#[derive(Debug, Clone)]
+pub struct SystemClock {
+  start: Instant,
+}
+
+impl SystemClock {
+  pub fn new() -> Self {
+    Self {
+      start: Instant::now(),
+    }
+  }
+}
+
+impl Default for SystemClock {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Clock for SystemClock {
+  fn now(&self) -> Duration {
+    self.start.elapsed()
+  }
+  fn sleep(&self, duration: Duration) {
+    thread::sleep(duration);
+  }
+}
+
+/// A clock that only moves when told to, for testing the daemon's timing.
+///
+/// Sleeping advances the clock instantly, clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+  now: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  pub fn advance(&self, duration: Duration) {
+    *self.now.lock().unwrap() += duration;
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> Duration {
+    *self.now.lock().unwrap()
+  }
+  fn sleep(&self, duration: Duration) {
+    self.advance(duration);
+  }
+}
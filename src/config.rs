@@ -1,15 +1,397 @@
-use std::{env::current_exe, fs, path::PathBuf};
+use std::{
+  collections::{BTreeMap, HashSet},
+  env::current_exe,
+  fmt, fs,
+  io::{self, BufWriter},
+  path::{Path, PathBuf},
+  sync::OnceLock,
+  time::{SystemTime, UNIX_EPOCH},
+};
 
 use serde::{Deserialize, Serialize};
+use windows::{
+  core::HSTRING,
+  Win32::Storage::FileSystem::{MoveFileExW, MOVEFILE_REPLACE_EXISTING, MOVEFILE_WRITE_THROUGH},
+};
 
+use crate::notify::{self, Category};
+
+/// Missing fields fall back to their default, so older config files keep loading.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
   pub exclude: Vec<String>,
   pub targets: Vec<String>,
+  /// Kinds of audio that never reduce the volume, on top of `exclude`.
+  pub exclude_categories: Vec<AudioCategory>,
 
   pub resotre_volume: f32,
   pub reduce_volume: f32,
   pub sensitivity: f32,
+
+  /// Show the volume and sensitivity menus in 5% steps instead of 10%.
+  pub fine_slider: bool,
+
+  /// Volume change per tick while reducing.
+  pub attack_speed: f32,
+  /// Volume change per tick while restoring.
+  pub release_speed: f32,
+
+  /// Overrides keyed by target, matched like `targets`.
+  pub target_rules: BTreeMap<String, TargetRule>,
+
+  pub restore_target: RestoreTarget,
+
+  pub detection: DetectionConfig,
+  /// Milliseconds to hold the highest peak before it decays, like the needle of a meter.
+  /// `0` compares the sensitivity to the peak as it is.
+  pub peak_hold_ms: u64,
+  /// Moved to `detection.source`, only read to migrate older files.
+  #[serde(skip_serializing)]
+  peak_source: Option<PeakSource>,
+
+  /// Leave the app in the foreground alone. It never reduces the volume, and a target isn't
+  /// reduced while it's in front, it gets its volume back when brought there.
+  pub exclude_foreground: bool,
+
+  /// Do nothing while the output device is muted, fades can't be heard anyway.
+  pub pause_while_muted: bool,
+
+  /// Restore the targets while the screen is locked or the system sleeps.
+  pub restore_while_away: bool,
+
+  /// Do nothing while Focus Assist is on or Windows is in presentation mode.
+  pub pause_on_focus_assist: bool,
+
+  /// Ignore the audio of apps whose media controls say they are paused or stopped.
+  pub use_smtc: bool,
+
+  /// Reduce every app except the excluded ones, which become the only ones that trigger it.
+  pub apply_to_all_sessions: bool,
+
+  /// Never reduce this app's own sessions, even when they are listed as a target.
+  pub exclude_self: bool,
+
+  /// Ignore the sessions of apps run by other users, e.g. on a shared machine.
+  pub same_user_only: bool,
+
+  /// Set Windows' own ducking during calls to "do nothing" while running, so calls don't reduce
+  /// the targets twice. The user's setting is put back on exit. Read at startup.
+  pub disable_system_ducking: bool,
+
+  /// Mute the default microphone while a target plays, so it doesn't pick the target up.
+  pub mute_mic_while_targets_play: bool,
+
+  /// Keep the volumes of targets across restarts, to undo a reduction left by a crash.
+  pub persist_app_volumes: bool,
+
+  /// Seconds to wait after launch before monitoring starts.
+  pub startup_delay_secs: u64,
+
+  /// Minimum milliseconds between volume writes to the same app, for drivers that glitch
+  /// under fast fades. `0` writes on every tick.
+  pub min_write_interval_ms: u64,
+
+  /// Milliseconds after which a fade that is still going jumps to its volume, so a very low
+  /// speed can't leave the targets fading for ages. `0` lets fades take as long as they take.
+  pub max_fade_ms: u64,
+
+  /// Ticks a session has to exist before it can reduce the volume, to ignore short UI sounds.
+  pub min_session_age_ticks: usize,
+
+  /// Sessions polled for their peak on each tick, the most recently active first.
+  /// Every session is still polled now and then, so a quiet one can become active.
+  pub max_peak_sessions: usize,
+
+  pub notifications: NotificationConfig,
+
+  /// Look for a new release once a day.
+  pub check_for_updates: bool,
+
+  pub hotkeys: HotkeyConfig,
+
+  /// Custom tray icons, read at startup.
+  pub icons: IconConfig,
+
+  /// Serve the status on this port of 127.0.0.1, read at startup.
+  pub http_port: Option<u16>,
+
+  /// Run without the tray icon, controlled only by the command line and the pipe. Read at startup,
+  /// same as `--headless`.
+  pub headless: bool,
+
+  /// Notify when an app that is neither a target nor excluded starts playing audio, the first
+  /// time it plays above `sensitivity`, with buttons to add it as a target or exclude it.
+  pub notify_on_new_session: bool,
+
+  /// Append the peak and state to `metrics.csv` once a second, for tuning `sensitivity`.
+  pub record_metrics: bool,
+
+  /// Rotate `sound-priority.log` to `sound-priority.log.1` once it grows past this.
+  pub log_max_size_kb: u64,
+  /// Old logs to keep, `0` starts over instead.
+  pub log_rotations: usize,
+  /// Logs of previous runs to keep, as `sound-priority.<started>.log`.
+  pub log_runs: usize,
+
+  /// Write the peak and state to a CSV every `peak_log_interval_ticks`, emptied on each run.
+  pub log_peak_to_file: bool,
+  /// Defaults to `peaks.csv` in the state directory, e.g. `--set peak_log_path=D:\peaks.csv`.
+  pub peak_log_path: Option<String>,
+  pub peak_log_interval_ticks: u32,
+
+  /// Overrides keyed by endpoint id, or by a part of the device name.
+  pub devices: BTreeMap<String, PartialConfig>,
+}
+
+/// Overrides for a single device, missing fields use the global value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PartialConfig {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub resotre_volume: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reduce_volume: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sensitivity: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub restore_target: Option<RestoreTarget>,
+}
+
+/// Overrides for a single target, missing fields use the global value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TargetRule {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reduce_volume: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub attack_speed: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub release_speed: Option<f32>,
+}
+
+/// System wide hotkeys like `Ctrl+Alt+P`, `None` or empty leaves one unregistered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HotkeyConfig {
+  /// Pause or resume.
+  pub pause: Option<String>,
+}
+
+impl Default for HotkeyConfig {
+  fn default() -> Self {
+    Self {
+      pause: Some("Ctrl+Alt+P".to_string()),
+    }
+  }
+}
+
+/// Tray icon files per state, `None` or a file that fails to load keeps the built-in icon.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IconConfig {
+  /// While the targets are reduced.
+  pub active: Option<PathBuf>,
+  /// While nothing is reduced, or paused.
+  pub idle: Option<PathBuf>,
+}
+
+/// Which notifications are shown, `enabled` turns all of them off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationConfig {
+  pub enabled: bool,
+  pub on_duck: bool,
+  pub on_error: bool,
+  pub on_device_change: bool,
+  pub on_update_available: bool,
+}
+
+impl Default for NotificationConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      on_duck: false,
+      on_error: true,
+      on_device_change: false,
+      on_update_available: false,
+    }
+  }
+}
+
+// well known apps, for guessing the category of a session
+const CHAT_APPS: [&str; 8] = [
+  "discord",
+  "teams",
+  "zoom",
+  "skype",
+  "slack",
+  "mumble",
+  "teamspeak",
+  "telegram",
+];
+const MEDIA_APPS: [&str; 8] = [
+  "spotify",
+  "vlc",
+  "foobar2000",
+  "musicbee",
+  "itunes",
+  "wmplayer",
+  "mpc-hc",
+  "aimp",
+];
+
+/// What kind of audio a session plays.
+///
+/// Windows doesn't expose the stream category another process picked for its session,
+/// so this is guessed from the app name, which has to be one of the well known apps exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioCategory {
+  /// Voice chat and calls.
+  Chat,
+  /// Music and video players.
+  Media,
+  /// Windows system sounds.
+  System,
+  Other,
+}
+
+impl AudioCategory {
+  pub fn of(name: &str) -> Self {
+    let name = normalize_name(name);
+    if name == "$system" {
+      AudioCategory::System
+    } else if CHAT_APPS.contains(&name.as_str()) {
+      AudioCategory::Chat
+    } else if MEDIA_APPS.contains(&name.as_str()) {
+      AudioCategory::Media
+    } else {
+      AudioCategory::Other
+    }
+  }
+}
+
+/// How the daemon turns the audio of the other apps into the peak compared with `sensitivity`.
+///
+/// The defaults detect the same way as before these settings existed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DetectionConfig {
+  pub source: PeakSource,
+  /// How the peaks of several apps add up, only used with `PeakSource::SessionAggregated`.
+  pub aggregation: Aggregation,
+  /// Peaks at or below this count as silence, for apps that hum while idle.
+  pub noise_floor: f32,
+  /// Stretch the range above `noise_floor` back to `0.0..=1.0`, so `sensitivity` keeps its scale.
+  pub normalize: bool,
+  /// How much of the previous peak carries over to the next tick, `0.0` reacts right away.
+  pub smoothing: f32,
+}
+
+impl DetectionConfig {
+  /// Apply the noise floor, and normalize when asked to.
+  pub fn clean(&self, peak: f32) -> f32 {
+    let floor = self.noise_floor.clamp(0.0, 1.0);
+    if peak <= floor {
+      return 0.0;
+    }
+    if self.normalize && floor < 1.0 {
+      (peak - floor) / (1.0 - floor)
+    } else {
+      peak
+    }
+  }
+  /// Blend the peak of this tick into the `previous` one.
+  pub fn smooth(&self, previous: f32, peak: f32) -> f32 {
+    let smoothing = self.smoothing.clamp(0.0, 1.0);
+    previous * smoothing + peak * (1.0 - smoothing)
+  }
+}
+
+/// How the peaks of several apps combine into one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregation {
+  /// The loudest app.
+  #[default]
+  Max,
+  /// All apps together, capped at `1.0`, so several quiet apps can reduce the volume.
+  Sum,
+}
+
+/// Where the daemon reads how loud the other apps are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PeakSource {
+  /// The loudest session that is neither a target nor excluded.
+  #[default]
+  SessionAggregated,
+  /// The meter of the output device, without enumerating sessions.
+  ///
+  /// Targets and excluded apps count too, so any audio reduces the volume. Lighter with many
+  /// sessions: `SessionAggregated` reads one meter per session every tick, this reads the one of
+  /// the device, and the sessions only while the output is above the sensitivity. Not measured,
+  /// but with a quiet output that is one COM call per tick instead of one per session.
+  EndpointMeter,
+  /// The audio captured from the output device, with the same caveat as `EndpointMeter`.
+  Loopback,
+}
+
+impl PeakSource {
+  /// The next source, for cycling through them in the menu.
+  pub fn next(self) -> Self {
+    match self {
+      PeakSource::SessionAggregated => PeakSource::EndpointMeter,
+      PeakSource::EndpointMeter => PeakSource::Loopback,
+      PeakSource::Loopback => PeakSource::SessionAggregated,
+    }
+  }
+  pub fn label(self) -> &'static str {
+    match self {
+      PeakSource::SessionAggregated => "Apps",
+      PeakSource::EndpointMeter => "Output Meter",
+      PeakSource::Loopback => "Loopback",
+    }
+  }
+}
+
+/// Where target volumes go back to once the other apps are quiet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestoreTarget {
+  /// Restore to 100%.
+  Full,
+  /// Restore to the volume captured right before reducing.
+  Previous,
+  /// Restore to `resotre_volume`.
+  #[default]
+  Fixed,
+}
+
+/// A config that loads fine but can't work as intended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigIssue {
+  /// Reducing would make targets louder.
+  ReduceAboveRestore,
+  /// Any sound at all reduces the volume.
+  ZeroSensitivity,
+  /// There is nothing to reduce.
+  NoTargets,
+}
+
+impl ConfigIssue {
+  /// A stable identifier, for logs and menu ids.
+  pub fn id(&self) -> &'static str {
+    match self {
+      ConfigIssue::ReduceAboveRestore => "reduce_above_restore",
+      ConfigIssue::ZeroSensitivity => "zero_sensitivity",
+      ConfigIssue::NoTargets => "no_targets",
+    }
+  }
+  pub fn message(&self) -> String {
+    match self {
+      ConfigIssue::ReduceAboveRestore => "Reduce volume is above restore volume".to_string(),
+      ConfigIssue::ZeroSensitivity => "Sensitivity is 0%, any sound reduces the volume".to_string(),
+      ConfigIssue::NoTargets => "No targets selected".to_string(),
+    }
+  }
 }
 
 impl Config {
@@ -17,28 +399,326 @@ impl Config {
     Self {
       exclude: vec![],
       targets: vec![],
+      exclude_categories: vec![],
       resotre_volume: 1.0,
       reduce_volume: 0.5,
       sensitivity: 0.1,
+      fine_slider: false,
+      attack_speed: 0.05,
+      release_speed: 0.05,
+      target_rules: BTreeMap::new(),
+      restore_target: RestoreTarget::default(),
+      detection: DetectionConfig::default(),
+      peak_hold_ms: 0,
+      peak_source: None,
+      exclude_foreground: false,
+      use_smtc: false,
+      pause_while_muted: false,
+      restore_while_away: false,
+      pause_on_focus_assist: false,
+      apply_to_all_sessions: false,
+      exclude_self: true,
+      same_user_only: true,
+      disable_system_ducking: false,
+      mute_mic_while_targets_play: false,
+      persist_app_volumes: true,
+      startup_delay_secs: 0,
+      min_write_interval_ms: 0,
+      max_fade_ms: 10_000,
+      min_session_age_ticks: 0,
+      max_peak_sessions: usize::MAX,
+      notifications: NotificationConfig::default(),
+      check_for_updates: false,
+      notify_on_new_session: false,
+      record_metrics: false,
+      log_max_size_kb: 5 * 1024,
+      log_rotations: 3,
+      log_runs: 10,
+      log_peak_to_file: false,
+      peak_log_path: None,
+      peak_log_interval_ticks: 10,
+      http_port: None,
+      headless: false,
+      hotkeys: HotkeyConfig::default(),
+      icons: IconConfig::default(),
+      devices: BTreeMap::new(),
+    }
+  }
+  /// Whether the daemon leaves an app alone when reducing.
+  pub fn is_excluded(&self, name: &str) -> bool {
+    self
+      .exclude
+      .iter()
+      .any(|exclude| matches_name(name, exclude))
+      || self.exclude_categories.contains(&AudioCategory::of(name))
+  }
+  /// Whether the daemon reduces an app, never this one with `exclude_self`.
+  pub fn is_target(&self, name: &str) -> bool {
+    if self.exclude_self && is_own_name(name) {
+      return false;
+    }
+    if self.apply_to_all_sessions {
+      return !self.is_excluded(name);
     }
+    self.targets.iter().any(|target| matches_name(name, target))
   }
+  /// The rule for a target with every field filled in from the global values.
+  pub fn target_rule(&self, name: &str) -> TargetRule {
+    let rule = self
+      .target_rules
+      .iter()
+      .find(|(pattern, _)| matches_name(name, pattern))
+      .map(|(_, rule)| rule.clone())
+      .unwrap_or_default();
+    TargetRule {
+      reduce_volume: rule.reduce_volume.or(Some(self.reduce_volume)),
+      attack_speed: rule.attack_speed.or(Some(self.attack_speed)),
+      release_speed: rule.release_speed.or(Some(self.release_speed)),
+    }
+  }
+  /// Resolve the config for a device, along with the section that applied.
+  ///
+  /// A section keyed by the endpoint id wins over one matching the device name.
+  pub fn for_device(&self, id: &str, name: &str) -> (Config, Option<&str>) {
+    let section = self.devices.get_key_value(id).or_else(|| {
+      self
+        .devices
+        .iter()
+        .find(|(key, _)| !key.is_empty() && name.contains(key.as_str()))
+    });
+
+    let mut config = self.clone();
+    let Some((key, overrides)) = section else {
+      return (config, None);
+    };
+
+    if let Some(volume) = overrides.resotre_volume {
+      config.resotre_volume = volume;
+    }
+    if let Some(volume) = overrides.reduce_volume {
+      config.reduce_volume = volume;
+    }
+    if let Some(sensitivity) = overrides.sensitivity {
+      config.sensitivity = sensitivity;
+    }
+    if let Some(restore_target) = overrides.restore_target {
+      config.restore_target = restore_target;
+    }
+    (config, Some(key.as_str()))
+  }
+  /// Load the config, falling back to the backup when it is broken.
   pub fn load() -> Option<Self> {
-    let path = Self::path();
-    if !path.exists() {
-      return None;
+    match Self::try_load() {
+      Ok(config) => config,
+      Err(err) => {
+        log::error!("[config] {}", err);
+        notify::show(Category::Error, "Config is broken", &err.to_string());
+        let backup = read(&Self::backup_path()).ok().flatten();
+        if backup.is_some() {
+          log::info!("[config] loaded the backup config");
+        }
+        backup
+      }
     }
-    let file = fs::File::open(path).expect("Failed to open config config file");
-    serde_json::from_reader(file).ok()
   }
-  pub fn save(&self) -> std::io::Result<()> {
+  /// Like `load`, but tells a missing file apart from one that can't be read.
+  ///
+  /// A file that can't be parsed is moved aside, so it isn't overwritten on the next save.
+  pub fn try_load() -> Result<Option<Self>, LoadError> {
     let path = Self::path();
-    let json = serde_json::to_vec(self).expect("Failed to serialize config config");
-    fs::write(path, json)
+    read(&path).map_err(|error| {
+      let is_syntax = error.is_syntax() || error.is_data() || error.is_eof();
+      let preserved = is_syntax.then(|| preserve_broken(&path)).flatten();
+      LoadError { error, preserved }
+    })
+  }
+  /// A copy with one top level field set from text, for the command line, e.g.
+  /// `config.quick_apply("sensitivity", "0.15")`.
+  ///
+  /// Numbers, switches, names and paths are written as they are, anything else as JSON.
+  pub fn quick_apply(&self, field: &str, value_str: &str) -> Result<Config, ConfigError> {
+    let mut json = serde_json::to_value(self).expect("failed to serialize config");
+    let Some(slot) = json.get_mut(field) else {
+      return Err(ConfigError::UnknownField(field.to_string()));
+    };
+    let invalid = |reason: String| ConfigError::InvalidValue {
+      field: field.to_string(),
+      reason,
+    };
+    let value_str = value_str.trim();
+    *slot = match *slot {
+      // parsed as JSON rather than `f32`, so integers stay integers
+      serde_json::Value::Number(_) => value_str
+        .parse::<serde_json::Number>()
+        .map(serde_json::Value::Number)
+        .map_err(|err| invalid(err.to_string()))?,
+      serde_json::Value::Bool(_) => match value_str {
+        "true" | "on" | "1" => serde_json::Value::Bool(true),
+        "false" | "off" | "0" => serde_json::Value::Bool(false),
+        _ => return Err(invalid("expected true or false".to_string())),
+      },
+      // a quoted name is JSON already
+      serde_json::Value::String(_) if !value_str.starts_with('"') => {
+        serde_json::Value::String(value_str.to_string())
+      }
+      // unset options, most of them are paths
+      serde_json::Value::Null => serde_json::from_str(value_str)
+        .unwrap_or_else(|_| serde_json::Value::String(value_str.to_string())),
+      _ => serde_json::from_str(value_str).map_err(|err| invalid(err.to_string()))?,
+    };
+    // integers don't take `1.0`, and enums check their names here
+    serde_json::from_value(json).map_err(|err| invalid(err.to_string()))
+  }
+  /// Settings that are valid on their own but broken together.
+  pub fn issues(&self) -> Vec<ConfigIssue> {
+    let mut issues = vec![];
+    if self.reduce_volume > self.resotre_volume {
+      issues.push(ConfigIssue::ReduceAboveRestore);
+    }
+    if self.sensitivity <= 0.0 {
+      issues.push(ConfigIssue::ZeroSensitivity);
+    }
+    if self.targets.is_empty() && !self.apply_to_all_sessions {
+      issues.push(ConfigIssue::NoTargets);
+    }
+    // an app that is both a target and excluded is fixed by `normalize` instead
+    issues
+  }
+  // move fields that older versions kept at the top level
+  fn migrate(&mut self) {
+    if let Some(peak_source) = self.peak_source.take() {
+      log::info!("[config] moved peak_source to detection.source");
+      self.detection.source = peak_source;
+    }
+  }
+  /// Trim and dedupe the app lists, an app that is both a target and excluded stays a target.
+  pub fn normalize(&mut self) {
+    let targets = dedupe_names(&mut self.targets);
+    let exclude = dedupe_names(&mut self.exclude);
+
+    let before = self.exclude.len();
+    self
+      .exclude
+      .retain(|name| !self.targets.iter().any(|target| same_name(target, name)));
+    let both = before - self.exclude.len();
+
+    if targets + exclude + both > 0 {
+      log::info!(
+        "[config] cleaned app lists: {} duplicate targets, {} duplicate excludes, {} excludes also targeted",
+        targets,
+        exclude,
+        both
+      );
+    }
+  }
+  /// Write the config to a temporary file first, so a crash mid-save can't leave it truncated.
+  pub fn save(&self) -> io::Result<()> {
+    self.save_to(&Self::path())
+  }
+  /// Like `save`, but to `path`, with the backup next to it.
+  pub fn save_to(&self, path: &Path) -> io::Result<()> {
+    // keep the last saved config, in case this one gets broken
+    if path.exists() {
+      fs::copy(path, path.with_extension("json.bak"))?;
+    }
+
+    let temp = path.with_extension("json.tmp");
+    let mut writer = BufWriter::new(fs::File::create(&temp)?);
+    serde_json::to_writer(&mut writer, self).expect("Failed to serialize config config");
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    drop(file);
+    replace_file(&temp, path)
   }
   pub fn path() -> PathBuf {
     let path = current_exe().expect("Failed to get exe path");
     path.parent().unwrap().to_path_buf().join("config.json")
   }
+  pub fn backup_path() -> PathBuf {
+    Self::path().with_extension("json.bak")
+  }
+}
+
+/// A config file that exists but can't be read.
+#[derive(Debug)]
+pub struct LoadError {
+  pub error: serde_json::Error,
+  /// Where the broken file was moved to.
+  pub preserved: Option<PathBuf>,
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "failed to read config: {}", self.error)?;
+    if let Some(preserved) = &self.preserved {
+      write!(f, ", the file was kept as {}", preserved.display())?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Why `Config::quick_apply` rejected a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+  UnknownField(String),
+  InvalidValue { field: String, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConfigError::UnknownField(field) => write!(f, "unknown field {}", field),
+      ConfigError::InvalidValue { field, reason } => {
+        write!(f, "invalid value for {}: {}", field, reason)
+      }
+    }
+  }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn read(path: &Path) -> Result<Option<Config>, serde_json::Error> {
+  if !path.exists() {
+    return Ok(None);
+  }
+  let file = fs::File::open(path).map_err(serde_json::Error::io)?;
+  let mut config: Config = serde_json::from_reader(file)?;
+  config.migrate();
+  config.normalize();
+  Ok(Some(config))
+}
+
+// move `from` over `to`, on disk by the time it returns
+fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+  unsafe {
+    MoveFileExW(
+      &HSTRING::from(from),
+      &HSTRING::from(to),
+      MOVEFILE_REPLACE_EXISTING | MOVEFILE_WRITE_THROUGH,
+    )?;
+  }
+  Ok(())
+}
+
+// move a broken config out of the way, returning where it went
+fn preserve_broken(path: &Path) -> Option<PathBuf> {
+  let timestamp = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|time| time.as_secs())
+    .unwrap_or_default();
+  let mut broken = path.as_os_str().to_owned();
+  broken.push(format!(".broken-{}", timestamp));
+  let broken = PathBuf::from(broken);
+
+  match fs::rename(path, &broken) {
+    Ok(()) => Some(broken),
+    Err(err) => {
+      log::error!("[config] failed to move the broken config aside: {}", err);
+      None
+    }
+  }
 }
 
 impl Default for Config {
@@ -46,3 +726,42 @@ impl Default for Config {
     Self::new()
   }
 }
+
+/// The form app names are compared in, shared by the config, the daemon and the menu.
+pub fn normalize_name(name: &str) -> String {
+  name.trim().to_lowercase()
+}
+
+/// Whether two configured names refer to the same app.
+pub fn same_name(a: &str, b: &str) -> bool {
+  normalize_name(a) == normalize_name(b)
+}
+
+/// Whether `name` is the name of this app's sessions.
+pub fn is_own_name(name: &str) -> bool {
+  static OWN_NAME: OnceLock<Option<String>> = OnceLock::new();
+  OWN_NAME
+    .get_or_init(|| {
+      let path = current_exe().ok()?;
+      Some(path.file_stem()?.to_string_lossy().to_string())
+    })
+    .as_deref()
+    .is_some_and(|own| same_name(own, name))
+}
+
+/// Whether a session named `name` is matched by the configured `pattern`.
+pub fn matches_name(name: &str, pattern: &str) -> bool {
+  let pattern = normalize_name(pattern);
+  !pattern.is_empty() && normalize_name(name).contains(&pattern)
+}
+
+// trim names and drop empty or duplicated ones, keeping the first spelling
+fn dedupe_names(list: &mut Vec<String>) -> usize {
+  let before = list.len();
+  let mut seen = HashSet::new();
+  list.retain_mut(|name| {
+    *name = name.trim().to_string();
+    !name.is_empty() && seen.insert(normalize_name(name))
+  });
+  before - list.len()
+}
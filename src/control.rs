@@ -0,0 +1,329 @@
+use std::{
+  slice,
+  sync::{
+    atomic::{AtomicIsize, Ordering},
+    Mutex,
+  },
+};
+
+use windows::{
+  core::{w, PCWSTR},
+  Win32::{
+    Foundation::{BOOL, HANDLE, HINSTANCE, HWND, LPARAM, LRESULT, WPARAM},
+    System::{
+      Console::{AttachConsole, SetConsoleCtrlHandler, ATTACH_PARENT_PROCESS},
+      DataExchange::COPYDATASTRUCT,
+      LibraryLoader::GetModuleHandleW,
+      Power::RegisterSuspendResumeNotification,
+      RemoteDesktop::{WTSRegisterSessionNotification, NOTIFY_FOR_THIS_SESSION},
+    },
+    UI::WindowsAndMessaging::{
+      CreateWindowExW, DefWindowProcW, FindWindowExW, PostMessageW, RegisterClassW,
+      DEVICE_NOTIFY_WINDOW_HANDLE, HMENU, HWND_MESSAGE, PBT_APMRESUMEAUTOMATIC, PBT_APMSUSPEND,
+      WINDOW_EX_STYLE, WINDOW_STYLE, WM_APP, WM_COPYDATA, WM_ENDSESSION, WM_HOTKEY,
+      WM_POWERBROADCAST, WM_WTSSESSION_CHANGE, WNDCLASSW, WS_EX_TOOLWINDOW, WTS_SESSION_LOCK,
+      WTS_SESSION_UNLOCK,
+    },
+  },
+};
+
+use crate::{hotkey, pipe::PipeRequest};
+
+// the hidden window a running instance listens on
+const CLASS_NAME: PCWSTR = w!("SoundPriorityControl");
+// message-only windows don't get broadcasts, logoff and shutdown go to a hidden top level one
+const SESSION_CLASS_NAME: PCWSTR = w!("SoundPrioritySession");
+const WM_CONTROL: u32 = WM_APP + 1;
+
+/// The `dwData` of a `WM_COPYDATA` request.
+///
+/// Scripts find the window with `FindWindowEx(HWND_MESSAGE, 0, "SoundPriorityControl", 0)` and
+/// send `WM_COPYDATA` with `dwData` set to this and `lpData` holding a UTF-8 request in the
+/// pipe grammar, e.g. `{"command":"pause"}` or
+/// `{"command":"set-config-field","field":"sensitivity","value":0.2}`.
+/// The result is `1` when the request was accepted, replies are only sent on the pipe.
+pub const COPYDATA_REQUEST: usize = 0x5350;
+
+// the window created by `listen`, 0 before that
+static WINDOW: AtomicIsize = AtomicIsize::new(0);
+
+type Handler = Box<dyn Fn(ControlCommand) + Send>;
+static HANDLER: Mutex<Option<Handler>> = Mutex::new(None);
+
+type RequestHandler = Box<dyn Fn(PipeRequest) + Send>;
+static REQUEST_HANDLER: Mutex<Option<RequestHandler>> = Mutex::new(None);
+
+type EndSessionHandler = Box<dyn Fn() + Send>;
+static END_SESSION_HANDLER: Mutex<Option<EndSessionHandler>> = Mutex::new(None);
+
+/// A command for the running instance, given on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+  Pause,
+  Resume,
+  Exit,
+  /// Sent by the pause hotkey.
+  TogglePause,
+  /// Sent by a second launch without a command, so the user sees the app is running.
+  Show,
+  /// The screen was locked.
+  Lock,
+  Unlock,
+  /// The system is going to sleep.
+  Sleep,
+  Wake,
+}
+
+impl ControlCommand {
+  /// The first control flag among the arguments.
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Option<Self> {
+    args.into_iter().find_map(|arg| match arg.as_str() {
+      "--pause" => Some(ControlCommand::Pause),
+      "--resume" => Some(ControlCommand::Resume),
+      "--exit" => Some(ControlCommand::Exit),
+      _ => None,
+    })
+  }
+  fn code(self) -> usize {
+    match self {
+      ControlCommand::Pause => 1,
+      ControlCommand::Resume => 2,
+      ControlCommand::Exit => 3,
+      ControlCommand::TogglePause => 4,
+      ControlCommand::Show => 5,
+      ControlCommand::Lock => 6,
+      ControlCommand::Unlock => 7,
+      ControlCommand::Sleep => 8,
+      ControlCommand::Wake => 9,
+    }
+  }
+  fn from_code(code: usize) -> Option<Self> {
+    match code {
+      1 => Some(ControlCommand::Pause),
+      2 => Some(ControlCommand::Resume),
+      3 => Some(ControlCommand::Exit),
+      4 => Some(ControlCommand::TogglePause),
+      5 => Some(ControlCommand::Show),
+      6 => Some(ControlCommand::Lock),
+      7 => Some(ControlCommand::Unlock),
+      8 => Some(ControlCommand::Sleep),
+      9 => Some(ControlCommand::Wake),
+      _ => None,
+    }
+  }
+}
+
+/// Deliver a command to the running instance, returns `false` if none is listening.
+pub fn send(command: ControlCommand) -> bool {
+  unsafe {
+    let window = FindWindowExW(HWND_MESSAGE, HWND::default(), CLASS_NAME, PCWSTR::null());
+    window.0 != 0 && PostMessageW(window, WM_CONTROL, WPARAM(command.code()), LPARAM(0)).is_ok()
+  }
+}
+
+/// Listen for commands from other instances.
+///
+/// The window is serviced by the message loop of the calling thread, so call this on the
+/// event loop thread. `handler` runs on that thread too.
+pub fn listen(handler: impl Fn(ControlCommand) + Send + 'static) -> bool {
+  if let Ok(mut current) = HANDLER.lock() {
+    *current = Some(Box::new(handler));
+  }
+
+  unsafe {
+    let Ok(module) = GetModuleHandleW(PCWSTR::null()) else {
+      return false;
+    };
+
+    let class = WNDCLASSW {
+      lpfnWndProc: Some(window_proc),
+      hInstance: module.into(),
+      lpszClassName: CLASS_NAME,
+      ..Default::default()
+    };
+    if RegisterClassW(&class) == 0 {
+      return false;
+    }
+
+    let window = CreateWindowExW(
+      WINDOW_EX_STYLE::default(),
+      CLASS_NAME,
+      CLASS_NAME,
+      WINDOW_STYLE::default(),
+      0,
+      0,
+      0,
+      0,
+      HWND_MESSAGE,
+      HMENU::default(),
+      module,
+      None,
+    );
+    WINDOW.store(window.0, Ordering::Relaxed);
+    if window.0 != 0 {
+      register_session_notifications(window);
+    }
+    create_session_window(module.into());
+    window.0 != 0
+  }
+}
+
+/// The window created by `listen`.
+pub fn window() -> Option<HWND> {
+  let window = WINDOW.load(Ordering::Relaxed);
+  (window != 0).then_some(HWND(window))
+}
+
+/// Accept `WM_COPYDATA` requests on the window from `listen`, see `COPYDATA_REQUEST`.
+///
+/// `handler` runs on the event loop thread, so it must not wait for the app.
+pub fn on_request(handler: impl Fn(PipeRequest) + Send + 'static) {
+  if let Ok(mut current) = REQUEST_HANDLER.lock() {
+    *current = Some(Box::new(handler));
+  }
+}
+
+/// Run `handler` when the user logs off or Windows shuts down.
+///
+/// It runs on the event loop thread before the process is ended, so it has to finish quickly and
+/// can't rely on the event loop, which doesn't get to run again.
+pub fn on_end_session(handler: impl Fn() + Send + 'static) {
+  if let Ok(mut current) = END_SESSION_HANDLER.lock() {
+    *current = Some(Box::new(handler));
+  }
+}
+
+// a hidden, never shown window sharing `window_proc`, only there for `WM_ENDSESSION`
+unsafe fn create_session_window(module: HINSTANCE) {
+  let class = WNDCLASSW {
+    lpfnWndProc: Some(window_proc),
+    hInstance: module,
+    lpszClassName: SESSION_CLASS_NAME,
+    ..Default::default()
+  };
+  if RegisterClassW(&class) == 0 {
+    log::warn!("[control] failed to watch logoff and shutdown");
+    return;
+  }
+  let window = CreateWindowExW(
+    WS_EX_TOOLWINDOW,
+    SESSION_CLASS_NAME,
+    SESSION_CLASS_NAME,
+    WINDOW_STYLE::default(),
+    0,
+    0,
+    0,
+    0,
+    HWND::default(),
+    HMENU::default(),
+    module,
+    None,
+  );
+  if window.0 == 0 {
+    log::warn!("[control] failed to watch logoff and shutdown");
+  }
+}
+
+// screen lock and sleep arrive as `Lock`, `Unlock`, `Sleep` and `Wake`
+unsafe fn register_session_notifications(window: HWND) {
+  if let Err(err) = WTSRegisterSessionNotification(window, NOTIFY_FOR_THIS_SESSION) {
+    log::warn!("[control] failed to watch the screen lock: {}", err);
+  }
+  // message-only windows don't get `WM_POWERBROADCAST` without asking for it
+  if let Err(err) = RegisterSuspendResumeNotification(HANDLE(window.0), DEVICE_NOTIFY_WINDOW_HANDLE)
+  {
+    log::warn!("[control] failed to watch sleep: {}", err);
+  }
+}
+
+/// Send `Exit` to the `listen` handler on Ctrl+C in the console that started the app.
+///
+/// Only headless mode needs it, with a tray the menu has an exit item.
+pub fn exit_on_ctrl_c() {
+  unsafe {
+    // release builds have no console of their own
+    let _ = AttachConsole(ATTACH_PARENT_PROCESS);
+    if let Err(err) = SetConsoleCtrlHandler(Some(console_handler), BOOL::from(true)) {
+      log::warn!("[control] failed to handle Ctrl+C: {}", err);
+    }
+  }
+}
+
+// runs on a thread of its own, the process ends once it returns `false`
+unsafe extern "system" fn console_handler(_: u32) -> BOOL {
+  let Ok(handler) = HANDLER.lock() else {
+    return BOOL::from(false);
+  };
+  match handler.as_ref() {
+    Some(handler) => {
+      handler(ControlCommand::Exit);
+      BOOL::from(true)
+    }
+    None => BOOL::from(false),
+  }
+}
+
+unsafe extern "system" fn window_proc(
+  window: HWND,
+  message: u32,
+  wparam: WPARAM,
+  lparam: LPARAM,
+) -> LRESULT {
+  if message == WM_COPYDATA && lparam.0 != 0 {
+    return LRESULT(copydata(&*(lparam.0 as *const COPYDATASTRUCT)) as isize);
+  }
+  // the process may be ended any time after this returns
+  if message == WM_ENDSESSION && wparam.0 != 0 {
+    log::info!("[control] the session is ending");
+    if let Ok(handler) = END_SESSION_HANDLER.lock() {
+      if let Some(handler) = handler.as_ref() {
+        handler();
+      }
+    }
+    return LRESULT(0);
+  }
+  let command = match message {
+    WM_CONTROL => ControlCommand::from_code(wparam.0),
+    WM_HOTKEY => hotkey::command(wparam.0),
+    WM_WTSSESSION_CHANGE => match wparam.0 as u32 {
+      WTS_SESSION_LOCK => Some(ControlCommand::Lock),
+      WTS_SESSION_UNLOCK => Some(ControlCommand::Unlock),
+      _ => None,
+    },
+    WM_POWERBROADCAST => match wparam.0 as u32 {
+      PBT_APMSUSPEND => Some(ControlCommand::Sleep),
+      PBT_APMRESUMEAUTOMATIC => Some(ControlCommand::Wake),
+      _ => None,
+    },
+    _ => return DefWindowProcW(window, message, wparam, lparam),
+  };
+  if let (Some(command), Ok(handler)) = (command, HANDLER.lock()) {
+    if let Some(handler) = handler.as_ref() {
+      handler(command);
+    }
+  }
+  LRESULT(0)
+}
+
+// parse and dispatch a copied request, returns whether it was accepted
+unsafe fn copydata(data: &COPYDATASTRUCT) -> bool {
+  if data.dwData != COPYDATA_REQUEST || data.lpData.is_null() {
+    return false;
+  }
+  let bytes = slice::from_raw_parts(data.lpData as *const u8, data.cbData as usize);
+  // scripts often send the trailing \0 along
+  let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+  let request = match serde_json::from_slice::<PipeRequest>(bytes) {
+    Ok(request) => request,
+    Err(err) => {
+      log::warn!("[control] invalid copied request: {}", err);
+      return false;
+    }
+  };
+
+  log::info!("[control] received {:?}", request);
+  match REQUEST_HANDLER.lock() {
+    Ok(handler) => handler.as_ref().map(|handler| handler(request)).is_some(),
+    Err(_) => false,
+  }
+}
@@ -0,0 +1,87 @@
+use std::{
+  backtrace::Backtrace,
+  borrow::Cow,
+  cell::{Cell, RefCell},
+  collections::BTreeMap,
+  fmt::Display,
+  fs::OpenOptions,
+  io::Write,
+  panic,
+  path::PathBuf,
+  process, thread,
+};
+
+use crate::notify;
+
+thread_local! {
+  // what the thread was doing, written next to a panic
+  static CONTEXT: RefCell<BTreeMap<&'static str, Cow<'static, str>>> = RefCell::default();
+  // the app can't go on without the thread, see `abort_on_panic`
+  static FATAL: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Write panics with a backtrace to `logfile`. A panic on the main thread, or one marked with
+/// `abort_on_panic`, tells the user where to find it and aborts. Anywhere else only that thread
+/// is gone.
+///
+/// The log is written directly, the logger may not get to flush before the process is gone.
+pub fn install(logfile: PathBuf) {
+  panic::set_hook(Box::new(move |info| {
+    let report = report(info);
+    let fatal = thread::current().name() == Some("main") || FATAL.with(Cell::get);
+    let written = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&logfile)
+      .and_then(|mut file| {
+        file
+          .write_all(report.as_bytes())
+          .and_then(|_| file.sync_all())
+      });
+    if written.is_err() {
+      eprint!("{}", report);
+    }
+    if !fatal {
+      log::error!("[crash] a worker thread panicked, the app keeps running");
+      return;
+    }
+
+    let message = format!(
+      "Sound Priority crashed and has to close.\n\nThe details are in {}",
+      logfile.display()
+    );
+    notify::message_box("Crashed", &message);
+    process::abort();
+  }));
+}
+
+/// Abort the app when the current thread panics, for threads it can't work without.
+pub fn abort_on_panic() {
+  FATAL.with(|fatal| fatal.set(true));
+}
+
+/// Remember what the current thread is doing, to be written next to a panic.
+pub fn set_context(key: &'static str, value: impl Into<Cow<'static, str>>) {
+  CONTEXT.with(|context| {
+    context.borrow_mut().insert(key, value.into());
+  });
+}
+
+fn report(info: &dyn Display) -> String {
+  let thread = thread::current();
+  let mut report = format!(
+    "\n[crash] thread '{}' {}\n",
+    thread.name().unwrap_or("unnamed"),
+    info
+  );
+  // a panic while the context is borrowed shouldn't panic again
+  let _ = CONTEXT.try_with(|context| {
+    if let Ok(context) = context.try_borrow() {
+      for (key, value) in context.iter() {
+        report.push_str(&format!("[crash] {}: {}\n", key, value));
+      }
+    }
+  });
+  report.push_str(&format!("{}\n", Backtrace::force_capture()));
+  report
+}
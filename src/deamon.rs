@@ -1,29 +1,91 @@
 use std::{
-  collections::HashSet,
-  sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+  cmp::Reverse,
+  collections::{HashMap, HashSet, VecDeque},
+  mem,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{
+      channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError,
+      TrySendError,
+    },
+    Arc, Mutex,
+  },
   thread,
-  time::Duration,
+  time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{config::Config, winmix::WinMix};
+use serde::Serialize;
+use windows::Win32::UI::WindowsAndMessaging::{GetForegroundWindow, GetWindowThreadProcessId};
+
+use crate::{
+  clock::{Clock, SystemClock},
+  config::{normalize_name, Aggregation, Config, PeakSource, RestoreTarget, TargetRule},
+  crash,
+  focus::FocusAssist,
+  metrics::{MetricsOptions, MetricsRecorder, MetricsSample},
+  notify::{self, Category},
+  smtc::MediaSessions,
+  state::AppVolumes,
+  winmix::{
+    device::{Device, UserFilter},
+    loopback::LoopbackCapture,
+    session::Session,
+    volume::{scalar_to_db, EndpointMeter, VolumeControl},
+    WinMix,
+  },
+};
 
 const TICK: Duration = Duration::from_millis(100);
-const TRANSFORM_SPEED: f32 = 0.05;
 
 const REDUCE_TIMEOUT: Duration = Duration::from_millis(200);
 const RESOTRE_TIMEOUT: Duration = Duration::from_secs(3);
 
 const FORCE_RELOAD_TICKS: usize = 600;
+// failed volume writes in a row before a session is left alone until the next full sync
+const MAX_VOLUME_ERRORS: u32 = 3;
+const FOREGROUND_TICKS: usize = 5;
+const FULL_PEAK_SCAN_TICKS: usize = 10;
+const PEAK_HISTORY_SIZE: usize = 10;
+const SMTC_TICKS: usize = 10;
+const FOCUS_ASSIST_TICKS: usize = 20;
+// the held peak loses this much of itself per tick once the hold is over
+const PEAK_HOLD_DECAY: f32 = 0.1;
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+// how often to look for a default device while there is none
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct Deamon {
   sender: Sender<DaemonCommand>,
+  state: Arc<Mutex<DaemonStateSnapshot>>,
+  safe_mode: Arc<AtomicBool>,
+  events: StateEvents,
 }
 
 impl Deamon {
   pub fn create(config: Config) -> Self {
     let (sender, receiver) = channel();
-    create_daemon(receiver, config.clone());
-    Self { sender }
+    let state = Arc::new(Mutex::new(DaemonStateSnapshot::default()));
+    let safe_mode = Arc::new(AtomicBool::new(false));
+    let events = StateEvents::default();
+    create_daemon(
+      receiver,
+      state.clone(),
+      safe_mode.clone(),
+      events.clone(),
+      SystemClock::new(),
+      config.clone(),
+    );
+    Self {
+      sender,
+      state,
+      safe_mode,
+      events,
+    }
+  }
+  /// Where the reduce and restore transitions are published.
+  pub fn events(&self) -> StateEvents {
+    self.events.clone()
   }
   pub fn start(&mut self) {
     let _ = self.sender.send(DaemonCommand::Resume);
@@ -31,30 +93,225 @@ impl Deamon {
   pub fn stop(&self) {
     let _ = self.sender.send(DaemonCommand::Suspend);
   }
+  /// Enumerate the sessions again on the next tick, without touching the config.
+  pub fn refresh_sessions(&self) {
+    let _ = self.sender.send(DaemonCommand::RefreshSessions);
+  }
+  /// Restore the targets and suspend, for while the user is away. `start` resumes.
+  pub fn away(&self) {
+    let _ = self.sender.send(DaemonCommand::Away);
+  }
+  /// Keep detecting and logging, but leave every volume alone. Reduced targets are restored first.
+  pub fn set_safe_mode(&self, enabled: bool) {
+    log::info!("[daemon] safe mode {}", if enabled { "on" } else { "off" });
+    self.safe_mode.store(enabled, Ordering::Relaxed);
+  }
+  pub fn safe_mode(&self) -> bool {
+    self.safe_mode.load(Ordering::Relaxed)
+  }
   pub fn update(&mut self, config: &Config) {
     let _ = self.sender.send(DaemonCommand::Update(config.clone()));
   }
+  /// Restore the targets and stop the daemon, waiting for it to finish.
+  pub fn shutdown(&self) -> Result<(), RecvTimeoutError> {
+    let (done, receiver) = sync_channel(1);
+    if self.sender.send(DaemonCommand::Shutdown(done)).is_err() {
+      // already stopped
+      return Ok(());
+    }
+    receiver.recv_timeout(SHUTDOWN_TIMEOUT + TICK * 5)
+  }
+  /// The sessions the daemon sees, empty if it doesn't answer within a second.
+  pub fn get_sessions_snapshot(&self) -> Vec<SessionSnapshot> {
+    let (reply, receiver) = sync_channel(1);
+    if self
+      .sender
+      .send(DaemonCommand::QuerySessions(reply))
+      .is_err()
+    {
+      return vec![];
+    }
+    receiver
+      .recv_timeout(Duration::from_secs(1))
+      .unwrap_or_default()
+  }
+  /// The state published by the daemon on its last tick.
+  pub fn snapshot(&self) -> DaemonStateSnapshot {
+    self
+      .state
+      .lock()
+      .map(|state| state.clone())
+      .unwrap_or_default()
+  }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DaemonStateSnapshot {
+  pub status: VolumeStatus,
+  pub peak: f32,
+  /// The loudest app at the moment the volume was reduced.
+  pub trigger: Option<String>,
+  pub safe_mode: bool,
+  /// The name of the output device.
+  pub device: String,
+  /// The targets playing on the device.
+  pub targets: Vec<String>,
+  /// Why the daemon is doing nothing without being paused, e.g. `"Focus Assist"`.
+  pub paused_by: Option<&'static str>,
+}
+
+/// A session as the daemon sees it.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSnapshot {
+  pub name: String,
+  pub pid: u32,
+  pub volume: f32,
+  /// `volume` in decibels, `null` when silent. Added after `volume`, older readers skip it.
+  pub volume_db: Option<f32>,
+  pub peak: f32,
+  pub is_target: bool,
+  pub is_excluded: bool,
+}
+
+/// A reduce or restore transition.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateEvent {
+  /// `ducked` or `restored`.
+  pub state: &'static str,
+  pub trigger: Option<String>,
+  /// Unix timestamp, in seconds.
+  pub ts: u64,
+}
+
+/// Subscribers to the state events, each with a small buffer.
+///
+/// A subscriber that falls behind misses events, and one that is gone is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct StateEvents {
+  subscribers: Arc<Mutex<Vec<SyncSender<StateEvent>>>>,
+  recent: Arc<Mutex<VecDeque<StateEvent>>>,
+}
+
+impl StateEvents {
+  const BUFFER: usize = 16;
+  const RECENT: usize = 20;
+
+  /// The last few events, oldest first.
+  pub fn recent(&self) -> Vec<StateEvent> {
+    self
+      .recent
+      .lock()
+      .map(|recent| recent.iter().cloned().collect())
+      .unwrap_or_default()
+  }
+
+  pub fn subscribe(&self) -> Receiver<StateEvent> {
+    let (sender, receiver) = sync_channel(Self::BUFFER);
+    if let Ok(mut subscribers) = self.subscribers.lock() {
+      subscribers.push(sender);
+    }
+    receiver
+  }
+  fn publish(&self, event: StateEvent) {
+    if let Ok(mut recent) = self.recent.lock() {
+      if recent.len() == Self::RECENT {
+        recent.pop_front();
+      }
+      recent.push_back(event.clone());
+    }
+
+    let Ok(mut subscribers) = self.subscribers.lock() else {
+      return;
+    };
+    subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+      Ok(()) | Err(TrySendError::Full(_)) => true,
+      Err(TrySendError::Disconnected(_)) => false,
+    });
+  }
 }
 
 pub enum DaemonCommand {
   Resume,
   Suspend,
+  /// Restore the targets, then suspend.
+  Away,
   Update(Config),
+  RefreshSessions,
+  /// Restore the targets and stop, then reply on the sender.
+  Shutdown(SyncSender<()>),
+  QuerySessions(SyncSender<Vec<SessionSnapshot>>),
 }
 
-fn create_daemon(receiver: Receiver<DaemonCommand>, mut config: Config) {
+fn create_daemon(
+  receiver: Receiver<DaemonCommand>,
+  state: Arc<Mutex<DaemonStateSnapshot>>,
+  safe_mode: Arc<AtomicBool>,
+  events: StateEvents,
+  clock: impl Clock,
+  mut base_config: Config,
+) {
   thread::spawn(move || {
+    crash::abort_on_panic();
+    if !wait_startup_delay(&receiver, &clock, &mut base_config) {
+      log::info!("[daemon.stopped]");
+      return;
+    }
+
     let winmix = WinMix::default();
     let mut transform = true;
     let mut ticks = 1_usize;
-    let mut volume_status = VolumeStatus::Restore;
-    let mut expect_volume = config.resotre_volume;
-    let mut timeout = Duration::ZERO;
+    let mut timer = StatusTimer::new();
+    let mut previous_volumes = PreviousVolumes::default();
+    let mut trigger = None;
+    let mut last_tick = clock.now();
+    let mut foreground = None;
+    let mut shutdown = None;
+    let mut refresh = false;
+    let mut last_writes = HashMap::<u32, Duration>::new();
+    // sessions that kept failing to take a volume, skipped until the next full sync
+    let mut poisoned = HashSet::new();
+    let mut volume_errors = HashMap::<u32, u32>::new();
+    // when the running fade began
+    let mut fade_started = None;
+    let mut known_sessions = HashSet::new();
+    let mut was_dry_run = false;
+    // new apps that weren't heard yet, announced once they play above the sensitivity
+    let mut unannounced = HashSet::<String>::new();
+    let mut first_seen = HashMap::<u32, usize>::new();
+    let mut users = UserFilter::current();
+    let mut last_active = HashMap::<u32, usize>::new();
+    let mut peak_histories = HashMap::<u32, PeakHistory>::new();
+    let mut media_sessions = MediaSessions::new();
+    let mut smoothed_peak = 0.0_f32;
+    let mut peak_hold = PeakHold::default();
+    let mut metrics = MetricsRecorder::new();
+    let mut peak_log = MetricsRecorder::new();
+    let mut master_muted = false;
+    let mut focus_assist = FocusAssist::new();
+    let mut meter: Option<Meter> = None;
+    let mut mic_timer = StatusTimer::new();
+    // the id of the microphone the daemon muted itself, the only one it unmutes
+    let mut mic_muted: Option<String> = None;
 
-    let mut device = winmix.get_default().expect("failed to get default device");
+    let Some(mut device) = wait_for_device(&winmix, &receiver, &clock, &mut base_config) else {
+      log::info!("[daemon.stopped]");
+      return;
+    };
     if device.register().is_err() {
       log::error!("[daemon] failed to register device");
     }
+    let mut config = resolve_config(&base_config, &device);
+    opt_out_of_ducking(&device, &config);
+    let mut device_name = device.get_name().unwrap_or_default();
+    crash::set_context("device", device_name.clone());
+    log_issues(&config);
+
+    let mut app_volumes = AppVolumes::new();
+    if config.persist_app_volumes {
+      app_volumes = AppVolumes::load();
+      app_volumes.prune();
+      restore_leftovers(&device.sessions_read(), &config, &mut app_volumes);
+    }
 
     log::info!("[daemon.started]");
     'main: loop {
@@ -64,95 +321,1102 @@ fn create_daemon(receiver: Receiver<DaemonCommand>, mut config: Config) {
       match command {
         Ok(DaemonCommand::Update(new_config)) => {
           log::info!("[daemon.updated]");
-          config = new_config;
-        }
-        Ok(DaemonCommand::Suspend) => loop {
-          log::info!("[daemon.suspended]");
-          let command = receiver.recv();
-          match command {
-            Ok(DaemonCommand::Resume) => {
-              log::info!("[daemon.resumed]");
-              break;
+          base_config = new_config;
+          config = resolve_config(&base_config, &device);
+          log_issues(&config);
+        }
+        Ok(command @ (DaemonCommand::Suspend | DaemonCommand::Away)) => {
+          if matches!(command, DaemonCommand::Away) && timer.status() == VolumeStatus::Reduce {
+            log::info!("[daemon] user away, restoring targets");
+            restore_for_pause(
+              &device,
+              &config,
+              &previous_volumes,
+              &mut app_volumes,
+              &clock,
+              &state,
+            );
+            // start over once back, instead of reducing to the volumes from before
+            timer = StatusTimer::new();
+            trigger = None;
+            transform = false;
+          }
+          loop {
+            log::info!("[daemon.suspended]");
+            // nothing is recorded while suspended, don't leave lines in the buffer
+            metrics.close();
+            peak_log.close();
+            let command = receiver.recv();
+            match command {
+              Ok(DaemonCommand::Resume) => {
+                log::info!("[daemon.resumed]");
+                last_tick = clock.now();
+                break;
+              }
+              Ok(DaemonCommand::Shutdown(done)) => {
+                shutdown = Some(done);
+                break 'main;
+              }
+              Ok(DaemonCommand::QuerySessions(reply)) => {
+                let _ = reply.send(session_snapshots(&device, &config, &mut users));
+              }
+              Ok(DaemonCommand::Update(new_config)) => {
+                log::info!("[daemon.updated] while suspended");
+                base_config = new_config;
+                config = resolve_config(&base_config, &device);
+                log_issues(&config);
+              }
+              // done on the first tick after resuming
+              Ok(DaemonCommand::RefreshSessions) => refresh = true,
+              Ok(_) => log::warn!("[daemon.suspended] command ignored"),
+              Err(_) => break 'main,
             }
-            Ok(_) => log::warn!("[daemon.suspended] command ignored"),
-            Err(_) => break 'main,
           }
-        },
+        }
         Ok(DaemonCommand::Resume) => log::warn!("[daemon.resumed] Already running"),
+        Ok(DaemonCommand::RefreshSessions) => {
+          log::info!("[daemon] refreshing sessions");
+          refresh = true;
+        }
+        Ok(DaemonCommand::QuerySessions(reply)) => {
+          let _ = reply.send(session_snapshots(&device, &config, &mut users));
+        }
+        Ok(DaemonCommand::Shutdown(done)) => {
+          shutdown = Some(done);
+          break;
+        }
         Err(TryRecvError::Disconnected) => break,
         Err(TryRecvError::Empty) => {}
       }
 
       // running daemon
-      let faill = device.sync(ticks % FORCE_RELOAD_TICKS == 0).is_err();
-      if faill {
-        log::warn!("[daemon] failed to sync");
+      crash::set_context("daemon", "sync");
+      let dry_run = safe_mode.load(Ordering::Relaxed);
+      // safe mode leaves every volume alone, which includes not leaving them reduced
+      if dry_run && !was_dry_run {
+        if timer.status() == VolumeStatus::Reduce {
+          log::info!("[daemon] safe mode on, restoring targets");
+          restore_for_pause(
+            &device,
+            &config,
+            &previous_volumes,
+            &mut app_volumes,
+            &clock,
+            &state,
+          );
+          timer = StatusTimer::new();
+          trigger = None;
+          fade_started = None;
+          transform = false;
+        }
+        if mic_muted.is_some() {
+          match set_mic_mute(&winmix, false, mic_muted.clone()) {
+            Ok(muted) => mic_muted = muted,
+            Err(err) => log::warn!("[daemon] failed to unmute the microphone: {}", err),
+          }
+        }
+      }
+      was_dry_run = dry_run;
+      let mut device_changed = false;
+      let force_reload = ticks % FORCE_RELOAD_TICKS == 0 || mem::take(&mut refresh);
+      if force_reload && !poisoned.is_empty() {
+        log::info!("[daemon] full sync, retrying {} sessions", poisoned.len());
+        poisoned.clear();
+        volume_errors.clear();
+      }
+      match device.sync(force_reload) {
+        Ok(true) => {
+          device_changed = true;
+          config = resolve_config(&base_config, &device);
+          device_name = device.get_name().unwrap_or_default();
+          crash::set_context("device", device_name.clone());
+          opt_out_of_ducking(&device, &config);
+          notify::show(
+            Category::DeviceChange,
+            "Output device changed",
+            &device_name,
+          );
+        }
+        Ok(false) => {}
+        Err(_) => log::warn!("[daemon] failed to sync"),
+      }
+
+      // the mute is followed by notifications, so checking costs no COM calls
+      let muted = config.pause_while_muted && device.is_master_muted();
+      if muted != master_muted {
+        log::info!("[daemon] output muted: {}", muted);
+        master_muted = muted;
+      }
+      if config.pause_on_focus_assist && ticks % FOCUS_ASSIST_TICKS == 1 {
+        focus_assist.refresh();
+      }
+      let paused_by = if muted {
+        Some("output muted")
+      } else if config.pause_on_focus_assist && focus_assist.is_quiet() {
+        Some("Focus Assist")
+      } else {
+        None
+      };
+      if let Some(reason) = paused_by {
+        if timer.status() == VolumeStatus::Reduce {
+          log::info!("[daemon] paused by {}, restoring targets", reason);
+          restore_for_pause(
+            &device,
+            &config,
+            &previous_volumes,
+            &mut app_volumes,
+            &clock,
+            &state,
+          );
+          timer = StatusTimer::new();
+          trigger = None;
+          transform = false;
+        }
+        if let Ok(mut state) = state.lock() {
+          state.paused_by = paused_by;
+          state.peak = 0.0;
+        }
+        last_tick = clock.now();
+        ticks = ticks.wrapping_add(1);
+        clock.sleep(TICK);
+        continue;
+      }
+
+      let mut foreground_changed = false;
+      if !config.exclude_foreground {
+        foreground = None;
+      } else if foreground.is_none() || ticks % FOREGROUND_TICKS == 0 {
+        let previous = mem::replace(&mut foreground, foreground_pid());
+        foreground_changed = foreground != previous;
+      }
+
+      if config.use_smtc && ticks % SMTC_TICKS == 1 {
+        media_sessions.refresh(clock.now());
       }
 
       let mut peak = 0.0_f32;
+      let mut target_peak = 0.0_f32;
+      let mut loudest = None;
+      let mut loudest_pid = None;
+      let mut peak_sum = 0.0_f32;
+      let detection = &config.detection;
+      let mut candidates = vec![];
       let mut targets = HashSet::new();
-      let sessions = device.current_sessions();
+      let mut announced = HashSet::new();
+      // the first tick and a new device would report every session as new
+      let watch_new = config.notify_on_new_session && ticks > 1 && !device_changed;
+      crash::set_context("daemon", "peaks");
+      let sessions = device.sessions_read();
       for session in sessions.iter() {
+        if config.same_user_only && !users.is_same_user(session.pid) {
+          continue;
+        }
         let name = &session.name;
-        let is_target = config.targets.iter().any(|exclude| name.contains(exclude));
+        let is_listed_exclude = config.is_excluded(name);
+        let is_foreground = foreground == Some(session.pid);
+        let is_listed_target = config.is_target(name);
+        // the app the user works in is never reduced, see `Config::exclude_foreground`
+        let is_target = is_listed_target && !is_foreground;
+
+        // and one brought to the front while reduced gets its volume back at once
+        let reduced = timer.status() == VolumeStatus::Reduce;
+        if is_listed_target && is_foreground && foreground_changed && reduced && !dry_run {
+          let rule = config.target_rule(name);
+          let previous = previous_volumes
+            .get(session)
+            .or_else(|| app_volumes.get(name));
+          let volume = VolumeStatus::Restore.volume(&config, &rule, previous);
+          if let Err(err) = session.volume.set_volume_clamped(volume) {
+            log::warn!("[daemon] failed to restore {} in front: {}", name, err);
+          }
+        }
 
         if is_target {
+          if config.mute_mic_while_targets_play {
+            let session_peak = session.volume.get_peak().unwrap_or_default();
+            target_peak = target_peak.max(session_peak);
+          }
           targets.insert(session);
         }
 
-        let is_exclude = config.exclude.iter().any(|exclude| name.contains(exclude));
-        let need_check = !is_target && !is_exclude;
+        let is_exclude = is_listed_exclude || is_foreground;
+        // when ducking every app, the excluded ones are left to trigger it
+        let need_check = if config.apply_to_all_sessions {
+          is_listed_exclude && !is_foreground
+        } else {
+          !is_target && !is_exclude
+        };
 
-        if need_check {
-          if let Ok(session_peak) = session.volume.get_peak() {
-            peak = peak.max(session_peak);
+        let key = normalize_name(name);
+        let is_new = !known_sessions.contains(&key) && announced.insert(key.clone());
+        if watch_new && is_new && !is_target && !is_listed_exclude {
+          // many apps open their session long before they make a sound
+          unannounced.insert(key.clone());
+        }
+        if unannounced.contains(&key) {
+          if !config.notify_on_new_session || is_listed_target || is_listed_exclude {
+            unannounced.remove(&key);
+          } else if session
+            .volume
+            .get_peak()
+            .is_ok_and(|session_peak| session_peak > config.sensitivity)
+          {
+            unannounced.remove(&key);
+            notify::show_new_session(name);
           }
         }
+
+        let seen = *first_seen.entry(session.pid).or_insert(ticks);
+        let old_enough = ticks.wrapping_sub(seen) >= config.min_session_age_ticks;
+
+        if need_check && old_enough {
+          candidates.push(session);
+        }
       }
 
-      let status = VolumeStatus::new(peak > config.sensitivity);
+      // a meter replaces the peaks of the sessions, which are then only read to find the loudest
+      // app while there is something loud enough to reduce for
+      let meter_peak = if detection.source != PeakSource::SessionAggregated {
+        // reopen on a new device, the old one may be gone
+        if device_changed || meter.as_ref().map(Meter::source) != Some(detection.source) {
+          meter = Meter::open(&device, detection.source);
+        }
+        match meter.as_ref().map(Meter::get_peak) {
+          Some(Ok(meter_peak)) => Some(detection.clean(meter_peak)),
+          _ => {
+            meter = None;
+            Some(0.0)
+          }
+        }
+      } else {
+        meter = None;
+        None
+      };
+      if meter_peak.is_some_and(|meter_peak| meter_peak <= config.sensitivity) {
+        candidates.clear();
+        // the sessions aren't read while quiet, what they did before says nothing once they are
+        // read again. a stale history would take the first new peak as rising or not
+        peak_histories.clear();
+        last_active.clear();
+      }
 
-      if status != volume_status {
-        timeout += TICK;
-        if status.is_timeout(timeout) {
-          volume_status.toggle();
-          expect_volume = volume_status.volume(&config);
-          timeout = Duration::ZERO;
-          transform = true;
+      // on busy systems only poll the recently active sessions, with a full scan now and then
+      let full_scan = ticks % FULL_PEAK_SCAN_TICKS == 0;
+      if !full_scan && candidates.len() > config.max_peak_sessions {
+        candidates.sort_by_key(|session| {
+          Reverse(last_active.get(&session.pid).copied().unwrap_or_default())
+        });
+        candidates.truncate(config.max_peak_sessions);
+      }
+      for session in candidates {
+        let Ok(session_peak) = session.volume.get_peak() else {
+          continue;
+        };
+        let mut session_peak = detection.clean(session_peak);
+        // a paused player may still hum above the sensitivity
+        if config.use_smtc && media_sessions.is_paused(&session.name) {
+          session_peak = 0.0;
         }
+        peak_sum += session_peak;
+        if session_peak > 0.0 {
+          last_active.insert(session.pid, ticks);
+        }
+        peak_histories
+          .entry(session.pid)
+          .or_insert_with(|| PeakHistory::new(PEAK_HISTORY_SIZE))
+          .push(session_peak);
+        if session_peak > peak {
+          peak = session_peak;
+          loudest = Some(&session.name);
+          loudest_pid = Some(session.pid);
+        }
+      }
+
+      if detection.aggregation == Aggregation::Sum {
+        peak = peak_sum.min(1.0);
+      }
+
+      if let Some(meter_peak) = meter_peak {
+        peak = meter_peak;
+      }
+      smoothed_peak = detection.smooth(smoothed_peak, peak);
+      peak = peak_hold.update(
+        smoothed_peak,
+        clock.now(),
+        Duration::from_millis(config.peak_hold_ms),
+      );
+
+      first_seen.retain(|pid, _| sessions.iter().any(|session| session.pid == *pid));
+      users.retain(
+        &sessions
+          .iter()
+          .map(|session| session.pid)
+          .collect::<Vec<_>>(),
+      );
+      last_active.retain(|pid, _| first_seen.contains_key(pid));
+      peak_histories.retain(|pid, _| first_seen.contains_key(pid));
+      known_sessions = sessions
+        .iter()
+        .map(|session| normalize_name(&session.name))
+        .collect();
+      unannounced.retain(|key| known_sessions.contains(key));
+
+      let now = clock.now();
+      let elapsed = now - last_tick;
+      last_tick = now;
+
+      let mute_mic = config.mute_mic_while_targets_play && target_peak > config.sensitivity;
+      if mic_timer.update(VolumeStatus::new(mute_mic), elapsed) {
+        let mute = mic_timer.status() == VolumeStatus::Reduce;
+        if dry_run {
+          log::info!(
+            "[daemon] safe mode, would set the microphone mute to {}",
+            mute
+          );
+        } else {
+          match set_mic_mute(&winmix, mute, mic_muted.clone()) {
+            Ok(muted) => mic_muted = muted,
+            Err(err) => log::warn!("[daemon] failed to change the microphone mute: {}", err),
+          }
+        }
+      }
+
+      crash::set_context("daemon", "timer");
+      let status = VolumeStatus::new(peak > config.sensitivity);
+      // audio that just started reduces right away, audio dying down still waits out the
+      // restore timeout
+      let rising = loudest_pid
+        .and_then(|pid| peak_histories.get(&pid))
+        .is_some_and(is_rising);
+      let timer_elapsed = if rising && status == VolumeStatus::Reduce {
+        elapsed.max(REDUCE_TIMEOUT)
       } else {
-        timeout = Duration::ZERO;
+        elapsed
+      };
+      // published once the snapshot has the new state, for subscribers that read it
+      let mut transition = None;
+      if timer.update(status, timer_elapsed) {
+        let volume_status = timer.status();
+        if volume_status == VolumeStatus::Reduce {
+          // remember where the targets were, for restoring to the previous volume
+          previous_volumes = PreviousVolumes::default();
+          for target in targets.iter() {
+            if let Ok(volume) = target.volume.get_volume() {
+              previous_volumes.remember(target, volume);
+            }
+          }
+
+          if config.persist_app_volumes {
+            for target in targets.iter() {
+              if let Some(volume) = previous_volumes.get(target) {
+                let rule = config.target_rule(&target.name);
+                app_volumes.remember(&target.name, volume);
+                app_volumes.reduced(&target.name, volume_status.volume(&config, &rule, None));
+              }
+            }
+            app_volumes.save();
+          }
+        }
+        trigger = match volume_status {
+          VolumeStatus::Reduce => loudest.cloned(),
+          VolumeStatus::Restore => None,
+        };
+        log::info!(
+          "[daemon] {:?} volume, triggered by {}",
+          volume_status,
+          trigger.as_deref().unwrap_or("silence")
+        );
+        if let Some(trigger) = &trigger {
+          let message = format!("{} is playing", trigger);
+          notify::show(Category::Duck, "Volume reduced", &message);
+        }
+        transition = Some(StateEvent {
+          state: match volume_status {
+            VolumeStatus::Reduce => "ducked",
+            VolumeStatus::Restore => "restored",
+          },
+          trigger: trigger.clone(),
+          ts: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default(),
+        });
+        fade_started = None;
+        transform = true;
+      }
+
+      if let Ok(mut state) = state.lock() {
+        *state = DaemonStateSnapshot {
+          status: timer.status(),
+          peak,
+          trigger: trigger.clone(),
+          safe_mode: dry_run,
+          device: device_name.clone(),
+          targets: targets.iter().map(|target| target.name.clone()).collect(),
+          paused_by: None,
+        };
+      }
+      if let Some(event) = transition {
+        events.publish(event);
+      }
+
+      metrics.sync(MetricsOptions::metrics(&config));
+      peak_log.sync(MetricsOptions::peaks(&config));
+      let record_metrics = metrics.is_due(ticks);
+      if record_metrics || peak_log.is_due(ticks) {
+        // the peak log has no column for it, so it doesn't cost a COM call per target
+        let volumes = match record_metrics {
+          true => targets
+            .iter()
+            .filter_map(|target| target.volume.get_volume().ok())
+            .collect::<Vec<_>>(),
+          false => vec![],
+        };
+        let sample = MetricsSample {
+          peak,
+          trigger: trigger.as_deref(),
+          status: timer.status(),
+          target_volume: (!volumes.is_empty())
+            .then(|| volumes.iter().sum::<f32>() / volumes.len() as f32),
+        };
+        if record_metrics {
+          metrics.record(&sample);
+        }
+        if peak_log.is_due(ticks) {
+          peak_log.record(&sample);
+        }
+      }
+
+      if transform && dry_run {
+        for target in targets.iter() {
+          let rule = config.target_rule(&target.name);
+          let previous = previous_volumes.get(target);
+          let expect_volume = timer.status().volume(&config, &rule, previous);
+          log::info!(
+            "[daemon] safe mode, would fade {} to {:.2}",
+            target.name,
+            expect_volume
+          );
+        }
+        transform = false;
       }
 
       if transform {
+        crash::set_context("daemon", "fade");
+        let min_interval = Duration::from_millis(config.min_write_interval_ms);
+        let fade_elapsed = now - *fade_started.get_or_insert(now);
+        let snap = fade_expired(fade_elapsed, config.max_fade_ms);
+        if snap {
+          log::warn!(
+            "[daemon] fade took over {}ms, jumping to the volume",
+            config.max_fade_ms
+          );
+        }
         let mut fadeing = targets.len();
         for target in targets.iter() {
-          let volume = target.volume.get_volume().unwrap();
-          let offset = expect_volume - volume;
-          let volume = if offset.abs() > TRANSFORM_SPEED {
-            volume + offset.signum() * TRANSFORM_SPEED
-          } else {
+          if poisoned.contains(&target.pid) {
             fadeing -= 1;
-            expect_volume
-          };
-          let _ = target.volume.set_volume(volume);
+            continue;
+          }
+
+          // defer the write, the target keeps fading on a later tick
+          let too_soon = last_writes
+            .get(&target.pid)
+            .is_some_and(|last| now - *last < min_interval);
+          if too_soon {
+            continue;
+          }
+
+          let previous = previous_volumes
+            .get(target)
+            .or_else(|| app_volumes.get(&target.name));
+          let rule = config.target_rule(&target.name);
+          let expect_volume = timer.status().volume(&config, &rule, previous);
+          let speed = timer.status().speed(&rule);
+          let errors = volume_errors.entry(target.pid).or_default();
+          let step = fade_target(
+            &target.name,
+            &target.volume,
+            expect_volume,
+            speed,
+            snap,
+            errors,
+          );
+          last_writes.insert(target.pid, now);
+
+          match step {
+            FadeStep::Done => fadeing -= 1,
+            FadeStep::Fading | FadeStep::Failed => {}
+            FadeStep::GaveUp => {
+              poisoned.insert(target.pid);
+              fadeing -= 1;
+            }
+          }
         }
+        last_writes.retain(|pid, _| targets.iter().any(|target| target.pid == *pid));
+        volume_errors.retain(|pid, _| targets.iter().any(|target| target.pid == *pid));
 
         if fadeing == 0 {
           transform = false;
+          fade_started = None;
+
+          let restored = timer.status() == VolumeStatus::Restore;
+          if restored && app_volumes.clear_reduced() {
+            app_volumes.save();
+          }
         }
       }
 
       ticks = ticks.wrapping_add(1);
-      thread::sleep(TICK);
+      clock.sleep(TICK);
     }
 
+    if shutdown.is_some() {
+      log::info!("[daemon] shutting down, restoring targets");
+      let targets = device.get_sessions_matching(|session| config.is_target(&session.name));
+      let restored = restore_targets(&targets, &config, &previous_volumes, &app_volumes, &clock);
+      if restored {
+        app_volumes.clear_reduced();
+      }
+    }
+
+    metrics.close();
+    peak_log.close();
+    if mic_muted.is_some() {
+      let _ = set_mic_mute(&winmix, false, mic_muted);
+    }
+    if config.persist_app_volumes {
+      app_volumes.save();
+    }
     log::info!("[daemon.stopped]");
+    if let Some(done) = shutdown {
+      let _ = done.send(());
+    }
   });
 }
 
+// fade the targets back, giving up after `SHUTDOWN_TIMEOUT`.
+// returns `true` when every target reached its volume
+fn restore_targets(
+  targets: &[Session],
+  config: &Config,
+  previous_volumes: &PreviousVolumes,
+  app_volumes: &AppVolumes,
+  clock: &impl Clock,
+) -> bool {
+  let start = clock.now();
+  loop {
+    let mut fadeing = 0;
+    for target in targets {
+      let previous = previous_volumes
+        .get(target)
+        .or_else(|| app_volumes.get(&target.name));
+      let rule = config.target_rule(&target.name);
+      let expect_volume = VolumeStatus::Restore.volume(config, &rule, previous);
+      let Ok(volume) = target.volume.get_volume() else {
+        continue;
+      };
+      let volume = fade_step(volume, expect_volume, VolumeStatus::Restore.speed(&rule));
+      if volume != expect_volume {
+        fadeing += 1;
+      }
+      let _ = target.volume.set_volume_clamped(volume);
+    }
+
+    if fadeing == 0 {
+      return true;
+    }
+    if clock.now() - start >= SHUTDOWN_TIMEOUT {
+      log::warn!("[daemon] {} targets not restored before shutdown", fadeing);
+      return false;
+    }
+    clock.sleep(TICK);
+  }
+}
+
+// put reduced targets back before the daemon stops watching, so they aren't left reduced
+fn restore_for_pause(
+  device: &Device,
+  config: &Config,
+  previous_volumes: &PreviousVolumes,
+  app_volumes: &mut AppVolumes,
+  clock: &impl Clock,
+  state: &Mutex<DaemonStateSnapshot>,
+) {
+  let targets = device.get_sessions_matching(|session| config.is_target(&session.name));
+  let restored = restore_targets(&targets, config, previous_volumes, app_volumes, clock);
+  if restored && app_volumes.clear_reduced() {
+    app_volumes.save();
+  }
+  if let Ok(mut state) = state.lock() {
+    state.status = VolumeStatus::Restore;
+    state.trigger = None;
+  }
+}
+
+// mute the default microphone, leaving one the user muted alone, or unmute the one that was
+// muted even when another is the default by now. returns the id of the one the daemon holds muted
+fn set_mic_mute(
+  winmix: &WinMix,
+  mute: bool,
+  muted: Option<String>,
+) -> Result<Option<String>, windows_result::Error> {
+  match (mute, muted) {
+    (true, None) => {
+      let device = winmix.get_default_capture()?;
+      let mic = device.master()?;
+      if mic.get_mute()? {
+        return Ok(None);
+      }
+      let id = device.get_id()?;
+      mic.set_mute(true)?;
+      log::info!("[daemon] muted the microphone {}", id);
+      Ok(Some(id))
+    }
+    (false, Some(id)) => {
+      winmix.get_device(&id)?.master()?.set_mute(false)?;
+      log::info!("[daemon] unmuted the microphone {}", id);
+      Ok(None)
+    }
+    (_, muted) => Ok(muted),
+  }
+}
+
+// the volumes targets had before a reduction.
+// a target that restarts mid-reduction gets a new pid, so it falls back to the app name
+#[derive(Debug, Default)]
+struct PreviousVolumes {
+  by_pid: HashMap<u32, f32>,
+  by_name: HashMap<String, f32>,
+}
+
+impl PreviousVolumes {
+  fn remember(&mut self, session: &Session, volume: f32) {
+    self.by_pid.insert(session.pid, volume);
+    self.by_name.insert(normalize_name(&session.name), volume);
+  }
+  fn get(&self, session: &Session) -> Option<f32> {
+    self
+      .by_pid
+      .get(&session.pid)
+      .or_else(|| self.by_name.get(&normalize_name(&session.name)))
+      .copied()
+  }
+}
+
+/// The last peaks of a session, oldest first.
+#[derive(Debug)]
+struct PeakHistory {
+  samples: VecDeque<f32>,
+  max_size: usize,
+}
+
+impl PeakHistory {
+  fn new(max_size: usize) -> Self {
+    Self {
+      samples: VecDeque::with_capacity(max_size),
+      max_size,
+    }
+  }
+  fn push(&mut self, peak: f32) {
+    if self.samples.len() == self.max_size {
+      self.samples.pop_front();
+    }
+    self.samples.push_back(peak);
+  }
+}
+
+/// Whether the last 3 samples go up.
+fn is_rising(history: &PeakHistory) -> bool {
+  let mut last = history.samples.iter().rev().take(3);
+  match (last.next(), last.next(), last.next()) {
+    (Some(newest), Some(middle), Some(oldest)) => oldest < middle && middle < newest,
+    _ => false,
+  }
+}
+
+// keep Windows from ducking this app's own session during calls
+fn opt_out_of_ducking(device: &Device, config: &Config) {
+  if !config.disable_system_ducking {
+    return;
+  }
+  if let Err(err) = device.set_ducking_opt_out(true) {
+    log::warn!("[daemon] failed to opt out of system ducking: {}", err);
+  }
+}
+
+/// The highest recent peak, decaying once it was held long enough.
+#[derive(Debug, Default)]
+struct PeakHold {
+  peak: f32,
+  since: Duration,
+}
+
+impl PeakHold {
+  /// Feed the peak at `now`, returns the held peak.
+  fn update(&mut self, peak: f32, now: Duration, hold: Duration) -> f32 {
+    if hold.is_zero() || peak >= self.peak {
+      self.peak = peak;
+      self.since = now;
+    } else if now - self.since > hold {
+      self.peak = (self.peak * (1.0 - PEAK_HOLD_DECAY)).max(peak);
+    }
+    self.peak
+  }
+}
+
+// the peak reader for the sources other than sessions
+enum Meter {
+  Endpoint(EndpointMeter),
+  Loopback(LoopbackCapture),
+}
+
+impl Meter {
+  fn open(device: &Device, source: PeakSource) -> Option<Self> {
+    let meter = match source {
+      PeakSource::SessionAggregated => return None,
+      PeakSource::EndpointMeter => device.meter().map(Meter::Endpoint),
+      PeakSource::Loopback => device.loopback().map(Meter::Loopback),
+    };
+    meter
+      .inspect_err(|err| log::warn!("[daemon] failed to open {:?}: {}", source, err))
+      .ok()
+  }
+  fn source(&self) -> PeakSource {
+    match self {
+      Meter::Endpoint(_) => PeakSource::EndpointMeter,
+      Meter::Loopback(_) => PeakSource::Loopback,
+    }
+  }
+  fn get_peak(&self) -> Result<f32, windows_result::Error> {
+    match self {
+      Meter::Endpoint(meter) => meter.get_peak(),
+      Meter::Loopback(capture) => capture.get_peak(),
+    }
+  }
+}
+
+// move one step of `speed` from `volume` towards `expect`, a speed of 0 or less jumps there
+fn fade_step(volume: f32, expect: f32, speed: f32) -> f32 {
+  let offset = expect - volume;
+  if speed > 0.0 && offset.abs() > speed {
+    volume + offset.signum() * speed
+  } else {
+    expect
+  }
+}
+
+// whether a fade that began `fade_elapsed` ago ran past `max_fade_ms`, `0` never expires
+fn fade_expired(fade_elapsed: Duration, max_fade_ms: u64) -> bool {
+  max_fade_ms > 0 && fade_elapsed > Duration::from_millis(max_fade_ms)
+}
+
+// the next volume of a fade, jumping to `expect` once it expired
+fn fade_towards(volume: f32, expect: f32, speed: f32, expired: bool) -> f32 {
+  if expired {
+    expect
+  } else {
+    fade_step(volume, expect, speed)
+  }
+}
+
+// what one step of a fade did to a target
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FadeStep {
+  /// The target reached its volume.
+  Done,
+  Fading,
+  /// The volume couldn't be read or written, the next tick tries again.
+  Failed,
+  /// It failed `MAX_VOLUME_ERRORS` times in a row, leave it alone.
+  GaveUp,
+}
+
+// move a target one step towards `expect`, counting the failures in a row in `errors`
+fn fade_target(
+  name: &str,
+  volume: &impl VolumeControl,
+  expect: f32,
+  speed: f32,
+  snap: bool,
+  errors: &mut u32,
+) -> FadeStep {
+  let result = volume.get_volume().and_then(|current| {
+    let next = fade_towards(current, expect, speed, snap);
+    volume.set_volume_clamped(next).map(|_| next)
+  });
+  match result {
+    Ok(next) => {
+      *errors = 0;
+      if next == expect {
+        FadeStep::Done
+      } else {
+        FadeStep::Fading
+      }
+    }
+    // the session ended, the device is gone or access was denied
+    Err(err) => {
+      log::warn!("[daemon] set_volume failed for {}: {:?}", name, err);
+      *errors += 1;
+      if *errors < MAX_VOLUME_ERRORS {
+        return FadeStep::Failed;
+      }
+      log::warn!("[daemon] leaving {} alone until the next full sync", name);
+      FadeStep::GaveUp
+    }
+  }
+}
+
+// wait before touching the device, it may not be ready right after login.
+// returns `false` when the daemon was dropped while waiting
+fn wait_startup_delay(
+  receiver: &Receiver<DaemonCommand>,
+  clock: &impl Clock,
+  config: &mut Config,
+) -> bool {
+  if config.startup_delay_secs == 0 {
+    return true;
+  }
+
+  log::info!(
+    "[daemon] startup delay: waiting {}s",
+    config.startup_delay_secs
+  );
+  let start = clock.now();
+  while clock.now() - start < Duration::from_secs(config.startup_delay_secs) {
+    match receiver.try_recv() {
+      Ok(DaemonCommand::Update(new_config)) => {
+        log::info!("[daemon.updated] during startup delay");
+        *config = new_config;
+      }
+      Ok(DaemonCommand::Resume) => {
+        log::info!("[daemon] startup delay skipped");
+        break;
+      }
+      // nothing is reduced yet, so away is just suspended
+      Ok(DaemonCommand::Suspend | DaemonCommand::Away) => return wait_resumed(receiver, config),
+      // nothing has been touched yet
+      Ok(DaemonCommand::Shutdown(done)) => {
+        let _ = done.send(());
+        return false;
+      }
+      // nor enumerated
+      Ok(DaemonCommand::QuerySessions(reply)) => {
+        let _ = reply.send(vec![]);
+      }
+      // the first tick enumerates them anyway
+      Ok(DaemonCommand::RefreshSessions) => {}
+      Err(TryRecvError::Disconnected) => return false,
+      Err(TryRecvError::Empty) => clock.sleep(TICK),
+    }
+  }
+  true
+}
+
+// open the default device, waiting for one while there is none.
+// returns `None` when the daemon was dropped while waiting
+fn wait_for_device<'a>(
+  winmix: &'a WinMix,
+  receiver: &Receiver<DaemonCommand>,
+  clock: &impl Clock,
+  config: &mut Config,
+) -> Option<Device<'a>> {
+  let mut logged = false;
+  loop {
+    match winmix.get_default_retrying() {
+      Ok(device) => return Some(device),
+      Err(err) if !logged => {
+        log::error!("[daemon] no default device, waiting for one: {}", err);
+        logged = true;
+      }
+      Err(_) => {}
+    }
+
+    let start = clock.now();
+    while clock.now() - start < DEVICE_RETRY_INTERVAL {
+      match receiver.try_recv() {
+        Ok(DaemonCommand::Update(new_config)) => *config = new_config,
+        Ok(DaemonCommand::Suspend | DaemonCommand::Away) => {
+          if !wait_resumed(receiver, config) {
+            return None;
+          }
+        }
+        Ok(DaemonCommand::Shutdown(done)) => {
+          let _ = done.send(());
+          return None;
+        }
+        Ok(DaemonCommand::QuerySessions(reply)) => {
+          let _ = reply.send(vec![]);
+        }
+        Ok(_) => {}
+        Err(TryRecvError::Disconnected) => return None,
+        Err(TryRecvError::Empty) => clock.sleep(TICK),
+      }
+    }
+  }
+}
+
+// stay suspended before anything was touched, returns `false` when the daemon was dropped
+fn wait_resumed(receiver: &Receiver<DaemonCommand>, config: &mut Config) -> bool {
+  log::info!("[daemon.suspended] before starting");
+  loop {
+    match receiver.recv() {
+      Ok(DaemonCommand::Resume) => {
+        log::info!("[daemon.resumed]");
+        return true;
+      }
+      Ok(DaemonCommand::Update(new_config)) => *config = new_config,
+      Ok(DaemonCommand::Shutdown(done)) => {
+        let _ = done.send(());
+        return false;
+      }
+      Ok(DaemonCommand::QuerySessions(reply)) => {
+        let _ = reply.send(vec![]);
+      }
+      Ok(_) => log::warn!("[daemon.suspended] command ignored"),
+      Err(_) => return false,
+    }
+  }
+}
+
+fn session_snapshots(
+  device: &Device,
+  config: &Config,
+  users: &mut UserFilter,
+) -> Vec<SessionSnapshot> {
+  device
+    .sessions_read()
+    .iter()
+    .filter(|session| !config.same_user_only || users.is_same_user(session.pid))
+    .map(|session| {
+      let volume = session.volume.get_volume().unwrap_or_default();
+      let peak = session.volume.get_peak().unwrap_or_default();
+      SessionSnapshot {
+        name: session.name.clone(),
+        pid: session.pid,
+        volume,
+        volume_db: scalar_to_db(volume),
+        peak,
+        is_target: config.is_target(&session.name),
+        is_excluded: config.is_excluded(&session.name),
+      }
+    })
+    .collect()
+}
+
+// the process owning the window the user is working in
+fn foreground_pid() -> Option<u32> {
+  let mut pid = 0;
+  unsafe {
+    let window = GetForegroundWindow();
+    GetWindowThreadProcessId(window, Some(&mut pid));
+  }
+  (pid != 0).then_some(pid)
+}
+
+// put back targets a previous run left reduced, e.g. after a crash
+fn restore_leftovers(sessions: &[Session], config: &Config, app_volumes: &mut AppVolumes) {
+  if app_volumes
+    .apps
+    .values()
+    .all(|app| app.reduced_to.is_none())
+  {
+    return;
+  }
+
+  for session in sessions {
+    let is_target = config.is_target(&session.name);
+    let Some(app) = app_volumes.apps.get(&normalize_name(&session.name)) else {
+      continue;
+    };
+    let (volume, Some(reduced_to)) = (app.volume, app.reduced_to) else {
+      continue;
+    };
+    let Ok(current) = session.volume.get_volume() else {
+      continue;
+    };
+
+    // each app is compared against its own reduced level, since rules can reduce them differently
+    let left_reduced = (current - reduced_to).abs() < f32::EPSILON;
+    if is_target && left_reduced && session.volume.set_volume_clamped(volume).is_ok() {
+      log::info!(
+        "[daemon] restored {} from {} to {}",
+        session.name,
+        current,
+        volume
+      );
+    }
+  }
+  app_volumes.clear_reduced();
+  app_volumes.save();
+}
+
+// apply the config section matching the current device
+fn resolve_config(config: &Config, device: &Device) -> Config {
+  let id = device.get_id().unwrap_or_default();
+  let name = device.get_name().unwrap_or_default();
+  let (config, section) = config.for_device(&id, &name);
+  match section {
+    Some(section) => log::info!("[daemon] device \"{}\" uses section \"{}\"", name, section),
+    None => log::info!("[daemon] device \"{}\" uses global config", name),
+  }
+  config
+}
+
+fn log_issues(config: &Config) {
+  for issue in config.issues() {
+    log::warn!("[daemon] config issue {}: {}", issue.id(), issue.message());
+  }
+  if !config.exclude_self {
+    log::warn!("[daemon] exclude_self is off, this app may reduce its own volume");
+  }
+}
+
+/// Applies a detected status only once it has held for its timeout.
+#[derive(Debug, Clone)]
+pub struct StatusTimer {
+  status: VolumeStatus,
+  timeout: Duration,
+}
+
+impl StatusTimer {
+  pub fn new() -> Self {
+    Self {
+      status: VolumeStatus::Restore,
+      timeout: Duration::ZERO,
+    }
+  }
+  /// The currently applied status.
+  pub fn status(&self) -> VolumeStatus {
+    self.status
+  }
+  /// Feed the status detected after `elapsed`, returns `true` when the applied status toggled.
+  pub fn update(&mut self, detected: VolumeStatus, elapsed: Duration) -> bool {
+    if detected == self.status {
+      self.timeout = Duration::ZERO;
+      return false;
+    }
+
+    self.timeout += elapsed;
+    if !detected.is_timeout(self.timeout) {
+      return false;
+    }
+
+    self.status.toggle();
+    self.timeout = Duration::ZERO;
+    true
+  }
+}
+
+impl Default for StatusTimer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
 pub enum VolumeStatus {
+  #[default]
   Restore,
   Reduce,
 }
@@ -171,11 +1435,22 @@ impl VolumeStatus {
         VolumeStatus::Reduce => REDUCE_TIMEOUT,
       }
   }
-  fn volume(&self, config: &Config) -> f32 {
+  fn volume(&self, config: &Config, rule: &TargetRule, previous: Option<f32>) -> f32 {
+    match self {
+      VolumeStatus::Restore => match config.restore_target {
+        RestoreTarget::Full => 1.0,
+        RestoreTarget::Previous => previous.unwrap_or(config.resotre_volume),
+        RestoreTarget::Fixed => config.resotre_volume,
+      },
+      VolumeStatus::Reduce => rule.reduce_volume.unwrap_or(config.reduce_volume),
+    }
+  }
+  fn speed(&self, rule: &TargetRule) -> f32 {
     match self {
-      VolumeStatus::Restore => config.resotre_volume,
-      VolumeStatus::Reduce => config.reduce_volume,
+      VolumeStatus::Restore => rule.release_speed,
+      VolumeStatus::Reduce => rule.attack_speed,
     }
+    .unwrap_or_default()
   }
   fn new(reduce: bool) -> Self {
     if reduce {
@@ -185,3 +1460,174 @@ impl VolumeStatus {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+
+  use windows::Win32::Media::Audio::AUDCLNT_E_DEVICE_INVALIDATED;
+
+  use super::*;
+  use crate::clock::ManualClock;
+
+  // fade like the daemon loop does, returning the ticks it took to reach `expect`
+  fn fade(clock: &ManualClock, from: f32, expect: f32, speed: f32, max_fade_ms: u64) -> usize {
+    let started = clock.now();
+    let mut volume = from;
+    let mut ticks = 0;
+    while volume != expect {
+      let expired = fade_expired(clock.now() - started, max_fade_ms);
+      volume = fade_towards(volume, expect, speed, expired);
+      clock.sleep(TICK);
+      ticks += 1;
+    }
+    ticks
+  }
+
+  #[test]
+  fn slow_fade_jumps_after_max_fade() {
+    let clock = ManualClock::new();
+    // a second of ticks, then the first one past it jumps
+    assert_eq!(fade(&clock, 1.0, 0.5, 0.0001, 1_000), 12);
+  }
+
+  #[test]
+  fn fast_fade_ends_before_max_fade() {
+    let clock = ManualClock::new();
+    assert_eq!(fade(&clock, 1.0, 0.5, 0.125, 1_000), 4);
+  }
+
+  // feed `detected` every tick until the timer toggles, returning how long it took
+  fn time_to_toggle(
+    clock: &ManualClock,
+    timer: &mut StatusTimer,
+    detected: VolumeStatus,
+  ) -> Duration {
+    let started = clock.now();
+    let mut last_tick = started;
+    loop {
+      clock.sleep(TICK);
+      let elapsed = clock.now() - last_tick;
+      last_tick = clock.now();
+      if timer.update(detected, elapsed) {
+        return clock.now() - started;
+      }
+    }
+  }
+
+  #[test]
+  fn timer_reduces_quickly_and_restores_slowly() {
+    let clock = ManualClock::new();
+    let mut timer = StatusTimer::new();
+    assert_eq!(
+      time_to_toggle(&clock, &mut timer, VolumeStatus::Reduce),
+      REDUCE_TIMEOUT
+    );
+    assert_eq!(timer.status(), VolumeStatus::Reduce);
+    assert_eq!(
+      time_to_toggle(&clock, &mut timer, VolumeStatus::Restore),
+      RESOTRE_TIMEOUT
+    );
+    assert_eq!(timer.status(), VolumeStatus::Restore);
+  }
+
+  #[test]
+  fn timer_starts_over_when_the_status_flips_back() {
+    let mut timer = StatusTimer::new();
+    assert!(!timer.update(VolumeStatus::Reduce, TICK));
+    assert!(!timer.update(VolumeStatus::Restore, TICK));
+    assert!(!timer.update(VolumeStatus::Reduce, TICK));
+    assert!(timer.update(VolumeStatus::Reduce, TICK));
+  }
+
+  #[test]
+  fn peak_hold_decays_after_the_hold() {
+    let clock = ManualClock::new();
+    let hold = Duration::from_millis(300);
+    let mut peak_hold = PeakHold::default();
+    assert_eq!(peak_hold.update(0.8, clock.now(), hold), 0.8);
+    for _ in 0..3 {
+      clock.sleep(TICK);
+      assert_eq!(peak_hold.update(0.1, clock.now(), hold), 0.8);
+    }
+    clock.sleep(TICK);
+    assert!(peak_hold.update(0.1, clock.now(), hold) < 0.8);
+    // no hold follows the peak as it is
+    assert_eq!(peak_hold.update(0.1, clock.now(), Duration::ZERO), 0.1);
+  }
+
+  // a volume that fails while `failing` is set
+  #[derive(Default)]
+  struct FakeVolume {
+    volume: Cell<f32>,
+    failing: Cell<bool>,
+  }
+
+  impl VolumeControl for FakeVolume {
+    fn get_volume(&self) -> Result<f32, windows_result::Error> {
+      if self.failing.get() {
+        return Err(windows_result::Error::new(
+          AUDCLNT_E_DEVICE_INVALIDATED,
+          "the session is gone",
+        ));
+      }
+      Ok(self.volume.get())
+    }
+    fn set_volume_clamped(&self, level: f32) -> Result<(), windows_result::Error> {
+      self.get_volume()?;
+      self.volume.set(level.clamp(0.0, 1.0));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn fade_target_reaches_the_volume() {
+    let volume = FakeVolume::default();
+    volume.volume.set(1.0);
+    let mut errors = 0;
+    assert_eq!(
+      fade_target("test", &volume, 0.5, 0.25, false, &mut errors),
+      FadeStep::Fading
+    );
+    assert_eq!(
+      fade_target("test", &volume, 0.5, 0.25, false, &mut errors),
+      FadeStep::Done
+    );
+    assert_eq!(volume.volume.get(), 0.5);
+  }
+
+  #[test]
+  fn fade_target_gives_up_on_a_failing_session() {
+    let volume = FakeVolume::default();
+    volume.failing.set(true);
+    let mut errors = 0;
+    for _ in 1..MAX_VOLUME_ERRORS {
+      assert_eq!(
+        fade_target("test", &volume, 0.5, 0.25, false, &mut errors),
+        FadeStep::Failed
+      );
+    }
+    assert_eq!(
+      fade_target("test", &volume, 0.5, 0.25, false, &mut errors),
+      FadeStep::GaveUp
+    );
+  }
+
+  #[test]
+  fn fade_target_forgets_failures_once_it_works() {
+    let volume = FakeVolume::default();
+    volume.failing.set(true);
+    let mut errors = 0;
+    fade_target("test", &volume, 0.5, 0.25, false, &mut errors);
+    volume.failing.set(false);
+    fade_target("test", &volume, 0.5, 0.25, false, &mut errors);
+    assert_eq!(errors, 0);
+  }
+
+  #[test]
+  fn zero_max_fade_never_expires() {
+    assert!(!fade_expired(Duration::from_secs(60 * 60), 0));
+    assert!(fade_expired(Duration::from_millis(1_001), 1_000));
+    assert!(!fade_expired(Duration::from_millis(1_000), 1_000));
+  }
+}
@@ -0,0 +1,82 @@
+use std::{
+  fs, io,
+  path::PathBuf,
+  thread,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+  config::Config,
+  deamon::{DaemonStateSnapshot, StateEvent},
+  notify::{self, Category},
+  state::state_dir,
+  winmix::{DeviceInfo, MixerInfo, WinMix},
+};
+
+/// Everything a bug report needs, written by the "Save Diagnostics" menu item.
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+  pub version: &'static str,
+  /// Unix timestamp, in seconds.
+  pub created: u64,
+  pub config: Config,
+  pub status: DaemonStateSnapshot,
+  pub transitions: Vec<StateEvent>,
+  pub devices: Vec<DeviceInfo>,
+  /// The default output device and its sessions.
+  pub mixer: Option<MixerInfo>,
+}
+
+/// Write the diagnostics to the state directory without blocking the caller, then show the path.
+pub fn dump(config: Config, status: DaemonStateSnapshot, transitions: Vec<StateEvent>) {
+  thread::spawn(move || {
+    let created = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|time| time.as_secs())
+      .unwrap_or_default();
+
+    let winmix = WinMix::default();
+    let devices = winmix.enumerate().unwrap_or_else(|err| {
+      log::warn!("[diagnostics] failed to list devices: {}", err);
+      vec![]
+    });
+    let mixer = winmix
+      .get_default()
+      .and_then(|device| device.describe())
+      .inspect_err(|err| log::warn!("[diagnostics] failed to read the mixer: {}", err))
+      .ok();
+
+    let diagnostics = Diagnostics {
+      version: env!("CARGO_PKG_VERSION"),
+      created,
+      config,
+      status,
+      transitions,
+      devices,
+      mixer,
+    };
+
+    let path = path(created);
+    let written = serde_json::to_vec_pretty(&diagnostics)
+      .map_err(io::Error::from)
+      .and_then(|json| fs::write(&path, json));
+    match written {
+      Ok(()) => {
+        log::info!("[diagnostics] saved to {}", path.display());
+        let message = format!("Saved to {}", path.display());
+        notify::show(Category::Diagnostics, "Diagnostics saved", &message);
+      }
+      Err(err) => {
+        log::error!("[diagnostics] failed to save: {}", err);
+        let message = format!("Failed to save diagnostics: {}", err);
+        notify::show(Category::Error, "Diagnostics", &message);
+      }
+    }
+  });
+}
+
+fn path(created: u64) -> PathBuf {
+  state_dir().join(format!("diagnostics-{}.json", created))
+}
@@ -0,0 +1,157 @@
+use std::{ffi::c_void, mem, ptr};
+
+use windows::Win32::{
+  Foundation::NTSTATUS,
+  UI::Shell::{
+    SHQueryUserNotificationState, QUERY_USER_NOTIFICATION_STATE, QUNS_PRESENTATION_MODE,
+  },
+};
+
+// `WNF_SHEL_QUIETHOURS_ACTIVE_PROFILE_CHANGED`, published by the shell whenever Focus Assist
+// changes. it holds the active profile as a `u32`
+const QUIET_HOURS_STATE: u64 = 0x0d83_063e_a3bf_1c75;
+
+/// The Focus Assist setting, as the shell publishes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FocusProfile {
+  Off,
+  PriorityOnly,
+  AlarmsOnly,
+}
+
+impl FocusProfile {
+  fn from_raw(profile: u32) -> Self {
+    match profile {
+      0 => FocusProfile::Off,
+      1 => FocusProfile::PriorityOnly,
+      // newer builds may add profiles, they are all some kind of quiet
+      _ => FocusProfile::AlarmsOnly,
+    }
+  }
+}
+
+/// Whether the user asked Windows for quiet, through Focus Assist or presentation mode.
+///
+/// The state is cached until the next `refresh`, and reads as not quiet where it can't be
+/// queried.
+#[derive(Default)]
+pub struct FocusAssist {
+  quiet: bool,
+  // only log the first of a run of errors
+  failing: bool,
+}
+
+impl FocusAssist {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Query the Focus Assist profile and the notification state again.
+  pub fn refresh(&mut self) {
+    let (profile, state) = (query_profile(), query_state());
+    match profile.as_ref().err().or(state.as_ref().err()) {
+      Some(err) => {
+        if !self.failing {
+          log::warn!("[focus] failed to query Focus Assist: {}", err);
+        }
+        self.failing = true;
+      }
+      None => self.failing = false,
+    }
+
+    let quiet = is_quiet(profile.ok(), state.ok());
+    if quiet != self.quiet {
+      log::info!("[focus] quiet: {}", quiet);
+      self.quiet = quiet;
+    }
+  }
+  pub fn is_quiet(&self) -> bool {
+    self.quiet
+  }
+}
+
+#[link(name = "ntdll")]
+extern "system" {
+  // undocumented, but stable since Windows 10 1709
+  fn NtQueryWnfStateData(
+    state_name: *const u64,
+    type_id: *const c_void,
+    explicit_scope: *const c_void,
+    change_stamp: *mut u32,
+    buffer: *mut c_void,
+    buffer_size: *mut u32,
+  ) -> i32;
+}
+
+// the raw calls, everything else works on what they return
+fn query_profile() -> windows::core::Result<FocusProfile> {
+  let mut profile = 0_u32;
+  let mut size = mem::size_of::<u32>() as u32;
+  let mut change_stamp = 0_u32;
+  let status = unsafe {
+    NtQueryWnfStateData(
+      &QUIET_HOURS_STATE,
+      ptr::null(),
+      ptr::null(),
+      &mut change_stamp,
+      &mut profile as *mut u32 as *mut c_void,
+      &mut size,
+    )
+  };
+  NTSTATUS(status).ok()?;
+  // nothing published yet, Focus Assist was never turned on
+  if size == 0 {
+    return Ok(FocusProfile::Off);
+  }
+  Ok(FocusProfile::from_raw(profile))
+}
+
+fn query_state() -> windows::core::Result<QUERY_USER_NOTIFICATION_STATE> {
+  unsafe { SHQueryUserNotificationState() }
+}
+
+// any Focus Assist profile, or presenting. fullscreen apps and games are left alone, and what
+// can't be queried reads as not quiet
+fn is_quiet(profile: Option<FocusProfile>, state: Option<QUERY_USER_NOTIFICATION_STATE>) -> bool {
+  profile.is_some_and(|profile| profile != FocusProfile::Off)
+    || state == Some(QUNS_PRESENTATION_MODE)
+}
+
+#[cfg(test)]
+mod tests {
+  use windows::Win32::UI::Shell::{QUNS_ACCEPTS_NOTIFICATIONS, QUNS_BUSY, QUNS_QUIET_TIME};
+
+  use super::*;
+
+  #[test]
+  fn profiles() {
+    assert_eq!(FocusProfile::from_raw(0), FocusProfile::Off);
+    assert_eq!(FocusProfile::from_raw(1), FocusProfile::PriorityOnly);
+    assert_eq!(FocusProfile::from_raw(2), FocusProfile::AlarmsOnly);
+  }
+
+  #[test]
+  fn focus_assist_is_quiet() {
+    let accepts = Some(QUNS_ACCEPTS_NOTIFICATIONS);
+    assert!(!is_quiet(Some(FocusProfile::Off), accepts));
+    assert!(is_quiet(Some(FocusProfile::PriorityOnly), accepts));
+    assert!(is_quiet(Some(FocusProfile::AlarmsOnly), accepts));
+    assert!(is_quiet(Some(FocusProfile::AlarmsOnly), None));
+  }
+
+  #[test]
+  fn presenting_is_quiet() {
+    assert!(is_quiet(
+      Some(FocusProfile::Off),
+      Some(QUNS_PRESENTATION_MODE)
+    ));
+    assert!(is_quiet(None, Some(QUNS_PRESENTATION_MODE)));
+  }
+
+  #[test]
+  fn fullscreen_and_first_run_are_not_quiet() {
+    // a fullscreen app, and the hour after setting up Windows
+    assert!(!is_quiet(Some(FocusProfile::Off), Some(QUNS_BUSY)));
+    assert!(!is_quiet(Some(FocusProfile::Off), Some(QUNS_QUIET_TIME)));
+    assert!(!is_quiet(None, None));
+  }
+}
@@ -0,0 +1,108 @@
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+  RegisterHotKey, UnregisterHotKey, HOT_KEY_MODIFIERS, MOD_ALT, MOD_CONTROL, MOD_NOREPEAT,
+  MOD_SHIFT, MOD_WIN, VK_F1,
+};
+
+use crate::{
+  config::HotkeyConfig,
+  control::{self, ControlCommand},
+  notify::{self, Category},
+};
+
+// the ids passed to RegisterHotKey, and the command each one sends
+const HOTKEYS: [(i32, ControlCommand); 1] = [(1, ControlCommand::TogglePause)];
+
+/// The command for a `WM_HOTKEY` id.
+pub fn command(id: usize) -> Option<ControlCommand> {
+  HOTKEYS
+    .iter()
+    .find(|(hotkey, _)| *hotkey as usize == id)
+    .map(|(_, command)| *command)
+}
+
+/// Register the configured hotkeys on the control window, replacing the previous ones.
+///
+/// Call after `control::listen`, on the same thread. Problems are logged and shown.
+pub fn register(config: &HotkeyConfig) {
+  unregister();
+  let Some(window) = control::window() else {
+    log::warn!("[hotkey] no control window to register on");
+    return;
+  };
+
+  for (id, command) in HOTKEYS {
+    let spec = match command {
+      ControlCommand::TogglePause => config.pause.as_deref(),
+      _ => None,
+    };
+    let Some(spec) = spec.filter(|spec| !spec.trim().is_empty()) else {
+      continue;
+    };
+
+    let result = parse(spec).and_then(|(modifiers, key)| unsafe {
+      RegisterHotKey(window, id, modifiers | MOD_NOREPEAT, key)
+        .map_err(|err| format!("{} is taken by another app: {}", spec, err))
+    });
+    match result {
+      Ok(()) => log::info!("[hotkey] registered {} for {:?}", spec, command),
+      Err(err) => {
+        log::error!("[hotkey] {}", err);
+        notify::show(Category::Error, "Hotkey", &err);
+      }
+    }
+  }
+}
+
+/// Release every hotkey, before exiting.
+pub fn unregister() {
+  let Some(window) = control::window() else {
+    return;
+  };
+  for (id, _) in HOTKEYS {
+    unsafe {
+      let _ = UnregisterHotKey(window, id);
+    }
+  }
+}
+
+/// Parse a hotkey like `Ctrl+Alt+P` into modifiers and a virtual key.
+///
+/// Keys are letters, digits or `F1` to `F24`, modifiers are `Ctrl`, `Alt`, `Shift` and `Win`.
+pub fn parse(spec: &str) -> Result<(HOT_KEY_MODIFIERS, u32), String> {
+  let mut modifiers = HOT_KEY_MODIFIERS(0);
+  let mut key = None;
+  for part in spec.split('+').map(|part| part.trim().to_uppercase()) {
+    let modifier = match part.as_str() {
+      "CTRL" | "CONTROL" => MOD_CONTROL,
+      "ALT" => MOD_ALT,
+      "SHIFT" => MOD_SHIFT,
+      "WIN" => MOD_WIN,
+      _ => {
+        if key.is_some() {
+          return Err(format!("{} has more than one key", spec));
+        }
+        key = Some(virtual_key(&part).ok_or_else(|| format!("{} has an unknown key", spec))?);
+        continue;
+      }
+    };
+    modifiers |= modifier;
+  }
+
+  let key = key.ok_or_else(|| format!("{} has no key", spec))?;
+  if modifiers.0 == 0 {
+    return Err(format!("{} needs a modifier", spec));
+  }
+  Ok((modifiers, key))
+}
+
+fn virtual_key(name: &str) -> Option<u32> {
+  if name.len() == 1 {
+    // letters and digits share their virtual key codes with ascii
+    let char = name.chars().next()?;
+    return char.is_ascii_alphanumeric().then_some(char as u32);
+  }
+  let number = name.strip_prefix('F')?.parse::<u32>().ok()?;
+  (1..=24)
+    .contains(&number)
+    .then_some(VK_F1.0 as u32 + number - 1)
+}
@@ -0,0 +1,181 @@
+use std::{
+  io::{self, BufRead, BufReader, Read, Write},
+  net::{Ipv4Addr, TcpListener, TcpStream},
+  sync::atomic::{AtomicBool, Ordering},
+  thread,
+  time::Duration,
+};
+
+use crate::{
+  deamon::StateEvents,
+  pipe::{PipeReply, PipeRequest},
+  websocket,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+// the request line and headers together, no route reads a body
+const MAX_HEAD_SIZE: u64 = 8 * 1024;
+
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Serve the status and a few commands on `127.0.0.1:port`, answered by `handler` like the pipe.
+///
+/// * `GET /status`
+/// * `POST /pause`, `POST /resume`
+/// * `GET /events`, a WebSocket pushing every `StateEvent`
+///
+/// Only requests addressed to `127.0.0.1:port` or `localhost:port` are answered, and none that
+/// come from a web page, so a page can't reach the commands through the browser. The WebSocket is
+/// the exception to the latter, browsers send an `Origin` with every upgrade and it only reads.
+pub fn listen(
+  port: u16,
+  events: StateEvents,
+  handler: impl Fn(PipeRequest) -> PipeReply + Send + 'static,
+) {
+  let listener = match bind(port) {
+    Ok(listener) => listener,
+    Err(err) => {
+      log::error!("[http] failed to listen on port {}: {}", port, err);
+      return;
+    }
+  };
+
+  log::info!("[http] listening on 127.0.0.1:{}", port);
+  thread::spawn(move || {
+    while !STOPPED.load(Ordering::Relaxed) {
+      match listener.accept() {
+        Ok((stream, address)) if address.ip().is_loopback() => {
+          if let Err(err) = serve(stream, &events, &handler) {
+            log::warn!("[http] failed to answer {}: {}", address, err);
+          }
+        }
+        Ok((_, address)) => log::warn!("[http] rejected {}", address),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+        Err(err) => log::warn!("[http] failed to accept: {}", err),
+      }
+    }
+    log::info!("[http] stopped");
+  });
+}
+
+/// Stop serving, the listener thread notices within a poll interval.
+pub fn stop() {
+  STOPPED.store(true, Ordering::Relaxed);
+}
+
+fn bind(port: u16) -> io::Result<TcpListener> {
+  let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+  // poll, so the thread can stop with the app
+  listener.set_nonblocking(true)?;
+  Ok(listener)
+}
+
+// answer a single request and close the connection, or hand it to a websocket thread
+fn serve(
+  stream: TcpStream,
+  events: &StateEvents,
+  handler: &impl Fn(PipeRequest) -> PipeReply,
+) -> io::Result<()> {
+  stream.set_nonblocking(false)?;
+  stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+  let mut reader = BufReader::new(stream.try_clone()?).take(MAX_HEAD_SIZE);
+  let mut request_line = String::new();
+  reader.read_line(&mut request_line)?;
+  let mut websocket_key = None;
+  let mut host = None;
+  let mut origin = None;
+  let mut header = String::new();
+  let mut complete = false;
+  while reader.read_line(&mut header)? > 0 {
+    if header.trim().is_empty() {
+      complete = true;
+      break;
+    }
+    if let Some((name, value)) = header.split_once(':') {
+      let value = Some(value.trim().to_string());
+      match name.trim().to_ascii_lowercase().as_str() {
+        "sec-websocket-key" => websocket_key = value,
+        "host" => host = value,
+        "origin" => origin = value,
+        _ => {}
+      }
+    }
+    header.clear();
+  }
+
+  let mut parts = request_line.split_whitespace();
+  let method = parts.next().unwrap_or_default();
+  let path = parts.next().unwrap_or_default();
+  let port = stream.local_addr()?.port();
+
+  let rejected = if !complete {
+    Some(("431 Request Header Fields Too Large", "request too large"))
+  } else if !host
+    .as_deref()
+    .is_some_and(|host| is_local_host(host, port))
+  {
+    Some(("403 Forbidden", "unexpected host"))
+  } else if origin.is_some() && !(method == "GET" && path == "/events") {
+    Some(("403 Forbidden", "requests from web pages are not accepted"))
+  } else {
+    None
+  };
+  if let Some((status, reason)) = rejected {
+    log::warn!("[http] rejected {} {}: {}", method, path, reason);
+    return respond(stream, status, &PipeReply::error(reason));
+  }
+
+  if let (("GET", "/events"), Some(key)) = ((method, path), &websocket_key) {
+    let key = key.clone();
+    let events = events.subscribe();
+    thread::spawn(move || {
+      let stopped = || STOPPED.load(Ordering::Relaxed);
+      if let Err(err) = websocket::push(stream, &key, events, stopped) {
+        log::info!("[http] websocket closed: {}", err);
+      }
+    });
+    return Ok(());
+  }
+  let request = match (method, path) {
+    ("GET", "/status") => Some(PipeRequest::QueryStatus),
+    ("POST", "/pause") => Some(PipeRequest::Pause),
+    ("POST", "/resume") => Some(PipeRequest::Resume),
+    _ => None,
+  };
+
+  let (status, reply) = match request {
+    Some(request) => {
+      let reply = handler(request);
+      let status = if reply.ok {
+        "200 OK"
+      } else {
+        "400 Bad Request"
+      };
+      (status, reply)
+    }
+    None => ("404 Not Found", PipeReply::error("unknown route")),
+  };
+  respond(stream, status, &reply)
+}
+
+fn respond(mut stream: TcpStream, status: &str, reply: &PipeReply) -> io::Result<()> {
+  let body = serde_json::to_string(reply)?;
+  write!(
+    stream,
+    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    body.len(),
+    body
+  )?;
+  stream.flush()
+}
+
+// whether a `Host` header names this server, rather than a name rebound to 127.0.0.1
+fn is_local_host(host: &str, port: u16) -> bool {
+  let Some((name, host_port)) = host.rsplit_once(':') else {
+    return false;
+  };
+  host_port == port.to_string() && (name == "127.0.0.1" || name.eq_ignore_ascii_case("localhost"))
+}
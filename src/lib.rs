@@ -0,0 +1,26 @@
+pub mod autolaunch;
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod crash;
+pub mod deamon;
+pub mod diagnostics;
+pub mod focus;
+pub mod hotkey;
+pub mod http;
+pub mod log_file;
+pub mod menu;
+pub mod metrics;
+pub mod notify;
+pub mod pipe;
+pub mod settings;
+pub mod smtc;
+pub mod state;
+pub mod system_ducking;
+pub mod task;
+pub mod update;
+pub mod websocket;
+pub mod winmix;
+
+pub const APP_NAME: &str = "Sound Priority";
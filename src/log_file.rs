@@ -0,0 +1,338 @@
+use std::{
+  env,
+  fmt::Display,
+  fs::{self, File, OpenOptions},
+  io::{self, BufRead, BufReader, Write},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Mutex,
+  },
+};
+
+use chrono::{DateTime, Local};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use windows::{
+  core::{w, HSTRING, PCWSTR},
+  Win32::{Foundation::HWND, UI::Shell::ShellExecuteW, UI::WindowsAndMessaging::SW_SHOWNORMAL},
+};
+
+const FILE_PREFIX: &str = "sound-priority.";
+const FILE_SUFFIX: &str = ".log";
+// sorts by time, and is allowed in a file name
+const RUN_FORMAT: &str = "%Y%m%d-%H%M%S";
+// the first line of a run is `===== <version> started at <rfc3339> =====`
+const STARTED_AT: &str = " started at ";
+
+// the limits, changed by `configure` once the config is read
+static MAX_SIZE: AtomicU64 = AtomicU64::new(5 * 1024 * 1024);
+static ROTATIONS: AtomicUsize = AtomicUsize::new(3);
+// bumped by `start_run`, an open file from an older run is reopened
+static RUN: AtomicUsize = AtomicUsize::new(0);
+// the first line of the current run, repeated at the top of every file rotated into
+static MARKER: Mutex<Option<String>> = Mutex::new(None);
+
+/// The log of the current run, `sound-priority.log` next to the exe.
+pub fn path() -> PathBuf {
+  env::current_exe()
+    .unwrap()
+    .with_file_name("sound-priority.log")
+}
+
+/// Rotate the log past `max_size` bytes, keeping `rotations` old files like
+/// `sound-priority.log.1`, and keep the logs of the last `runs` runs.
+pub fn configure(max_size: u64, rotations: usize, runs: usize) {
+  MAX_SIZE.store(max_size, Ordering::Relaxed);
+  ROTATIONS.store(rotations, Ordering::Relaxed);
+  prune(&path(), runs);
+}
+
+/// Move the log of the previous run aside, as `sound-priority.<started>.log` along with the files
+/// it rotated into, and start a new one.
+///
+/// Only for the instance that keeps running, a second one would take the log from under it.
+pub fn start_run(version: &str) {
+  let path = path();
+  if path.exists() {
+    let archived = archive_path(&path, started(&path));
+    match fs::rename(&path, &archived) {
+      Ok(()) => {
+        RUN.fetch_add(1, Ordering::Relaxed);
+        for index in 1.. {
+          let from = rotated(&path, index);
+          if !from.exists() {
+            break;
+          }
+          if let Err(err) = fs::rename(&from, rotated(&archived, index)) {
+            log::warn!("[log_file] failed to move {}: {}", from.display(), err);
+          }
+        }
+      }
+      Err(err) => log::warn!("[log_file] failed to move the last run: {}", err),
+    }
+  }
+
+  let marker = format!(
+    "[log_file] ===== {}{}{} =====",
+    version,
+    STARTED_AT,
+    Local::now().to_rfc3339()
+  );
+  if let Ok(mut current) = MARKER.lock() {
+    *current = Some(marker.clone());
+  }
+  log::info!("{}", marker);
+}
+
+/// Open the current log in the default editor.
+pub fn open() {
+  let path = HSTRING::from(path().as_os_str());
+  unsafe {
+    ShellExecuteW(
+      HWND::default(),
+      w!("open"),
+      &path,
+      PCWSTR::null(),
+      PCWSTR::null(),
+      SW_SHOWNORMAL,
+    );
+  }
+}
+
+/// The log file, rotated by size, see `configure`.
+///
+/// Every line is written through, so a crash loses nothing.
+pub struct RotatingLog {
+  path: PathBuf,
+  level: LevelFilter,
+  datetime_format: String,
+  // the file with its size and the run it was opened in
+  file: Mutex<Option<(File, u64, usize)>>,
+}
+
+impl RotatingLog {
+  pub fn new(path: &Path, level: LevelFilter, datetime_format: &str) -> Self {
+    Self {
+      path: path.to_path_buf(),
+      level,
+      datetime_format: datetime_format.to_string(),
+      file: Mutex::new(None),
+    }
+  }
+  fn write(&self, line: &str) -> io::Result<()> {
+    let Ok(mut file) = self.file.lock() else {
+      return Ok(());
+    };
+    let run = RUN.load(Ordering::Relaxed);
+    if file.as_ref().is_some_and(|(_, _, opened)| *opened != run) {
+      *file = None;
+    }
+    let (writer, size, _) = match &mut *file {
+      Some(file) => file,
+      None => file.insert(self.open(run)?),
+    };
+    writer.write_all(line.as_bytes())?;
+    *size += line.len() as u64;
+
+    if *size >= MAX_SIZE.load(Ordering::Relaxed) {
+      // close before renaming
+      *file = None;
+      rotate(&self.path, ROTATIONS.load(Ordering::Relaxed))?;
+
+      // so the next run can tell when this one started from any of its files
+      let marker = MARKER.lock().ok().and_then(|marker| marker.clone());
+      if let Some(marker) = marker {
+        let (writer, size, _) = file.insert(self.open(run)?);
+        let line = self.format(Level::Info, &marker);
+        writer.write_all(line.as_bytes())?;
+        *size += line.len() as u64;
+      }
+    }
+    Ok(())
+  }
+  fn open(&self, run: usize) -> io::Result<(File, u64, usize)> {
+    let writer = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)?;
+    let size = writer.metadata()?.len();
+    Ok((writer, size, run))
+  }
+  fn format(&self, level: Level, args: &dyn Display) -> String {
+    let time = chrono::Local::now().format(&self.datetime_format);
+    format!("{} {} {}\n", time, level, args)
+  }
+}
+
+impl Log for RotatingLog {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= self.level
+  }
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    let line = self.format(record.level(), record.args());
+    if let Err(err) = self.write(&line) {
+      // nowhere else to report it
+      eprintln!("failed to write the log: {}", err);
+    }
+  }
+  fn flush(&self) {}
+}
+
+// shift `name.1` to `name.2` and so on, dropping the oldest, then move the log to `name.1`
+fn rotate(path: &Path, rotations: usize) -> io::Result<()> {
+  if rotations == 0 {
+    return fs::remove_file(path);
+  }
+  let _ = fs::remove_file(rotated(path, rotations));
+  for index in (1..rotations).rev() {
+    let _ = fs::rename(rotated(path, index), rotated(path, index + 1));
+  }
+  fs::rename(path, rotated(path, 1))
+}
+
+// `name.<index>`
+fn rotated(path: &Path, index: usize) -> PathBuf {
+  let mut name = path.as_os_str().to_os_string();
+  name.push(format!(".{}", index));
+  PathBuf::from(name)
+}
+
+// `sound-priority.<started>.log`, with a counter when a run that started in the same second was
+// archived already
+fn archive_path(path: &Path, started: DateTime<Local>) -> PathBuf {
+  let started = started.format(RUN_FORMAT).to_string();
+  let mut archived = path.with_file_name(format!("{}{}{}", FILE_PREFIX, started, FILE_SUFFIX));
+  let mut count = 1;
+  while archived.exists() {
+    archived = path.with_file_name(format!(
+      "{}{}-{}{}",
+      FILE_PREFIX, started, count, FILE_SUFFIX
+    ));
+    count += 1;
+  }
+  archived
+}
+
+// when the run logged to `path` started, falling back to its last write.
+// the creation time can't be used, Windows hands it on to a file created under the same name
+fn started(path: &Path) -> DateTime<Local> {
+  let marked = File::open(path).ok().and_then(|file| {
+    BufReader::new(file)
+      .lines()
+      .map_while(Result::ok)
+      .find_map(|line| {
+        let (_, time) = line.split_once(STARTED_AT)?;
+        let time = time.trim_end_matches(" =====");
+        DateTime::parse_from_rfc3339(time).ok()
+      })
+  });
+  match marked {
+    Some(time) => time.with_timezone(&Local),
+    None => fs::metadata(path)
+      .and_then(|metadata| metadata.modified())
+      .map(DateTime::<Local>::from)
+      .unwrap_or_else(|_| Local::now()),
+  }
+}
+
+// delete all but the newest `runs` logs of previous runs, leaving any that can't be removed
+fn prune(path: &Path, runs: usize) {
+  let Some(dir) = path.parent() else {
+    return;
+  };
+  let Ok(entries) = fs::read_dir(dir) else {
+    return;
+  };
+  let paths = entries
+    .filter_map(Result::ok)
+    .map(|entry| entry.path())
+    .collect::<Vec<_>>();
+  let mut archived = paths
+    .iter()
+    .filter(|path| is_archived(path))
+    .collect::<Vec<_>>();
+  // the names sort by when the run started, without the suffix a counter goes after the run
+  // it was added for
+  archived.sort_by_key(|path| path.file_stem().map(|stem| stem.to_os_string()));
+  let excess = archived.len().saturating_sub(runs);
+  for run in archived.into_iter().take(excess) {
+    // along with the files the run rotated into
+    let removed = paths
+      .iter()
+      .filter(|path| *path == run || is_rotated_from(path, run));
+    for path in removed {
+      if let Err(err) = fs::remove_file(path) {
+        log::warn!("[log_file] failed to remove {}: {}", path.display(), err);
+      }
+    }
+  }
+}
+
+// `<log>.<index>`, as `rotate` names them
+fn is_rotated_from(path: &Path, log: &Path) -> bool {
+  let (Some(name), Some(log)) = (
+    path.file_name().and_then(|name| name.to_str()),
+    log.file_name().and_then(|name| name.to_str()),
+  ) else {
+    return false;
+  };
+  name
+    .strip_prefix(log)
+    .and_then(|index| index.strip_prefix('.'))
+    .is_some_and(|index| !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()))
+}
+
+// `sound-priority.<started>.log`, not the current log or one rotated by size
+fn is_archived(path: &Path) -> bool {
+  let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+    return false;
+  };
+  name
+    .strip_prefix(FILE_PREFIX)
+    .and_then(|name| name.strip_suffix(FILE_SUFFIX))
+    .is_some_and(|started| {
+      !started.is_empty() && started.chars().all(|c| c.is_ascii_digit() || c == '-')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn archived_runs() {
+    assert!(is_archived(Path::new("sound-priority.20240101-120000.log")));
+    assert!(is_archived(Path::new(
+      "sound-priority.20240101-120000-1.log"
+    )));
+    assert!(!is_archived(Path::new("sound-priority.log")));
+    assert!(!is_archived(Path::new(
+      "sound-priority.20240101-120000.log.1"
+    )));
+  }
+
+  #[test]
+  fn rotated_files_of_a_run() {
+    let run = Path::new("sound-priority.20240101-120000.log");
+    assert!(is_rotated_from(
+      Path::new("sound-priority.20240101-120000.log.1"),
+      run
+    ));
+    assert!(is_rotated_from(
+      Path::new("sound-priority.20240101-120000.log.12"),
+      run
+    ));
+    assert!(!is_rotated_from(run, run));
+    assert!(!is_rotated_from(
+      Path::new("sound-priority.20240101-120000.log.bak"),
+      run
+    ));
+    assert!(!is_rotated_from(
+      Path::new("sound-priority.20240101-120001.log.1"),
+      run
+    ));
+  }
+}
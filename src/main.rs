@@ -1,20 +1,43 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-pub mod config;
-pub mod deamon;
-pub mod menu;
-pub mod settings;
-pub mod winmix;
-
-use std::fs;
+use std::collections::VecDeque;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 use std::vec::IntoIter;
 
-use config::Config;
-use deamon::Deamon;
 use ftail::Ftail;
-use menu::MenuSystem;
-use settings::Settings;
 use single_instance::SingleInstance;
+use sound_priority::cli;
+use sound_priority::config::Config;
+use sound_priority::config::RestoreTarget;
+use sound_priority::control;
+use sound_priority::control::ControlCommand;
+use sound_priority::crash;
+use sound_priority::deamon::Deamon;
+use sound_priority::deamon::StateEvent;
+use sound_priority::diagnostics;
+use sound_priority::hotkey;
+use sound_priority::http;
+use sound_priority::log_file;
+use sound_priority::log_file::RotatingLog;
+use sound_priority::menu::CustomAction;
+use sound_priority::menu::MenuSystem;
+use sound_priority::notify;
+use sound_priority::notify::Category;
+use sound_priority::pipe;
+use sound_priority::pipe::PipeReply;
+use sound_priority::pipe::PipeRequest;
+use sound_priority::pipe::PipeStatus;
+use sound_priority::settings::Settings;
+use sound_priority::system_ducking;
+use sound_priority::system_ducking::SystemDucking;
+use sound_priority::update;
+use sound_priority::winmix::WinMix;
+use sound_priority::APP_NAME;
 use tray_icon::menu::MenuEvent;
 use winit::application::ApplicationHandler;
 use winit::event::DeviceEvent;
@@ -23,40 +46,176 @@ use winit::event::WindowEvent;
 use winit::event_loop::ActiveEventLoop;
 use winit::event_loop::ControlFlow;
 use winit::event_loop::EventLoop;
+use winit::event_loop::EventLoopProxy;
 use winit::window::WindowId;
 
-pub const APP_NAME: &str = "Sound Priority";
+const TOOLTIP_INTERVAL: Duration = Duration::from_millis(100);
+const PIPE_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const LOG_DATETIME_FORMAT: &str = "%m-%d %H:%M:%S";
+// clicks on custom items kept for scripts that don't poll
+const MAX_CUSTOM_ACTIONS: usize = 32;
+
+/// Events delivered to the app from other threads.
+enum AppEvent {
+  Control(ControlCommand),
+  Pipe(PipeRequest, SyncSender<PipeReply>),
+  /// The daemon reduced or restored, refresh the tray.
+  State(StateEvent),
+}
 
 fn main() {
-  start_logger();
+  let args = std::env::args().skip(1).collect::<Vec<_>>();
+  if let Some(code) = cli::run(&args) {
+    std::process::exit(code);
+  }
+
+  start_logger(cli::is_verbose(&args));
+
+  let headless = cli::is_headless(&args);
+  let command = ControlCommand::from_args(args);
 
   let instance = SingleInstance::new(APP_NAME).unwrap();
   if !instance.is_single() {
     log::info!("[main] detected another instance");
+    let Some(command) = command else {
+      // a plain launch, point the user at the running instance
+      if !control::send(ControlCommand::Show) {
+        log::warn!("[main] failed to reach the running instance");
+        notify::message_box(
+          "Already running",
+          "Sound Priority is already running, look for its icon in the tray.",
+        );
+      }
+      return;
+    };
+    let delivered = control::send(command);
+    log::info!(
+      "[main] sent {:?} to the running instance: {}",
+      command,
+      delivered
+    );
+    std::process::exit(if delivered { 0 } else { 1 });
+  }
+  if command == Some(ControlCommand::Exit) {
+    log::info!("[main] nothing to exit");
     return;
   }
+  let paused = command == Some(ControlCommand::Pause);
+
+  log_file::start_run(&cli::version());
 
   log::info!("[main] loading config");
   let config = Config::load().unwrap_or_default();
+  log_file::configure(
+    config.log_max_size_kb * 1024,
+    config.log_rotations,
+    config.log_runs,
+  );
 
   log::info!("[main] loading settings");
   let settings = Settings::new(config.clone());
+  settings.repair_autolaunch();
+
+  // a run that crashed or was logged off never put the user's preference back
+  if let Err(err) = SystemDucking::recover() {
+    log::warn!("[main] failed to restore system ducking: {}", err);
+  }
+  let system_ducking = if config.disable_system_ducking {
+    SystemDucking::disable()
+      .inspect_err(|err| log::warn!("[main] failed to turn off system ducking: {}", err))
+      .ok()
+  } else {
+    if system_ducking::is_active() {
+      log::warn!(
+        "[main] Windows also reduces other sounds during calls, the targets may be reduced twice. \
+         Set disable_system_ducking to turn it off"
+      );
+    }
+    None
+  };
+
+  if config.check_for_updates {
+    update::check(false);
+  }
 
-  log::info!("[main] loading menu");
-  let mut menu = MenuSystem::new();
+  // without a tray, everything goes through the command line and the pipe
+  let menu = if headless || config.headless {
+    log::info!("[main] headless, skip menu");
+    None
+  } else {
+    log::info!("[main] loading menu");
+    let winmix = Arc::new(WinMix::default());
+    let mut menu = MenuSystem::new(winmix, &config.icons);
+    menu.set_paused(paused);
 
-  log::info!("[main] update menu");
-  menu.update(&settings);
+    log::info!("[main] update menu");
+    menu.update_loading(&settings);
+    Some(menu)
+  };
 
   log::info!("[main] start daemon");
   let daemon = Deamon::create(config);
+  if paused {
+    daemon.stop();
+  }
 
   log::info!("[main] start create event loop");
-  let event_loop = EventLoop::builder().build().unwrap();
+  let event_loop = EventLoop::with_user_event().build().unwrap();
   event_loop.set_control_flow(ControlFlow::Wait);
 
+  log::info!("[main] listen for commands");
+  let proxy = event_loop.create_proxy();
+  let listening = control::listen(move |command| {
+    let _ = proxy.send_event(AppEvent::Control(command));
+  });
+  if !listening {
+    log::warn!("[main] failed to listen for commands");
+  }
+  if menu.is_none() {
+    control::exit_on_ctrl_c();
+  }
+  // the event loop doesn't get to exit on logoff and shutdown
+  control::on_end_session(|| {
+    if let Err(err) = SystemDucking::recover() {
+      log::warn!("[main] failed to restore system ducking: {}", err);
+    }
+  });
+  hotkey::register(&settings.config().hotkeys);
+
+  let proxy = event_loop.create_proxy();
+  control::on_request(move |request| {
+    // nobody waits for the reply
+    let (reply, _) = sync_channel(1);
+    let _ = proxy.send_event(AppEvent::Pipe(request, reply));
+  });
+
+  pipe::listen(request_handler(event_loop.create_proxy()));
+  if let Some(port) = settings.config().http_port {
+    http::listen(
+      port,
+      daemon.events(),
+      request_handler(event_loop.create_proxy()),
+    );
+  }
+
+  // raw input is what drives the tray otherwise, a duck while nobody touches the mouse or
+  // keyboard would go unseen
+  if menu.is_some() {
+    let events = daemon.events().subscribe();
+    let proxy = event_loop.create_proxy();
+    thread::spawn(move || {
+      for event in events {
+        if proxy.send_event(AppEvent::State(event)).is_err() {
+          break;
+        }
+      }
+    });
+  }
+
   log::info!("[main] start create app");
   let mut app = App::new(daemon, settings, menu);
+  app.paused = paused;
+  app.system_ducking = system_ducking;
 
   log::info!("[main] mount app");
   event_loop.run_app(&mut app).unwrap();
@@ -65,36 +224,173 @@ fn main() {
 struct App {
   pub daemon: Deamon,
   pub settings: Settings,
-  pub menu: MenuSystem,
+  /// `None` when headless.
+  pub menu: Option<MenuSystem>,
+  tooltip_updated: Instant,
+  update_shown: bool,
+  paused: bool,
+  // the screen is locked or the system asleep, see `Config::restore_while_away`
+  locked: bool,
+  asleep: bool,
+  /// Put back on exit.
+  system_ducking: Option<SystemDucking>,
+  /// Clicks on the items added by scripts, until they take them.
+  custom_actions: VecDeque<CustomAction>,
 }
 
 impl App {
-  fn new(daemon: Deamon, settings: Settings, menu: MenuSystem) -> Self {
+  fn new(daemon: Deamon, settings: Settings, menu: Option<MenuSystem>) -> Self {
     Self {
       daemon,
       settings,
       menu,
+      tooltip_updated: Instant::now(),
+      update_shown: false,
+      paused: false,
+      locked: false,
+      asleep: false,
+      system_ducking: None,
+      custom_actions: VecDeque::new(),
+    }
+  }
+  fn set_paused(&mut self, paused: bool) {
+    if paused {
+      self.daemon.stop();
+    } else {
+      self.daemon.start();
+    }
+    self.paused = paused;
+    if let Some(menu) = &mut self.menu {
+      menu.set_paused(paused);
+    }
+  }
+  fn set_away(&mut self, locked: bool, asleep: bool) {
+    let was_away = self.locked || self.asleep;
+    self.locked = locked;
+    self.asleep = asleep;
+    let away = locked || asleep;
+    if away == was_away || self.paused || !self.settings.config().restore_while_away {
+      return;
+    }
+    if away {
+      self.daemon.away();
+    } else {
+      self.daemon.start();
+    }
+  }
+  /// Read the config from disk again, keeping the current one if it can't be read.
+  fn reload_config(&mut self) -> Result<(), String> {
+    match self.settings.reload() {
+      Ok(()) => {
+        self.daemon.update(self.settings.config());
+        hotkey::register(&self.settings.config().hotkeys);
+        Ok(())
+      }
+      Err(err) => {
+        log::error!("[main] {}", err);
+        Err(err.to_string())
+      }
+    }
+  }
+  fn update_menu(&mut self) {
+    if let Some(menu) = &mut self.menu {
+      menu.update(&self.settings, self.daemon.snapshot().status);
+    }
+  }
+  fn handle_pipe(&mut self, request: PipeRequest) -> PipeReply {
+    match request {
+      PipeRequest::Pause => self.set_paused(true),
+      PipeRequest::Resume => self.set_paused(false),
+      PipeRequest::SafeMode { enabled } => self.set_safe_mode(enabled),
+      PipeRequest::SetConfigField { field, value } => {
+        if let Err(err) = self.settings.set_field(&field, value) {
+          return PipeReply::error(err.to_string());
+        }
+        self.daemon.update(self.settings.config());
+      }
+      PipeRequest::ListSessions => {
+        return PipeReply::sessions(self.daemon.get_sessions_snapshot());
+      }
+      PipeRequest::SetCustomMenuItems { items } => {
+        if let Some(menu) = &mut self.menu {
+          menu.set_custom_items(items);
+        }
+      }
+      PipeRequest::RefreshSessions => self.daemon.refresh_sessions(),
+      PipeRequest::AddTarget { name } => {
+        self.settings.add_target(&name);
+        self.daemon.update(self.settings.config());
+      }
+      PipeRequest::AddExclude { name } => {
+        self.settings.add_exclude(&name);
+        self.daemon.update(self.settings.config());
+      }
+      PipeRequest::ReloadConfig => {
+        if let Err(err) = self.reload_config() {
+          return PipeReply::error(err);
+        }
+      }
+      PipeRequest::TakeCustomActions => {
+        return PipeReply::actions(self.custom_actions.drain(..).collect());
+      }
+      PipeRequest::QueryStatus => {
+        return PipeReply::status(PipeStatus {
+          paused: self.paused,
+          daemon: self.daemon.snapshot(),
+        });
+      }
     }
+    self.update_menu();
+    PipeReply::ok()
+  }
+  fn set_safe_mode(&mut self, safe_mode: bool) {
+    self.daemon.set_safe_mode(safe_mode);
+    if let Some(menu) = &mut self.menu {
+      menu.set_safe_mode(safe_mode);
+    }
+  }
+  fn show(&mut self) {
+    let message = match &self.menu {
+      Some(menu) => {
+        menu.show();
+        "Already running, look for the icon here."
+      }
+      None => "Already running without a tray icon, control it from the command line.",
+    };
+    notify::show(Category::AlreadyRunning, APP_NAME, message);
+  }
+  fn exit(&mut self) -> ! {
+    http::stop();
+    hotkey::unregister();
+    self.settings.save();
+    if let Err(err) = self.daemon.shutdown() {
+      log::warn!("[main] daemon did not shut down in time: {}", err);
+    }
+    if let Some(system_ducking) = self.system_ducking.take() {
+      if let Err(err) = system_ducking.restore() {
+        log::warn!("[main] failed to restore system ducking: {}", err);
+      }
+    }
+    std::process::exit(0)
   }
   fn click_menu_item(&mut self, event: MenuEvent) -> bool {
     let id = event.id().0.as_str();
     let idents = id.split('.').collect::<Vec<_>>();
     let mut idents = idents.into_iter();
-    
+
     log::info!("[main] click menu item: {}", id);
     match idents.next().unwrap_or_default() {
       "volume" => {
         let ident = idents.next().unwrap();
-        let volume = get_slider_valuee(idents);
-        let config = &mut self.settings.config;
+        let volume =
+          get_slider_value_fine(&mut idents.clone()).unwrap_or_else(|| get_slider_valuee(idents));
         match ident {
-          "sensitivity" => config.sensitivity = volume,
-          "restore" => config.resotre_volume = volume,
-          "reduce" => config.reduce_volume = volume,
+          "sensitivity" => self.settings.set_sensitivity(volume),
+          "restore" => self.settings.set_restore_volume(volume),
+          "reduce" => self.settings.set_reduce_volume(volume),
           _ => unimplemented!(),
         }
-        let _ = config.save();
-        self.daemon.update(&config);
+        self.daemon.update(self.settings.config());
       }
       "apps" => {
         let app_name = idents.next().unwrap();
@@ -103,23 +399,146 @@ impl App {
           "target" => self.settings.select_target(app_name),
           _ => unimplemented!(),
         }
-        self.daemon.update(&self.settings.config);
+        self.daemon.update(self.settings.config());
       }
       "settings" => match idents.next().unwrap() {
         "autolaunch" => {
-          let autolaunch = self.settings.get_autolaunch();
-          self.settings.set_autolaunch(!autolaunch);
+          let result = self
+            .settings
+            .get_autolaunch()
+            .and_then(|autolaunch| self.settings.set_autolaunch(!autolaunch));
+          if let Err(err) = result {
+            log::error!("[main] failed to toggle autolaunch: {}", err);
+            notify::show(
+              Category::Error,
+              "Launch on startup",
+              &format!("Failed to change launch on startup: {}", err),
+            );
+          }
+        }
+        "restore_target" => {
+          let restore_target = match idents.next().unwrap() {
+            "full" => RestoreTarget::Full,
+            "previous" => RestoreTarget::Previous,
+            "fixed" => RestoreTarget::Fixed,
+            _ => unimplemented!(),
+          };
+          self.settings.set_restore_target(restore_target);
+          self.daemon.update(self.settings.config());
+        }
+        "peak_source" => {
+          let peak_source = self.settings.config().detection.source.next();
+          self.settings.set_peak_source(peak_source);
+          self.daemon.update(self.settings.config());
+        }
+        "exclude_foreground" => {
+          let exclude_foreground = !self.settings.config().exclude_foreground;
+          self.settings.set_exclude_foreground(exclude_foreground);
+          self.daemon.update(self.settings.config());
+        }
+        "apply_to_all_sessions" => {
+          let apply_to_all_sessions = !self.settings.config().apply_to_all_sessions;
+          self
+            .settings
+            .set_apply_to_all_sessions(apply_to_all_sessions);
+          self.daemon.update(self.settings.config());
+          self.update_menu();
+        }
+        "startup_delay" => {
+          let secs = idents.next().unwrap().parse().unwrap();
+          self.settings.set_startup_delay(secs);
+          self.daemon.update(self.settings.config());
+        }
+        "check_for_updates" => {
+          let check_for_updates = !self.settings.config().check_for_updates;
+          self.settings.set_check_for_updates(check_for_updates);
+          if check_for_updates {
+            update::check(true);
+          }
+        }
+        "notifications" => {
+          let mut notifications = self.settings.config().notifications.clone();
+          let value = match idents.next().unwrap() {
+            "enabled" => &mut notifications.enabled,
+            "on_duck" => &mut notifications.on_duck,
+            "on_error" => &mut notifications.on_error,
+            "on_device_change" => &mut notifications.on_device_change,
+            "on_update_available" => &mut notifications.on_update_available,
+            _ => unimplemented!(),
+          };
+          *value = !*value;
+          self.settings.set_notifications(notifications);
+        }
+        "record_metrics" => {
+          let record_metrics = !self.settings.config().record_metrics;
+          self.settings.set_record_metrics(record_metrics);
+          self.daemon.update(self.settings.config());
+        }
+        "log_peak_to_file" => {
+          let log_peak_to_file = !self.settings.config().log_peak_to_file;
+          self.settings.set_log_peak_to_file(log_peak_to_file);
+          self.daemon.update(self.settings.config());
+        }
+        "open_log" => log_file::open(),
+        "dump_state" => diagnostics::dump(
+          self.settings.config().clone(),
+          self.daemon.snapshot(),
+          self.daemon.events().recent(),
+        ),
+        "reset" => {
+          let confirmed = notify::confirm(
+            "Reset All Settings",
+            "Reset all settings to their defaults? This can't be undone.",
+          );
+          if confirmed {
+            let config = self.settings.reset_to_defaults();
+            self.daemon.update(&config);
+          }
         }
         _ => unimplemented!(),
       },
       //--------------------------------
-      "exit" => std::process::exit(0),
-      "reload" => {}
+      "exit" => self.exit(),
+      "pause" => self.set_paused(!self.paused),
+      "safe_mode" => self.set_safe_mode(!self.daemon.safe_mode()),
+      "debug" => match idents.next() {
+        Some("panic") => panic!("[main] test panic from the menu"),
+        _ => log::warn!("[main] unknown debug item: {}", id),
+      },
+      "update" => {
+        if let Some(release) = update::available() {
+          update::open_page(&release);
+        }
+      }
+      "custom" => {
+        let id = id.strip_prefix("custom.").unwrap_or_default();
+        if self.custom_actions.len() == MAX_CUSTOM_ACTIONS {
+          self.custom_actions.pop_front();
+        }
+        self.custom_actions.push_back(CustomAction::new(id));
+      }
+      "reload" => {
+        if let Err(err) = self.reload_config() {
+          notify::show(
+            Category::Error,
+            "Reload",
+            &format!("Kept the current config, {}", err),
+          );
+        }
+      }
+      "refresh" => self.daemon.refresh_sessions(),
       _ => {
         return false;
       }
     }
 
+    // the 5% steps have two or three digits, `05` to `100`
+    fn get_slider_value_fine(idents: &mut IntoIter<&str>) -> Option<f32> {
+      let ident = idents.next().filter(|ident| ident.len() >= 2)?;
+      let percent = ident.parse::<u32>().ok()?;
+      (percent <= 100 && percent % 5 == 0).then_some(percent as f32 / 100.0)
+    }
+
     fn get_slider_valuee(mut event: IntoIter<&str>) -> f32 {
       match event.next().unwrap() {
         "a" => 1.0,
@@ -141,7 +560,13 @@ impl App {
   }
 }
 
-impl ApplicationHandler for App {
+impl Drop for App {
+  fn drop(&mut self) {
+    let _ = self.daemon.shutdown();
+  }
+}
+
+impl ApplicationHandler<AppEvent> for App {
   fn device_event(&mut self, _: &ActiveEventLoop, _: DeviceId, _: DeviceEvent) {
     let mut updated = false;
 
@@ -149,32 +574,114 @@ impl ApplicationHandler for App {
       updated |= self.click_menu_item(event);
     }
 
+    // show a newly found release
+    if !self.update_shown && update::available().is_some() {
+      self.update_shown = true;
+      updated = true;
+    }
+
+    // write config changes
+    self.settings.flush();
+
+    let Some(menu) = &mut self.menu else {
+      return;
+    };
+
     // update menu
     if updated {
-      self.menu.update(&self.settings);
+      menu.update(&self.settings, self.daemon.snapshot().status);
+    } else {
+      menu.poll_loading(&self.settings);
+    }
+
+    // update tooltip
+    if self.tooltip_updated.elapsed() >= TOOLTIP_INTERVAL {
+      menu.update_tooltip(&self.daemon.snapshot());
+      self.tooltip_updated = Instant::now();
     }
   }
 
+  fn user_event(&mut self, _: &ActiveEventLoop, event: AppEvent) {
+    let command = match event {
+      AppEvent::Control(command) => command,
+      AppEvent::Pipe(request, reply) => {
+        let _ = reply.send(self.handle_pipe(request));
+        // device events, which write the config otherwise, don't reach a headless app. a request
+        // is a single change, nothing to wait for
+        self.settings.save();
+        return;
+      }
+      AppEvent::State(event) => {
+        log::debug!("[main] daemon {}", event.state);
+        if let Some(menu) = &mut self.menu {
+          let snapshot = self.daemon.snapshot();
+          menu.set_state_indicator(snapshot.status);
+          menu.update_tooltip(&snapshot);
+          self.tooltip_updated = Instant::now();
+        }
+        return;
+      }
+    };
+    log::info!("[main] received {:?}", command);
+    match command {
+      ControlCommand::Pause => self.set_paused(true),
+      ControlCommand::Resume => self.set_paused(false),
+      ControlCommand::Exit => self.exit(),
+      ControlCommand::TogglePause => self.set_paused(!self.paused),
+      ControlCommand::Show => self.show(),
+      ControlCommand::Lock => self.set_away(true, self.asleep),
+      ControlCommand::Unlock => self.set_away(false, self.asleep),
+      ControlCommand::Sleep => self.set_away(self.locked, true),
+      ControlCommand::Wake => self.set_away(self.locked, false),
+    }
+    self.update_menu();
+  }
+
   fn resumed(&mut self, _: &ActiveEventLoop) {}
   fn window_event(&mut self, _: &ActiveEventLoop, _: WindowId, _: WindowEvent) {}
 }
 
-fn start_logger() {
-  let logfile = std::env::current_exe()
-    .unwrap()
-    .with_file_name("sound-priority.log");
+// answer pipe and http requests on the event loop, waiting for the reply
+fn request_handler(proxy: EventLoopProxy<AppEvent>) -> impl Fn(PipeRequest) -> PipeReply + Send {
+  move |request| {
+    let (reply, receiver) = sync_channel(1);
+    if proxy.send_event(AppEvent::Pipe(request, reply)).is_err() {
+      return PipeReply::error("the app is exiting");
+    }
+    receiver
+      .recv_timeout(PIPE_REPLY_TIMEOUT)
+      .unwrap_or_else(|_| PipeReply::error("no reply from the app"))
+  }
+}
+
+fn start_logger(verbose: bool) {
+  let logfile = log_file::path();
 
-  fs::remove_file(&logfile).ok();
+  // moved aside by the next run rather than cleared, so a crash report stays around
+  crash::install(logfile.clone());
 
-  let logfile = logfile.to_str().unwrap_or("sound-priority.log");
   let mut ftail = Ftail::new();
-  ftail = ftail.datetime_format("%m-%d %H:%M:%S");
+  ftail = ftail.datetime_format(LOG_DATETIME_FORMAT);
 
-  if cfg!(debug_assertions) {
+  // debug builds always have a console, release builds open one for `--verbose`
+  if verbose {
+    cli::open_console();
+  }
+  if verbose || cfg!(debug_assertions) {
     ftail = ftail.formatted_console(log::LevelFilter::Debug);
   }
 
-  ftail = ftail.single_file(logfile, false, log::LevelFilter::Info);
+  ftail = ftail.custom(
+    move |_| {
+      let log = RotatingLog::new(&logfile, log::LevelFilter::Info, LOG_DATETIME_FORMAT);
+      Box::new(log) as Box<dyn log::Log + Send + Sync>
+    },
+    log::LevelFilter::Info,
+  );
 
   ftail.init().unwrap();
+
+  if !WinMix::default().has_default_device() {
+    log::warn!("[main] no audio output device");
+  }
 }
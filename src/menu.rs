@@ -1,40 +1,231 @@
-use std::collections::HashSet;
+use std::{
+  collections::HashSet,
+  path::{Path, PathBuf},
+  sync::{
+    mpsc::{channel, Receiver},
+    Arc,
+  },
+  thread,
+};
 
 use convert_case::{Case, Casing};
+use serde::{Deserialize, Serialize};
 use tray_icon::{
   menu::{IsMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu},
   Icon, TrayIcon, TrayIconBuilder,
 };
 
-use crate::{settings::Settings, winmix::WinMix, APP_NAME};
+use crate::{
+  config::{normalize_name, same_name, ConfigIssue, IconConfig, NotificationConfig, RestoreTarget},
+  deamon::{DaemonStateSnapshot, VolumeStatus},
+  settings::Settings,
+  update,
+  winmix::{device::UserFilter, WinMix},
+  APP_NAME,
+};
+
+const PEAK_BAR_WIDTH: usize = 10;
+const TOOLTIP_MAX_LEN: usize = 127;
+const STARTUP_DELAYS: [u64; 7] = [0, 5, 10, 15, 20, 25, 30];
+
+/// A menu item added by a script over the pipe, clicks are queued as `CustomAction`s.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomMenuItem {
+  pub id: String,
+  pub label: String,
+  #[serde(default = "enabled")]
+  pub enabled: bool,
+}
+
+fn enabled() -> bool {
+  true
+}
+
+/// A click on a `CustomMenuItem`, like `{"cmd":"custom_action","id":"..."}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomAction {
+  pub cmd: &'static str,
+  pub id: String,
+}
+
+impl CustomAction {
+  pub fn new(id: impl Into<String>) -> Self {
+    Self {
+      cmd: "custom_action",
+      id: id.into(),
+    }
+  }
+}
 
 pub struct MenuSystem {
+  winmix: Arc<WinMix>,
   tray: TrayIcon,
+  icons: TrayIcons,
+  active: bool,
+  tooltip: String,
+  loading: Option<Receiver<Option<Vec<String>>>>,
+  paused: bool,
+  safe_mode: bool,
+  state: VolumeStatus,
+  custom_items: Vec<CustomMenuItem>,
 }
 
 impl MenuSystem {
-  pub fn new() -> Self {
+  pub fn new(winmix: Arc<WinMix>, icons: &IconConfig) -> Self {
+    let icons = TrayIcons::load(icons);
     let tray = TrayIconBuilder::new()
       .with_tooltip(APP_NAME)
-      .with_icon(Icon::from_resource(32512, None).expect("failed to load icon"))
+      .with_icon(icons.idle.clone())
       .with_menu_on_left_click(true)
       .build()
       .unwrap();
-    Self { tray }
+    Self {
+      winmix,
+      tray,
+      icons,
+      active: false,
+      tooltip: APP_NAME.to_string(),
+      loading: None,
+      paused: false,
+      safe_mode: false,
+      state: VolumeStatus::Restore,
+      custom_items: vec![],
+    }
+  }
+  /// Put the tray icon back, in case it got lost.
+  pub fn show(&self) {
+    if let Err(err) = self.tray.set_visible(true) {
+      log::warn!("[menu] failed to show the tray icon: {}", err);
+    }
+  }
+  /// Show whether the daemon is paused, takes effect on the next update.
+  pub fn set_paused(&mut self, paused: bool) {
+    self.paused = paused;
+  }
+  /// Show whether the daemon only monitors, takes effect on the next update.
+  pub fn set_safe_mode(&mut self, safe_mode: bool) {
+    self.safe_mode = safe_mode;
+  }
+  /// Replace the items added by scripts, takes effect on the next update.
+  pub fn set_custom_items(&mut self, items: Vec<CustomMenuItem>) {
+    self.custom_items = items;
   }
-  pub fn update(&mut self, settings: &Settings) {
+  /// Show whether the daemon is ducking at the top of the menu, takes effect on the next update.
+  pub fn set_state_indicator(&mut self, state: VolumeStatus) {
+    self.state = state;
+  }
+  /// Show the state in the tooltip and the icon, so it doesn't depend on telling colors apart.
+  pub fn update_tooltip(&mut self, snapshot: &DaemonStateSnapshot) {
+    let active = !self.paused && snapshot.status == VolumeStatus::Reduce;
+    if active != self.active {
+      let icon = if active {
+        &self.icons.active
+      } else {
+        &self.icons.idle
+      };
+      let _ = self.tray.set_icon(Some(icon.clone()));
+      self.active = active;
+    }
+
+    let state = match (self.paused, snapshot.paused_by, active) {
+      (true, _, _) => "PAUSED".to_string(),
+      (false, Some(reason), _) => format!("PAUSED: {}", reason),
+      (false, None, true) => "ACTIVE: ducking".to_string(),
+      (false, None, false) => "IDLE".to_string(),
+    };
+    let mut tooltip = format!(
+      "{} | {} | Peak: {} {:.2}",
+      APP_NAME,
+      state,
+      peak_bar(snapshot.peak),
+      snapshot.peak
+    );
+    if let Some(trigger) = &snapshot.trigger {
+      tooltip.push_str(&format!(" | Ducked by {}", trigger));
+    }
+    tooltip = tooltip.chars().take(TOOLTIP_MAX_LEN).collect();
+
+    // the tray api is slow, skip it when nothing changed
+    if tooltip != self.tooltip {
+      let _ = self.tray.set_tooltip(Some(&tooltip));
+      self.tooltip = tooltip;
+    }
+  }
+  pub fn update(&mut self, settings: &Settings, status: VolumeStatus) {
+    self.set_state_indicator(status);
+    self.loading = None;
+    let sessions = session_names(&self.winmix, settings.config().same_user_only);
+    self.build(settings, Apps::from(sessions));
+  }
+  /// Show the menu right away, the apps list is filled in by `poll_loading`.
+  pub fn update_loading(&mut self, settings: &Settings) {
+    let (sender, receiver) = channel();
+    let same_user_only = settings.config().same_user_only;
+    thread::spawn(move || {
+      // COM is set up per thread, so the loader needs its own
+      let _ = sender.send(session_names(&WinMix::default(), same_user_only));
+    });
+    self.loading = Some(receiver);
+    self.build(settings, Apps::Loading);
+  }
+  /// Fill in the apps list once it's loaded, returns `true` when the menu was rebuilt.
+  pub fn poll_loading(&mut self, settings: &Settings) -> bool {
+    let Some(sessions) = self
+      .loading
+      .as_ref()
+      .and_then(|receiver| receiver.try_recv().ok())
+    else {
+      return false;
+    };
+    self.loading = None;
+    self.build(settings, Apps::from(sessions));
+    true
+  }
+  fn build(&mut self, settings: &Settings, apps: Apps) {
     log::info!("[menu] update menu");
     let menu = Menu::with_items(&[
-      &MenuItem::with_id("reload", "Reload", true, None),
+      &MenuItem::with_id("refresh", "Refresh Apps", true, None),
+      &MenuItem::with_id("reload", "Reload Config", true, None),
+      &MenuItem::with_id("pause", checkbox("Paused", self.paused), true, None),
+      &MenuItem::with_id(
+        "safe_mode",
+        checkbox("Safe Mode (monitor only)", self.safe_mode),
+        true,
+        None,
+      ),
       &PredefinedMenuItem::separator(),
     ])
     .unwrap();
 
+    if let Some(release) = update::available() {
+      let text = format!("Update available ({}) — open page", release.tag_name);
+      menu
+        .prepend(&MenuItem::with_id("update.open", text, true, None))
+        .unwrap();
+    }
+
+    let state = match self.state {
+      VolumeStatus::Reduce => "● Ducking active",
+      VolumeStatus::Restore => "○ Monitoring",
+    };
+    menu.prepend(&MenuItem::new(state, false, None)).unwrap();
+
     log::info!("[menu] reload apps list");
-    let apps = self.get_apps(settings);
-    for app in apps.into_iter() {
-      let app = app.as_ref();
-      menu.append(app).expect("failed to create menu");
+    match apps {
+      Apps::Loading => {
+        let item = MenuItem::with_id("loading", "Loading apps…", false, None);
+        menu.append(&item).unwrap();
+      }
+      Apps::NoDevice => {
+        let item = MenuItem::with_id("apps.none", "No audio device", false, None);
+        menu.append(&item).unwrap();
+      }
+      Apps::Sessions(sessions) => {
+        for app in self.get_apps(settings, sessions).into_iter() {
+          let app = app.as_ref();
+          menu.append(app).expect("failed to create menu");
+        }
+      }
     }
 
     log::info!("[menu] reload settings");
@@ -42,6 +233,20 @@ impl MenuSystem {
       .append_items(&[
         &PredefinedMenuItem::separator(),
         &self.get_settings(settings),
+      ])
+      .unwrap();
+
+    if !self.custom_items.is_empty() {
+      menu.append(&PredefinedMenuItem::separator()).unwrap();
+      for item in self.custom_items.iter() {
+        let id = format!("custom.{}", item.id);
+        let item = MenuItem::with_id(id, &item.label, item.enabled, None);
+        menu.append(&item).unwrap();
+      }
+    }
+
+    menu
+      .append_items(&[
         &PredefinedMenuItem::separator(),
         &MenuItem::with_id("exit", "&Exit", true, None),
       ])
@@ -50,39 +255,42 @@ impl MenuSystem {
     log::info!("[menu] flush menu");
     self.tray.set_menu(Some(Box::new(menu)));
   }
-  pub fn get_apps(&self, settings: &Settings) -> Vec<Box<dyn IsMenuItem>> {
-    let config = &settings.config;
+  pub fn get_apps(&self, settings: &Settings, sessions: Vec<String>) -> Vec<Box<dyn IsMenuItem>> {
+    let config = settings.config();
+
+    let exclude = config.exclude.clone();
+    let targets = config.targets.clone();
+    // every app that isn't excluded is a target, the list is just for show
+    let all_targets = config.apply_to_all_sessions;
 
-    let mut exclude = config.exclude.clone();
-    let mut targets = config.targets.clone();
-    let mut sessions: Vec<String> = {
-      let winmix = WinMix::default();
-       // we only reload the apps list after operation
-       // so we can just get the current default
-      let device = winmix.get_default();
-      let sessions = device.and_then(|device| device.get_sessions());
-      sessions.map(|session| session.into_iter().map(|session| session.name).collect())
+    // group by priority: targets, excludes, then everything else
+    let mut set = HashSet::new();
+    let (mut target_apps, mut exclude_apps, mut other_apps) = (vec![], vec![], vec![]);
+    for name in [targets.clone(), exclude.clone(), sessions].concat() {
+      if !set.insert(normalize_name(&name)) {
+        continue;
+      }
+
+      if targets.iter().any(|target| same_name(target, &name)) {
+        target_apps.push(name);
+      } else if exclude.iter().any(|exclude| same_name(exclude, &name)) {
+        exclude_apps.push(name);
+      } else {
+        other_apps.push(name);
+      }
     }
-    .unwrap_or_default();
 
-    exclude.sort();
-    targets.sort();
-    sessions.sort();
+    for group in [&mut target_apps, &mut exclude_apps, &mut other_apps] {
+      group.sort_by_key(|name| normalize_name(name));
+    }
 
-    let list = [exclude.clone(), targets.clone(), sessions.clone()].concat();
-    let mut set = HashSet::new();
+    let list = [target_apps, exclude_apps, other_apps].concat();
 
     list
       .into_iter()
-      .filter_map(|name| {
-        if set.contains(&name) {
-          return None;
-        } else {
-          set.insert(name.clone());
-        }
-
-        let is_exclude = exclude.contains(&name);
-        let is_target = targets.contains(&name);
+      .map(|name| {
+        let is_exclude = exclude.iter().any(|exclude| same_name(exclude, &name));
+        let is_target = targets.iter().any(|target| same_name(target, &name));
 
         let display_name = {
           let mut name = name.clone();
@@ -99,7 +307,7 @@ impl MenuSystem {
           if is_exclude {
             name.push_str(" ×");
           }
-          if is_target {
+          if is_target && !all_targets {
             name.push_str(" ♪");
           }
           name
@@ -113,8 +321,8 @@ impl MenuSystem {
           &[
             &MenuItem::with_id(
               &format!("apps.{}.target", name),
-              checkbox("Target", is_target),
-              !is_exclude,
+              checkbox("Target", is_target && !all_targets),
+              !is_exclude && !all_targets,
               None,
             ),
             &MenuItem::with_id(
@@ -127,34 +335,220 @@ impl MenuSystem {
         )
         .unwrap();
 
-        Some(Box::new(menu) as Box<dyn IsMenuItem>)
+        Box::new(menu) as Box<dyn IsMenuItem>
       })
       .collect()
   }
   pub fn get_settings(&self, settings: &Settings) -> Submenu {
-    let config = &settings.config;
+    let config = settings.config();
+    let fine = config.fine_slider;
+    let issues = settings.issues();
+    let title = if issues.is_empty() {
+      "Settings"
+    } else {
+      "Settings ⚠"
+    };
     let settings = Submenu::with_items(
-      "Settings",
+      title,
       true,
       &[
-        &slider("volume.sensitivity", "Sensitivity", config.sensitivity),
-        &slider("volume.restore", "Restore Volume", config.resotre_volume),
-        &slider("volume.reduce", "Reduce Volume", config.reduce_volume),
+        &slider(
+          "volume.sensitivity",
+          "Sensitivity",
+          config.sensitivity,
+          fine,
+        ),
+        &slider(
+          "volume.restore",
+          "Restore Volume",
+          config.resotre_volume,
+          fine,
+        ),
+        &slider("volume.reduce", "Reduce Volume", config.reduce_volume, fine),
+        &restore_target(config.restore_target),
         &MenuItem::with_id(
-          "settings.autolaunch",
-          checkbox("Launch on startup", settings.get_autolaunch()),
+          "settings.peak_source",
+          format!("Detect From: {}", config.detection.source.label()),
+          true,
+          None,
+        ),
+        &MenuItem::with_id(
+          "settings.apply_to_all_sessions",
+          checkbox("Duck all apps", config.apply_to_all_sessions),
+          true,
+          None,
+        ),
+        &MenuItem::with_id(
+          "settings.exclude_foreground",
+          checkbox("Ignore foreground app", config.exclude_foreground),
           true,
           None,
         ),
+        &startup_delay(config.startup_delay_secs),
+        &notifications(&config.notifications),
+        &MenuItem::with_id(
+          "settings.check_for_updates",
+          checkbox("Check for updates", config.check_for_updates),
+          true,
+          None,
+        ),
+        &autolaunch(settings),
+        &PredefinedMenuItem::separator(),
+        &MenuItem::with_id("settings.open_log", "Open Log File", true, None),
+        &MenuItem::with_id("settings.dump_state", "Save Diagnostics", true, None),
+        &MenuItem::with_id(
+          "settings.record_metrics",
+          checkbox("Record Metrics", config.record_metrics),
+          true,
+          None,
+        ),
+        &MenuItem::with_id(
+          "settings.log_peak_to_file",
+          checkbox("Log Peaks", config.log_peak_to_file),
+          true,
+          None,
+        ),
+        &MenuItem::with_id("settings.reset", "Reset All Settings", true, None),
       ],
     )
     .expect("failed to create settings submenu");
 
-    fn slider(id: &str, text: &str, value: f32) -> Submenu {
+    // lets the crash report be checked end to end
+    if cfg!(debug_assertions) {
+      let item = MenuItem::with_id("debug.panic", "Crash (debug)", true, None);
+      settings.append(&item).unwrap();
+    }
+
+    if !issues.is_empty() {
+      settings
+        .prepend_items(&[&problems(&issues), &PredefinedMenuItem::separator()])
+        .unwrap();
+    }
+
+    fn problems(issues: &[ConfigIssue]) -> Submenu {
+      let menu = Submenu::new(format!("Problems ({})", issues.len()), true);
+      for issue in issues {
+        let item = MenuItem::with_id(
+          format!("settings.problems.{}", issue.id()),
+          format!("⚠ {}", issue.message()),
+          false,
+          None,
+        );
+        menu.append(&item).unwrap();
+      }
+      menu
+    }
+
+    fn restore_target(value: RestoreTarget) -> Submenu {
+      let item = |id: &str, text: &str, target: RestoreTarget| {
+        MenuItem::with_id(
+          format!("settings.restore_target.{}", id),
+          checkbox(text, value == target),
+          value != target,
+          None,
+        )
+      };
+
+      Submenu::with_items(
+        "Restore To",
+        true,
+        &[
+          &item("full", "Full Volume", RestoreTarget::Full),
+          &item("previous", "Previous Volume", RestoreTarget::Previous),
+          &item("fixed", "Restore Volume", RestoreTarget::Fixed),
+        ],
+      )
+      .unwrap()
+    }
+
+    fn startup_delay(value: u64) -> Submenu {
+      let menu = Submenu::new(format!("Startup Delay ({}s)", value), true);
+      for secs in STARTUP_DELAYS {
+        let item = MenuItem::with_id(
+          format!("settings.startup_delay.{}", secs),
+          format!("{}s", secs),
+          secs != value,
+          None,
+        );
+        menu.append(&item).unwrap();
+      }
+      menu
+    }
+
+    fn notifications(value: &NotificationConfig) -> Submenu {
+      let item = |id: &str, text: &str, checked: bool| {
+        MenuItem::with_id(
+          format!("settings.notifications.{}", id),
+          checkbox(text, checked),
+          value.enabled,
+          None,
+        )
+      };
+
+      Submenu::with_items(
+        "Notifications",
+        true,
+        &[
+          &MenuItem::with_id(
+            "settings.notifications.enabled",
+            checkbox("Show notifications", value.enabled),
+            true,
+            None,
+          ),
+          &PredefinedMenuItem::separator(),
+          &item("on_duck", "Volume reduced", value.on_duck),
+          &item("on_error", "Errors", value.on_error),
+          &item("on_device_change", "Device changed", value.on_device_change),
+          &item(
+            "on_update_available",
+            "Update available",
+            value.on_update_available,
+          ),
+        ],
+      )
+      .unwrap()
+    }
+
+    fn autolaunch(settings: &Settings) -> MenuItem {
+      match settings.autolaunch_unavailable() {
+        Some(reason) => MenuItem::with_id(
+          "settings.autolaunch",
+          format!("Launch on startup (unavailable: {})", reason),
+          false,
+          None,
+        ),
+        None => MenuItem::with_id(
+          "settings.autolaunch",
+          checkbox(
+            "Launch on startup",
+            settings.get_autolaunch().unwrap_or(false),
+          ),
+          true,
+          None,
+        ),
+      }
+    }
+
+    fn slider(id: &str, text: &str, value: f32, fine: bool) -> Submenu {
       fn enabled(value: f32, condition: f32) -> bool {
         (value - condition).abs() > f32::EPSILON
       }
 
+      // 5% steps, with ids like `volume.sensitivity.05`
+      if fine {
+        let menu = Submenu::with_id(id, format!("{} ({})", text, value), true);
+        for percent in (0..=100).rev().step_by(5) {
+          let item = MenuItem::with_id(
+            format!("{}.{:02}", id, percent),
+            format!("{:>3}%", percent),
+            enabled(value, percent as f32 / 100.0),
+            None,
+          );
+          menu.append(&item).unwrap();
+        }
+        return menu;
+      }
+
       Submenu::with_id_and_items(
         id,
         format!("{} ({})", text, value),
@@ -180,7 +574,86 @@ impl MenuSystem {
   }
 }
 
+// the apps playing on the default device, `None` without any output device
+fn session_names(winmix: &WinMix, same_user_only: bool) -> Option<Vec<String>> {
+  // counting doesn't open the endpoints, unlike enumerating their sessions
+  if winmix.get_device_count().unwrap_or_default() == 0 {
+    return None;
+  }
+
+  // we only reload the apps list after operation
+  // so we can just get the current default
+  let device = winmix.get_default();
+  let sessions = device.and_then(|device| device.get_sessions());
+  let mut users = UserFilter::current();
+  let names = sessions
+    .map(|session| {
+      session
+        .into_iter()
+        .filter(|session| !same_user_only || users.is_same_user(session.pid))
+        .map(|session| session.name)
+        .collect()
+    })
+    .unwrap_or_default();
+  Some(names)
+}
+
+// what goes in the apps section of the menu
+enum Apps {
+  Loading,
+  NoDevice,
+  Sessions(Vec<String>),
+}
+
+impl From<Option<Vec<String>>> for Apps {
+  fn from(sessions: Option<Vec<String>>) -> Self {
+    match sessions {
+      Some(sessions) => Apps::Sessions(sessions),
+      None => Apps::NoDevice,
+    }
+  }
+}
+
 fn checkbox(name: &str, value: bool) -> String {
   let icon = if value { "✔" } else { "✖" };
   format!("[{}] {}", icon, name)
 }
+
+/// The tray icons per state, the embedded icon stands in for any that isn't configured.
+struct TrayIcons {
+  active: Icon,
+  idle: Icon,
+}
+
+impl TrayIcons {
+  fn load(config: &IconConfig) -> Self {
+    let embedded = Icon::from_resource(32512, None).expect("failed to load icon");
+    let load = |path: &Option<PathBuf>| {
+      path
+        .as_deref()
+        .and_then(load_icon)
+        .unwrap_or_else(|| embedded.clone())
+    };
+    Self {
+      active: load(&config.active),
+      idle: load(&config.idle),
+    }
+  }
+}
+
+fn load_icon(path: &Path) -> Option<Icon> {
+  Icon::from_path(path, None)
+    .inspect_err(|err| log::warn!("[menu] failed to load icon {}: {}", path.display(), err))
+    .ok()
+}
+
+fn peak_bar(peak: f32) -> String {
+  let level = peak.clamp(0.0, 1.0) * PEAK_BAR_WIDTH as f32;
+  (0..PEAK_BAR_WIDTH)
+    .map(|i| match level - i as f32 {
+      fill if fill >= 1.0 => '█',
+      fill if fill >= 0.5 => '▓',
+      _ => '░',
+    })
+    .collect()
+}
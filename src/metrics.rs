@@ -0,0 +1,223 @@
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{self, BufWriter, Write},
+  path::PathBuf,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::Config, deamon::VolumeStatus, state::state_dir};
+
+// rotate to `metrics.1.csv` past this size, so a forgotten toggle can't fill the disk
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const HEADER: &str = "timestamp,peak,trigger,state,target_volume\n";
+const PEAKS_HEADER: &str = "timestamp,peak,status\n";
+// `Config::record_metrics` writes once a second
+const METRICS_TICKS: usize = 10;
+// lines buffered before they are written out, the file lags behind by at most this many
+const FLUSH_LINES: usize = 10;
+
+/// One line of `metrics.csv`.
+pub struct MetricsSample<'a> {
+  /// The peak of the apps that are neither targets nor excluded.
+  pub peak: f32,
+  pub trigger: Option<&'a str>,
+  pub status: VolumeStatus,
+  /// The average volume of the targets, `None` without any.
+  pub target_volume: Option<f32>,
+}
+
+/// Where and how often a `MetricsRecorder` writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsOptions {
+  pub path: PathBuf,
+  /// Daemon ticks between two lines.
+  pub interval_ticks: usize,
+  /// Empty the file on the first write, so it only holds the current run. Otherwise lines are
+  /// appended and the file is rotated once it gets big.
+  pub truncate: bool,
+  /// Write `timestamp,peak,status` lines, with the timestamp in milliseconds.
+  pub peaks_only: bool,
+}
+
+impl MetricsOptions {
+  /// `metrics.csv` for `Config::record_metrics`.
+  pub fn metrics(config: &Config) -> Option<Self> {
+    config.record_metrics.then(|| MetricsOptions {
+      path: path(),
+      interval_ticks: METRICS_TICKS,
+      truncate: false,
+      peaks_only: false,
+    })
+  }
+  /// The peak history for `Config::log_peak_to_file`.
+  pub fn peaks(config: &Config) -> Option<Self> {
+    config.log_peak_to_file.then(|| MetricsOptions {
+      path: peaks_path(config),
+      interval_ticks: config.peak_log_interval_ticks.max(1) as usize,
+      truncate: true,
+      peaks_only: true,
+    })
+  }
+}
+
+/// Appends the daemon state to a CSV, for picking a sensitivity in a spreadsheet.
+///
+/// Lines are flushed in batches of a few, and whenever the file is closed or the options change,
+/// so the file can be watched while it grows. Write errors are logged and the lines not written
+/// yet are dropped, the file is opened again for the next one.
+#[derive(Default)]
+pub struct MetricsRecorder {
+  options: Option<MetricsOptions>,
+  writer: Option<BufWriter<File>>,
+  // lines written to the buffer since the last flush
+  unflushed: usize,
+  size: u64,
+  // whether the file was emptied already, it is only appended to after that
+  truncated: bool,
+  // only log the first of a run of errors, e.g. while the disk is full
+  failing: bool,
+}
+
+impl MetricsRecorder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Follow the options from the config, `None` stops recording.
+  ///
+  /// Changed options close the file, the next `record` opens the new one.
+  pub fn sync(&mut self, options: Option<MetricsOptions>) {
+    if self.options == options {
+      return;
+    }
+    self.close();
+    self.options = options;
+    self.truncated = false;
+    self.failing = false;
+  }
+  /// Whether a line is due on tick `ticks`.
+  pub fn is_due(&self, ticks: usize) -> bool {
+    self
+      .options
+      .as_ref()
+      .is_some_and(|options| ticks % options.interval_ticks.max(1) == 0)
+  }
+  pub fn record(&mut self, sample: &MetricsSample) {
+    let Some(options) = self.options.clone() else {
+      return;
+    };
+    match self.write(&options, sample) {
+      Ok(()) => self.failing = false,
+      Err(err) => {
+        if !self.failing {
+          log::warn!(
+            "[metrics] failed to record to {}: {}",
+            options.path.display(),
+            err
+          );
+        }
+        self.failing = true;
+        self.writer = None;
+        self.unflushed = 0;
+      }
+    }
+  }
+  /// Flush and close the file, the next `record` opens it again.
+  pub fn close(&mut self) {
+    self.unflushed = 0;
+    if let Some(mut writer) = self.writer.take() {
+      if let Err(err) = writer.flush() {
+        log::warn!("[metrics] failed to flush: {}", err);
+      }
+      if let Some(options) = &self.options {
+        log::info!("[metrics] closed {}", options.path.display());
+      }
+    }
+  }
+  fn write(&mut self, options: &MetricsOptions, sample: &MetricsSample) -> io::Result<()> {
+    if !options.truncate && self.size >= MAX_FILE_SIZE {
+      self.close();
+      fs::rename(&options.path, options.path.with_extension("1.csv"))?;
+    }
+
+    let line = if options.peaks_only {
+      format!(
+        "{},{:.3},{:?}\n",
+        timestamp_millis(),
+        sample.peak,
+        sample.status
+      )
+    } else {
+      // app names never hold a quote, but may hold a comma
+      format!(
+        "{},{:.3},\"{}\",{:?},{}\n",
+        timestamp_millis() / 1000,
+        sample.peak,
+        sample.trigger.unwrap_or_default(),
+        sample.status,
+        sample
+          .target_volume
+          .map(|volume| format!("{:.3}", volume))
+          .unwrap_or_default()
+      )
+    };
+    let flush = self.unflushed + 1 >= FLUSH_LINES;
+    let writer = match &mut self.writer {
+      Some(writer) => writer,
+      None => self.open(options)?,
+    };
+    writer.write_all(line.as_bytes())?;
+    if flush {
+      writer.flush()?;
+    }
+    self.size += line.len() as u64;
+    self.unflushed = if flush { 0 } else { self.unflushed + 1 };
+    Ok(())
+  }
+  fn open(&mut self, options: &MetricsOptions) -> io::Result<&mut BufWriter<File>> {
+    let file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .append(!options.truncate || self.truncated)
+      .truncate(options.truncate && !self.truncated)
+      .open(&options.path)?;
+    self.truncated = true;
+    self.size = file.metadata()?.len();
+    let mut writer = BufWriter::new(file);
+    if self.size == 0 {
+      let header = if options.peaks_only {
+        PEAKS_HEADER
+      } else {
+        HEADER
+      };
+      writer.write_all(header.as_bytes())?;
+      self.size = header.len() as u64;
+    }
+    log::info!("[metrics] recording to {}", options.path.display());
+    Ok(self.writer.insert(writer))
+  }
+}
+
+impl Drop for MetricsRecorder {
+  fn drop(&mut self) {
+    self.close();
+  }
+}
+
+pub fn path() -> PathBuf {
+  state_dir().join("metrics.csv")
+}
+
+/// Where the peaks of `config` are logged, `peaks.csv` next to the state unless configured.
+pub fn peaks_path(config: &Config) -> PathBuf {
+  match &config.peak_log_path {
+    Some(path) => PathBuf::from(path),
+    None => state_dir().join("peaks.csv"),
+  }
+}
+
+fn timestamp_millis() -> u128 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|time| time.as_millis())
+    .unwrap_or_default()
+}
@@ -0,0 +1,302 @@
+use std::{ffi::c_void, mem, sync::Mutex, thread, time::Duration};
+
+use windows::{
+  core::{w, IInspectable, Interface, HSTRING, PCWSTR},
+  Data::Xml::Dom::XmlDocument,
+  Foundation::TypedEventHandler,
+  Win32::{
+    Foundation::HWND,
+    System::{
+      LibraryLoader::GetModuleHandleW,
+      Registry::{RegSetKeyValueW, HKEY_CURRENT_USER, REG_SZ},
+    },
+    UI::{
+      Shell::{
+        Shell_NotifyIconW, NIF_ICON, NIF_INFO, NIF_TIP, NIIF_WARNING, NIM_ADD, NIM_DELETE,
+        NOTIFYICONDATAW,
+      },
+      WindowsAndMessaging::{
+        CreateWindowExW, DestroyWindow, LoadIconW, MessageBoxW, HMENU, HWND_MESSAGE, IDYES,
+        MB_ICONWARNING, MB_OK, MB_SETFOREGROUND, MB_YESNO, WINDOW_EX_STYLE, WINDOW_STYLE,
+      },
+    },
+  },
+  UI::Notifications::{ToastActivatedEventArgs, ToastNotification, ToastNotificationManager},
+};
+
+use crate::{config::NotificationConfig, pipe, APP_NAME};
+
+// the balloon gets its own short-lived icon, apart from the tray menu icon
+const BALLOON_ID: u32 = 0x5350;
+const BALLOON_DURATION: Duration = Duration::from_secs(6);
+// the icon embedded by build.rs
+const ICON_RESOURCE: u16 = 32512;
+// toasts from an unpackaged app need an AppUserModelID registered for the user
+const APP_ID: &str = "xiao-e-yun.SoundPriority";
+const APP_ID_KEY: &str = r"Software\Classes\AppUserModelId\xiao-e-yun.SoundPriority";
+// the buttons stay usable from the action center this long, then the toast is removed
+const TOAST_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const PIPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static PREFERENCES: Mutex<Option<NotificationConfig>> = Mutex::new(None);
+
+/// What a notification is about, each can be turned off in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+  Duck,
+  Error,
+  DeviceChange,
+  UpdateAvailable,
+  /// Gated by `Config::notify_on_new_session` rather than the preferences.
+  NewSession,
+  /// The result of an action the user asked for.
+  Diagnostics,
+  /// The app was launched again while running, shown even with notifications off.
+  AlreadyRunning,
+}
+
+/// Apply the notification preferences from the config.
+pub fn configure(preferences: &NotificationConfig) {
+  if let Ok(mut current) = PREFERENCES.lock() {
+    *current = Some(preferences.clone());
+  }
+}
+
+// back to no preferences, so tests that configure them don't leak into others
+#[cfg(test)]
+pub(crate) fn reset() {
+  if let Ok(mut current) = PREFERENCES.lock() {
+    *current = None;
+  }
+}
+
+fn allows(category: Category) -> bool {
+  // the answer to a launch the user just made, so they can tell it did something
+  if category == Category::AlreadyRunning {
+    return true;
+  }
+  let Ok(preferences) = PREFERENCES.lock() else {
+    return true;
+  };
+  let preferences = preferences.clone().unwrap_or_default();
+  preferences.enabled
+    && match category {
+      Category::Duck => preferences.on_duck,
+      Category::Error => preferences.on_error,
+      Category::DeviceChange => preferences.on_device_change,
+      Category::UpdateAvailable => preferences.on_update_available,
+      Category::NewSession | Category::Diagnostics | Category::AlreadyRunning => true,
+    }
+}
+
+/// Show a notification to the user without blocking the caller.
+pub fn show(category: Category, title: &str, message: &str) {
+  log::info!("[notify] {}: {}", title, message);
+  if !allows(category) {
+    return;
+  }
+
+  let title = title.to_string();
+  let message = message.to_string();
+  thread::spawn(move || {
+    if !balloon(&title, &message) {
+      log::warn!("[notify] failed to show balloon, falling back to a message box");
+      message_box(&title, &message);
+    }
+  });
+}
+
+/// Tell the user a new app is playing audio, with buttons to make it a target or exclude it.
+///
+/// The buttons send `add-target` or `add-exclude` to the pipe, like a script would. Falls back to
+/// a balloon pointing to the tray menu when the toast can't be shown.
+pub fn show_new_session(name: &str) {
+  let title = "New app playing audio";
+  log::info!("[notify] {}: {}", title, name);
+  if !allows(Category::NewSession) {
+    return;
+  }
+
+  let name = name.to_string();
+  thread::spawn(move || {
+    if let Err(err) = new_session_toast(title, &name) {
+      log::warn!(
+        "[notify] failed to show toast, falling back to a balloon: {}",
+        err
+      );
+      let message = format!(
+        "{} is now playing audio. Add it as a target or exclude it from the tray menu.",
+        name
+      );
+      if !balloon(title, &message) {
+        message_box(title, &message);
+      }
+    }
+  });
+}
+
+/// Ask the user a yes/no question, blocking until it's answered.
+pub fn confirm(title: &str, message: &str) -> bool {
+  let title = HSTRING::from(format!("{} - {}", APP_NAME, title));
+  let message = HSTRING::from(message);
+  let result = unsafe {
+    MessageBoxW(
+      HWND::default(),
+      &message,
+      &title,
+      MB_YESNO | MB_ICONWARNING | MB_SETFOREGROUND,
+    )
+  };
+  result == IDYES
+}
+
+// show an actionable toast, blocking until it's removed again
+fn new_session_toast(title: &str, name: &str) -> windows::core::Result<()> {
+  register_app_id()?;
+
+  let xml = XmlDocument::new()?;
+  xml.LoadXml(&HSTRING::from(format!(
+    r#"<toast>
+  <visual>
+    <binding template="ToastGeneric">
+      <text>{}</text>
+      <text>{} is now playing audio. Add it as a target or exclude it?</text>
+    </binding>
+  </visual>
+  <actions>
+    <action content="Add Target" arguments="add-target" activationType="foreground"/>
+    <action content="Exclude" arguments="add-exclude" activationType="foreground"/>
+  </actions>
+</toast>"#,
+    escape_xml(title),
+    escape_xml(name)
+  )))?;
+  let toast = ToastNotification::CreateToastNotification(&xml)?;
+
+  let name = name.to_string();
+  toast.Activated(&TypedEventHandler::new(
+    move |_: &Option<ToastNotification>, args: &Option<IInspectable>| {
+      let Some(args) = args else {
+        return Ok(());
+      };
+      let command = args.cast::<ToastActivatedEventArgs>()?.Arguments()?;
+      // a click on the toast itself opens nothing
+      if command == "add-target" || command == "add-exclude" {
+        send_to_pipe(&command.to_string(), &name);
+      }
+      Ok(())
+    },
+  ))?;
+
+  let notifier = ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(APP_ID))?;
+  notifier.Show(&toast)?;
+  thread::sleep(TOAST_LIFETIME);
+  let _ = notifier.Hide(&toast);
+  Ok(())
+}
+
+// the running instance answers its own pipe, so the buttons work like any other client
+fn send_to_pipe(command: &str, name: &str) {
+  let request = serde_json::json!({ "command": command, "name": name }).to_string();
+  log::info!("[notify] toast button: {}", request);
+  let reply = pipe::request(&request, PIPE_TIMEOUT);
+  if !reply.is_some_and(|reply| reply["ok"] == true) {
+    log::warn!("[notify] the pipe didn't take {}", request);
+  }
+}
+
+// the name toasts are shown under, written before each one in case the key was removed
+fn register_app_id() -> windows::core::Result<()> {
+  let name = HSTRING::from(APP_NAME);
+  let result = unsafe {
+    RegSetKeyValueW(
+      HKEY_CURRENT_USER,
+      &HSTRING::from(APP_ID_KEY),
+      w!("DisplayName"),
+      REG_SZ.0,
+      Some(name.as_ptr() as *const c_void),
+      ((name.len() + 1) * mem::size_of::<u16>()) as u32,
+    )
+  };
+  result.ok()
+}
+
+fn escape_xml(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+// show a tray balloon, blocking until it's gone
+fn balloon(title: &str, message: &str) -> bool {
+  unsafe {
+    let Ok(module) = GetModuleHandleW(PCWSTR::null()) else {
+      return false;
+    };
+
+    let window = CreateWindowExW(
+      WINDOW_EX_STYLE::default(),
+      w!("STATIC"),
+      &HSTRING::from(APP_NAME),
+      WINDOW_STYLE::default(),
+      0,
+      0,
+      0,
+      0,
+      HWND_MESSAGE,
+      HMENU::default(),
+      module,
+      None,
+    );
+    if window.0 == 0 {
+      return false;
+    }
+
+    let mut data = NOTIFYICONDATAW {
+      cbSize: mem::size_of::<NOTIFYICONDATAW>() as u32,
+      hWnd: window,
+      uID: BALLOON_ID,
+      uFlags: NIF_ICON | NIF_TIP | NIF_INFO,
+      hIcon: LoadIconW(module, PCWSTR(ICON_RESOURCE as usize as *const u16)).unwrap_or_default(),
+      dwInfoFlags: NIIF_WARNING,
+      ..Default::default()
+    };
+    copy_wide(&mut data.szTip, APP_NAME);
+    copy_wide(&mut data.szInfoTitle, title);
+    copy_wide(&mut data.szInfo, message);
+
+    let shown = Shell_NotifyIconW(NIM_ADD, &data).as_bool();
+    if shown {
+      thread::sleep(BALLOON_DURATION);
+      Shell_NotifyIconW(NIM_DELETE, &data);
+    }
+
+    let _ = DestroyWindow(window);
+    shown
+  }
+}
+
+/// Show a message box, blocking until it's closed.
+pub fn message_box(title: &str, message: &str) {
+  let title = HSTRING::from(format!("{} - {}", APP_NAME, title));
+  let message = HSTRING::from(message);
+  unsafe {
+    MessageBoxW(
+      HWND::default(),
+      &message,
+      &title,
+      MB_OK | MB_ICONWARNING | MB_SETFOREGROUND,
+    );
+  }
+}
+
+// copy into a fixed size wide string, truncating and keeping the trailing \0
+fn copy_wide(buffer: &mut [u16], text: &str) {
+  let max = buffer.len() - 1;
+  let text = text.encode_utf16().take(max).chain([0]);
+  for (slot, char) in buffer.iter_mut().zip(text) {
+    *slot = char;
+  }
+}
@@ -0,0 +1,283 @@
+use std::{
+  fs::{File, OpenOptions},
+  io::{BufRead, BufReader, Write},
+  mem,
+  os::windows::io::FromRawHandle,
+  sync::mpsc::sync_channel,
+  thread,
+  time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use windows::{
+  core::w,
+  Win32::{
+    Foundation::{ERROR_FILE_NOT_FOUND, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED},
+    Security::{
+      Authorization::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1},
+      PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+    },
+    Storage::FileSystem::PIPE_ACCESS_DUPLEX,
+    System::Pipes::{
+      ConnectNamedPipe, CreateNamedPipeW, WaitNamedPipeW, PIPE_READMODE_BYTE,
+      PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    },
+  },
+};
+
+use crate::{
+  deamon::{DaemonStateSnapshot, SessionSnapshot},
+  menu::{CustomAction, CustomMenuItem},
+};
+
+const BUFFER_SIZE: u32 = 4096;
+const PIPE_NAME: &str = r"\\.\pipe\sound-priority";
+// the listener creates the next instance right after serving a client
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A line of JSON sent to `\\.\pipe\sound-priority`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum PipeRequest {
+  Pause,
+  Resume,
+  /// Set one top level field of the config, e.g. `{"command":"set-config-field","field":"sensitivity","value":0.2}`.
+  SetConfigField {
+    field: String,
+    value: serde_json::Value,
+  },
+  /// Keep monitoring without changing any volume.
+  SafeMode {
+    enabled: bool,
+  },
+  QueryStatus,
+  /// Enumerate the sessions again and rebuild the menu, the config is left as it is.
+  RefreshSessions,
+  /// Read the config from disk again.
+  ReloadConfig,
+  /// Make an app a target, e.g. `{"command":"add-target","name":"spotify"}`. Nothing changes
+  /// when it already is one.
+  AddTarget {
+    name: String,
+  },
+  /// Exclude an app, nothing changes when it already is excluded.
+  AddExclude {
+    name: String,
+  },
+  #[serde(alias = "list_sessions")]
+  ListSessions,
+  /// Replace the menu items added by scripts, e.g.
+  /// `{"command":"set-custom-menu-items","items":[{"id":"obs.scene","label":"Next Scene"}]}`.
+  SetCustomMenuItems {
+    items: Vec<CustomMenuItem>,
+  },
+  /// Take the clicks on those items since the last time, oldest first.
+  TakeCustomActions,
+}
+
+/// The line of JSON written back for each request.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PipeReply {
+  pub ok: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub status: Option<PipeStatus>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sessions: Option<Vec<SessionSnapshot>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub actions: Option<Vec<CustomAction>>,
+}
+
+impl PipeReply {
+  pub fn ok() -> Self {
+    PipeReply {
+      ok: true,
+      ..Default::default()
+    }
+  }
+  pub fn error(message: impl Into<String>) -> Self {
+    PipeReply {
+      ok: false,
+      error: Some(message.into()),
+      ..Default::default()
+    }
+  }
+  pub fn status(status: PipeStatus) -> Self {
+    PipeReply {
+      ok: true,
+      status: Some(status),
+      ..Default::default()
+    }
+  }
+  pub fn sessions(sessions: Vec<SessionSnapshot>) -> Self {
+    PipeReply {
+      ok: true,
+      sessions: Some(sessions),
+      ..Default::default()
+    }
+  }
+  pub fn actions(actions: Vec<CustomAction>) -> Self {
+    PipeReply {
+      ok: true,
+      actions: Some(actions),
+      ..Default::default()
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PipeStatus {
+  pub paused: bool,
+  #[serde(flatten)]
+  pub daemon: DaemonStateSnapshot,
+}
+
+/// Serve requests on a background thread, one client at a time.
+///
+/// `handler` answers each request, it is called on the listener thread.
+pub fn listen(handler: impl Fn(PipeRequest) -> PipeReply + Send + 'static) {
+  thread::spawn(move || {
+    // only the user running the app may connect
+    let mut descriptor = PSECURITY_DESCRIPTOR::default();
+    let converted = unsafe {
+      ConvertStringSecurityDescriptorToSecurityDescriptorW(
+        w!("D:P(A;;GA;;;OW)"),
+        SDDL_REVISION_1,
+        &mut descriptor,
+        None,
+      )
+    };
+    if let Err(err) = converted {
+      log::error!("[pipe] failed to create the security descriptor: {}", err);
+      return;
+    }
+    let attributes = SECURITY_ATTRIBUTES {
+      nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+      lpSecurityDescriptor: descriptor.0,
+      bInheritHandle: false.into(),
+    };
+
+    log::info!("[pipe] listening");
+    loop {
+      let Some(pipe) = accept(&attributes) else {
+        return;
+      };
+      serve(pipe, &handler);
+    }
+  });
+}
+
+/// Send one request line to the running instance and read the reply.
+///
+/// Returns `None` when no instance answers within `timeout`. A busy pipe, or one caught between
+/// two clients, is retried until then.
+pub fn request(request: &str, timeout: Duration) -> Option<serde_json::Value> {
+  let request = request.to_string();
+  let deadline = Instant::now() + timeout;
+  let (sender, receiver) = sync_channel(1);
+  // a wedged instance may accept and never answer, the thread is left behind then
+  thread::spawn(move || {
+    let _ = sender.send(exchange(&request, deadline));
+  });
+  receiver.recv_timeout(timeout).ok().flatten()
+}
+
+fn exchange(request: &str, deadline: Instant) -> Option<serde_json::Value> {
+  let mut pipe = connect(deadline)?;
+  writeln!(pipe, "{}", request).ok()?;
+  let mut line = String::new();
+  BufReader::new(pipe).read_line(&mut line).ok()?;
+  serde_json::from_str(&line).ok()
+}
+
+// open the pipe, waiting while every instance is busy or the next one is being created
+fn connect(deadline: Instant) -> Option<File> {
+  loop {
+    let error = match OpenOptions::new().read(true).write(true).open(PIPE_NAME) {
+      Ok(pipe) => return Some(pipe),
+      Err(err) => err,
+    };
+    let remaining = deadline.checked_duration_since(Instant::now())?;
+    match error.raw_os_error().map(|code| code as u32) {
+      Some(code) if code == ERROR_PIPE_BUSY.0 => unsafe {
+        // returns early once an instance is free, or fails once the pipe is gone
+        let millis = remaining.as_millis().clamp(1, u32::MAX as u128) as u32;
+        if !WaitNamedPipeW(w!(r"\\.\pipe\sound-priority"), millis).as_bool() {
+          thread::sleep(RECONNECT_INTERVAL.min(remaining));
+        }
+      },
+      Some(code) if code == ERROR_FILE_NOT_FOUND.0 => {
+        thread::sleep(RECONNECT_INTERVAL.min(remaining))
+      }
+      _ => {
+        log::warn!("[pipe] failed to connect: {}", error);
+        return None;
+      }
+    }
+  }
+}
+
+// wait for the next client on a new pipe instance
+fn accept(attributes: &SECURITY_ATTRIBUTES) -> Option<File> {
+  loop {
+    let handle = unsafe {
+      CreateNamedPipeW(
+        w!(r"\\.\pipe\sound-priority"),
+        PIPE_ACCESS_DUPLEX,
+        PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+        PIPE_UNLIMITED_INSTANCES,
+        BUFFER_SIZE,
+        BUFFER_SIZE,
+        0,
+        Some(attributes as *const _),
+      )
+    };
+    if handle.is_invalid() {
+      log::error!("[pipe] failed to create the pipe");
+      return None;
+    }
+
+    // the file closes the handle once the client is served
+    let pipe = unsafe { File::from_raw_handle(handle.0 as _) };
+    match unsafe { ConnectNamedPipe(handle, None) } {
+      Ok(()) => return Some(pipe),
+      // connected between creating and waiting
+      Err(err) if err.code() == ERROR_PIPE_CONNECTED.to_hresult() => return Some(pipe),
+      Err(err) => log::warn!("[pipe] failed to accept a client: {}", err),
+    }
+  }
+}
+
+// answer every line until the client hangs up
+fn serve(pipe: File, handler: &impl Fn(PipeRequest) -> PipeReply) {
+  let Ok(mut writer) = pipe.try_clone() else {
+    return;
+  };
+  for line in BufReader::new(pipe).lines() {
+    let Ok(line) = line else {
+      return;
+    };
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let reply = match serde_json::from_str::<PipeRequest>(&line) {
+      Ok(request) => {
+        log::info!("[pipe] received {:?}", request);
+        handler(request)
+      }
+      Err(err) => PipeReply::error(format!("invalid request: {}", err)),
+    };
+    let json = match serde_json::to_string(&reply) {
+      Ok(json) => json,
+      Err(err) => {
+        log::error!("[pipe] failed to serialize reply: {}", err);
+        return;
+      }
+    };
+    if writeln!(writer, "{}", json).is_err() {
+      return;
+    }
+  }
+}
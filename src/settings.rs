@@ -1,66 +1,423 @@
-use std::env::current_exe;
+use std::{
+  env::current_exe,
+  io,
+  path::PathBuf,
+  time::{Duration, Instant},
+};
 
 use auto_launch::AutoLaunch;
 
-use crate::{config::Config, APP_NAME};
+use crate::{
+  config::{
+    is_own_name, same_name, Config, ConfigError, ConfigIssue, LoadError, NotificationConfig,
+    PeakSource, RestoreTarget,
+  },
+  notify, APP_NAME,
+};
+
+// wait for the config to settle before writing it
+const SAVE_DEBOUNCE: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Clone)]
 pub struct Settings {
   autolaunch: AutoLaunch,
-  pub config: Config,
+  autolaunch_unavailable: Option<String>,
+  config: Config,
+  // where `save` writes, `Config::path` outside of tests
+  path: PathBuf,
+  dirty_since: Option<Instant>,
 }
 
 impl Settings {
   pub fn new(config: Config) -> Self {
+    Self::with_path(config, Config::path())
+  }
+  /// Like `new`, but saving the config to `path`.
+  pub fn with_path(config: Config, path: PathBuf) -> Self {
+    notify::configure(&config.notifications);
+    Self {
+      autolaunch_unavailable: autolaunch_unavailable(),
+      ..Self::unprobed(config, path)
+    }
+  }
+  // without checking the startup entry or applying the notification preferences,
+  // so tests stay out of the registry and the global preferences
+  fn unprobed(config: Config, path: PathBuf) -> Self {
     let autolaunch = {
       let path = current_exe().expect("failed to get exe path");
       let path = path.to_str().unwrap();
       AutoLaunch::new(APP_NAME, &path)
     };
 
-    Self { autolaunch, config }
+    Self {
+      autolaunch,
+      autolaunch_unavailable: None,
+      config,
+      path,
+      dirty_since: None,
+    }
+  }
+  pub fn config(&self) -> &Config {
+    &self.config
+  }
+  /// Problems with the current config, shown in the menu.
+  pub fn issues(&self) -> Vec<ConfigIssue> {
+    self.config.issues()
   }
   pub fn update(&mut self, config: Config) {
     self.config = config;
+    notify::configure(&self.config.notifications);
+    self.mark_dirty();
+  }
+
+  /// Set one top level field by its name in config.json, the same way `--set` does.
+  pub fn set_field(&mut self, field: &str, value: serde_json::Value) -> Result<(), ConfigError> {
+    // a string is taken as it is, anything else as the JSON it was sent as
+    let value = match value {
+      serde_json::Value::String(value) => value,
+      value => value.to_string(),
+    };
+    let config = self.config.quick_apply(field, &value)?;
+    self.update(config);
+    Ok(())
+  }
+
+  /// Replace the config with the one on disk, pending changes are dropped.
+  ///
+  /// The current config is kept if the file can't be read.
+  pub fn reload(&mut self) -> Result<(), LoadError> {
+    let config = Config::try_load()?.unwrap_or_default();
+    log::info!("[settings] reloaded config");
+    self.config = config;
+    self.dirty_since = None;
+    notify::configure(&self.config.notifications);
+    self.autolaunch_unavailable = autolaunch_unavailable();
+    Ok(())
   }
 
   // functions
-  pub fn get_autolaunch(&self) -> bool {
-    self.autolaunch.is_enabled().unwrap_or(false)
+  pub fn get_autolaunch(&self) -> io::Result<bool> {
+    self.autolaunch.is_enabled()
   }
-  pub fn set_autolaunch(&mut self, autolaunch: bool) {
+  pub fn set_autolaunch(&mut self, autolaunch: bool) -> io::Result<()> {
     if autolaunch {
-      self
-        .autolaunch
-        .enable()
-        .expect("failed to enable autolaunch");
+      self.autolaunch.enable()
     } else {
-      self
-        .autolaunch
-        .disable()
-        .expect("failed to disable autolaunch");
+      self.autolaunch.disable()
+    }
+  }
+  /// The reason autolaunch can't be changed, if the startup probe failed.
+  pub fn autolaunch_unavailable(&self) -> Option<&str> {
+    self.autolaunch_unavailable.as_deref()
+  }
+  /// Point an existing startup entry at the current exe, in case it was moved. A missing one is
+  /// left missing, so a failure can't leave the app launching against the user's choice.
+  ///
+  /// Writes the registry, so it's only run once at startup.
+  pub fn repair_autolaunch(&self) {
+    let repaired = self
+      .autolaunch
+      .is_enabled()
+      .and_then(|enabled| match enabled {
+        true => crate::autolaunch::repair(APP_NAME, &current_exe()?),
+        false => Ok(()),
+      });
+    if let Err(err) = repaired {
+      log::warn!("[settings] failed to repair the startup entry: {}", err);
     }
   }
 
   pub fn select_exclude(&mut self, name: &str) {
     select_item(&mut self.config.exclude, name);
-    self.save();
+    self.mark_dirty();
   }
 
   pub fn select_target(&mut self, name: &str) {
+    let selecting = !self
+      .config
+      .targets
+      .iter()
+      .any(|target| same_name(target, name));
+    if selecting && is_own_name(name) {
+      log::warn!(
+        "[settings] {} is this app, it's only reduced with exclude_self off",
+        name
+      );
+    }
     select_item(&mut self.config.targets, name);
+    self.mark_dirty();
+  }
+
+  /// Like `select_target`, but leaving an app that already is a target alone.
+  pub fn add_target(&mut self, name: &str) {
+    if !self
+      .config
+      .targets
+      .iter()
+      .any(|target| same_name(target, name))
+    {
+      self.select_target(name);
+    }
+  }
+
+  /// Like `select_exclude`, but leaving an app that already is excluded alone.
+  pub fn add_exclude(&mut self, name: &str) {
+    if !self
+      .config
+      .exclude
+      .iter()
+      .any(|exclude| same_name(exclude, name))
+    {
+      self.select_exclude(name);
+    }
+  }
+
+  pub fn set_sensitivity(&mut self, sensitivity: f32) {
+    self.config.sensitivity = sensitivity;
+    self.mark_dirty();
+  }
+
+  pub fn set_restore_volume(&mut self, volume: f32) {
+    self.config.resotre_volume = volume;
+    self.mark_dirty();
+  }
+
+  pub fn set_reduce_volume(&mut self, volume: f32) {
+    self.config.reduce_volume = volume;
+    self.mark_dirty();
+  }
+
+  pub fn set_restore_target(&mut self, restore_target: RestoreTarget) {
+    self.config.restore_target = restore_target;
+    self.mark_dirty();
+  }
+
+  pub fn set_peak_source(&mut self, peak_source: PeakSource) {
+    self.config.detection.source = peak_source;
+    self.mark_dirty();
+  }
+
+  pub fn set_exclude_foreground(&mut self, exclude_foreground: bool) {
+    self.config.exclude_foreground = exclude_foreground;
+    self.mark_dirty();
+  }
+
+  pub fn set_apply_to_all_sessions(&mut self, apply_to_all_sessions: bool) {
+    self.config.apply_to_all_sessions = apply_to_all_sessions;
+    self.mark_dirty();
+  }
+
+  pub fn set_startup_delay(&mut self, secs: u64) {
+    self.config.startup_delay_secs = secs;
+    self.mark_dirty();
+  }
+
+  pub fn set_record_metrics(&mut self, record_metrics: bool) {
+    self.config.record_metrics = record_metrics;
+    self.mark_dirty();
+  }
+
+  pub fn set_log_peak_to_file(&mut self, log_peak_to_file: bool) {
+    self.config.log_peak_to_file = log_peak_to_file;
+    self.mark_dirty();
+  }
+
+  pub fn set_check_for_updates(&mut self, check_for_updates: bool) {
+    self.config.check_for_updates = check_for_updates;
+    self.mark_dirty();
+  }
+
+  pub fn set_notifications(&mut self, notifications: NotificationConfig) {
+    self.config.notifications = notifications;
+    notify::configure(&self.config.notifications);
+    self.mark_dirty();
+  }
+
+  /// Replace the config with the defaults and save it right away.
+  ///
+  /// Autolaunch is left alone, it's OS state rather than part of the config.
+  pub fn reset_to_defaults(&mut self) -> Config {
+    log::info!("[settings] reset to defaults");
+    self.config = Config::default();
+    notify::configure(&self.config.notifications);
+    self.mark_dirty();
     self.save();
+    self.config.clone()
+  }
+
+  // persistence
+  fn mark_dirty(&mut self) {
+    self.dirty_since = Some(Instant::now());
+  }
+  /// Save pending changes once the config has been unchanged for a moment.
+  pub fn flush(&mut self) {
+    self.flush_at(Instant::now());
+  }
+  fn flush_at(&mut self, now: Instant) {
+    let settled = self
+      .dirty_since
+      .is_some_and(|since| now.saturating_duration_since(since) >= SAVE_DEBOUNCE);
+    if settled {
+      self.save();
+    }
   }
+  /// Save pending changes immediately.
+  pub fn save(&mut self) {
+    if self.dirty_since.take().is_none() {
+      return;
+    }
 
-  pub fn save(&self) {
-    let _ = self.config.save();
+    log::info!("[settings] save config");
+    self.config.normalize();
+    if let Err(err) = self.config.save_to(&self.path) {
+      log::error!("[settings] failed to save config: {}", err);
+    }
   }
 }
 
+// check that the startup entry can be read and written, without writing it
+fn autolaunch_unavailable() -> Option<String> {
+  crate::autolaunch::probe().err().map(|err| {
+    log::warn!("[settings] autolaunch unavailable: {}", err);
+    err.to_string()
+  })
+}
+
 fn select_item(list: &mut Vec<String>, name: &str) {
-  if list.contains(&name.to_string()) {
-    list.retain(|n| n != name)
+  if list.iter().any(|n| same_name(n, name)) {
+    list.retain(|n| !same_name(n, name))
   } else {
-    list.push(name.to_string())
+    list.push(name.trim().to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, path::Path};
+
+  use super::*;
+
+  // a directory of its own per test and run, tests run in parallel
+  fn settings(name: &str) -> Settings {
+    let dir = std::env::temp_dir().join(format!(
+      "sound-priority-settings-{}-{}",
+      name,
+      std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    Settings::unprobed(Config::default(), dir.join("config.json"))
+  }
+
+  // mutations apply the notification preferences, this puts them back when a test ends
+  struct ResetPreferences;
+
+  impl Drop for ResetPreferences {
+    fn drop(&mut self) {
+      notify::reset();
+    }
+  }
+
+  // whether a save happened since the last call
+  fn saved(path: &Path) -> bool {
+    fs::remove_file(path).is_ok()
+  }
+
+  // each mutation path has to save once the debounce passed, and only once
+  fn assert_saves_once(name: &str, mutate: impl FnOnce(&mut Settings)) {
+    let _preferences = ResetPreferences;
+    let mut settings = settings(name);
+    let path = settings.path.clone();
+    mutate(&mut settings);
+
+    settings.flush_at(Instant::now());
+    assert!(!saved(&path), "{} saved before the debounce", name);
+
+    settings.flush_at(Instant::now() + SAVE_DEBOUNCE);
+    assert!(saved(&path), "{} wasn't saved", name);
+
+    settings.flush_at(Instant::now() + SAVE_DEBOUNCE);
+    settings.save();
+    assert!(!saved(&path), "{} saved twice", name);
+  }
+
+  #[test]
+  fn mutations_save_once() {
+    assert_saves_once("update", |s| s.update(Config::default()));
+    assert_saves_once("set-field", |s| {
+      s.set_field("sensitivity", serde_json::json!(0.2)).unwrap()
+    });
+    assert_saves_once("select-exclude", |s| s.select_exclude("discord"));
+    assert_saves_once("select-target", |s| s.select_target("spotify"));
+    assert_saves_once("add-exclude", |s| s.add_exclude("discord"));
+    assert_saves_once("add-target", |s| s.add_target("spotify"));
+    assert_saves_once("sensitivity", |s| s.set_sensitivity(0.2));
+    assert_saves_once("restore-volume", |s| s.set_restore_volume(0.9));
+    assert_saves_once("reduce-volume", |s| s.set_reduce_volume(0.1));
+    assert_saves_once("restore-target", |s| {
+      s.set_restore_target(RestoreTarget::default())
+    });
+    assert_saves_once("peak-source", |s| s.set_peak_source(PeakSource::default()));
+    assert_saves_once("exclude-foreground", |s| s.set_exclude_foreground(true));
+    assert_saves_once("all-sessions", |s| s.set_apply_to_all_sessions(true));
+    assert_saves_once("startup-delay", |s| s.set_startup_delay(5));
+    assert_saves_once("record-metrics", |s| s.set_record_metrics(true));
+    assert_saves_once("log-peak", |s| s.set_log_peak_to_file(true));
+    assert_saves_once("updates", |s| s.set_check_for_updates(false));
+    assert_saves_once("notifications", |s| {
+      s.set_notifications(NotificationConfig::default())
+    });
+  }
+
+  #[test]
+  fn set_field_parses_like_the_command_line() {
+    let _preferences = ResetPreferences;
+    let mut settings = settings("set-field-parse");
+    settings
+      .set_field("sensitivity", serde_json::json!("0.2"))
+      .unwrap();
+    assert_eq!(settings.config().sensitivity, 0.2);
+    settings
+      .set_field("exclude_foreground", serde_json::json!("on"))
+      .unwrap();
+    assert!(settings.config().exclude_foreground);
+
+    assert!(settings
+      .set_field("sensitivity", serde_json::json!("loud"))
+      .is_err());
+    assert!(settings
+      .set_field("no_such_field", serde_json::json!(1))
+      .is_err());
+    assert_eq!(settings.config().sensitivity, 0.2);
+  }
+
+  #[test]
+  fn save_skips_the_debounce() {
+    let _preferences = ResetPreferences;
+    let mut settings = settings("save");
+    let path = settings.path.clone();
+    settings.set_sensitivity(0.2);
+    settings.save();
+    assert!(saved(&path));
+  }
+
+  #[test]
+  fn reset_saves_right_away() {
+    let _preferences = ResetPreferences;
+    let mut settings = settings("reset");
+    let path = settings.path.clone();
+    settings.reset_to_defaults();
+    assert!(saved(&path));
+    settings.flush_at(Instant::now() + SAVE_DEBOUNCE);
+    assert!(!saved(&path));
+  }
+
+  #[test]
+  fn nothing_to_save() {
+    let mut settings = settings("clean");
+    let path = settings.path.clone();
+    settings.flush_at(Instant::now() + SAVE_DEBOUNCE);
+    settings.save();
+    assert!(!saved(&path));
   }
 }
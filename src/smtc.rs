@@ -0,0 +1,117 @@
+use std::{collections::HashMap, time::Duration};
+
+use windows::Media::Control::{
+  GlobalSystemMediaTransportControlsSessionManager as SessionManager,
+  GlobalSystemMediaTransportControlsSessionPlaybackStatus as PlaybackStatus,
+};
+
+use crate::config::normalize_name;
+
+// asking again after a failure, doubling up to the longest wait
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// The playback state apps report to the system media controls (SMTC).
+///
+/// Some apps keep their audio session alive with a little noise while paused, SMTC tells those
+/// apart from real playback. The lookups are cached until the next `refresh`, and everything
+/// reads as playing while SMTC isn't available, which is asked again less and less often.
+#[derive(Default)]
+pub struct MediaSessions {
+  manager: Option<SessionManager>,
+  // when to ask again after a failure, and how long to wait after the next one
+  retry_at: Option<Duration>,
+  backoff: Duration,
+  // normalized app name to whether any of its media sessions is playing
+  playing: HashMap<String, bool>,
+}
+
+impl MediaSessions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Read the playback state of every media session again, `now` being the daemon clock.
+  pub fn refresh(&mut self, now: Duration) {
+    if self.retry_at.is_some_and(|retry_at| now < retry_at) {
+      return;
+    }
+    match self.read() {
+      Ok(playing) => {
+        if self.retry_at.take().is_some() {
+          log::info!("[smtc] media sessions available again");
+        }
+        self.backoff = Duration::ZERO;
+        self.playing = playing;
+      }
+      Err(err) => {
+        // older Windows versions have no SMTC at all, the service may also just be restarting
+        self.backoff = next_backoff(self.backoff);
+        log::warn!(
+          "[smtc] media sessions unavailable, retrying in {:?}: {}",
+          self.backoff,
+          err
+        );
+        self.retry_at = Some(now + self.backoff);
+        self.manager = None;
+        self.playing.clear();
+      }
+    }
+  }
+  /// Whether the app named `name` reports its media as paused or stopped.
+  pub fn is_paused(&self, name: &str) -> bool {
+    self.playing.get(&normalize_name(name)) == Some(&false)
+  }
+  fn read(&mut self) -> windows::core::Result<HashMap<String, bool>> {
+    let manager = match &self.manager {
+      Some(manager) => manager,
+      None => self.manager.insert(SessionManager::RequestAsync()?.get()?),
+    };
+
+    let mut playing = HashMap::new();
+    for session in &manager.GetSessions()? {
+      let Ok(app) = session.SourceAppUserModelId() else {
+        continue;
+      };
+      let Ok(status) = session
+        .GetPlaybackInfo()
+        .and_then(|info| info.PlaybackStatus())
+      else {
+        continue;
+      };
+      let is_playing = !matches!(status, PlaybackStatus::Paused | PlaybackStatus::Stopped);
+      *playing.entry(app_name(&app.to_string())).or_insert(false) |= is_playing;
+    }
+    Ok(playing)
+  }
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+  (backoff * 2).clamp(RETRY_BACKOFF, MAX_RETRY_BACKOFF)
+}
+
+// the process name in an AppUserModelId, which is either the exe like `Spotify.exe` or
+// a packaged app like `SpotifyAB.SpotifyMusic_zpdnekdrzrea0!Spotify`
+fn app_name(app_user_model_id: &str) -> String {
+  let name = app_user_model_id
+    .rsplit('!')
+    .next()
+    .unwrap_or(app_user_model_id);
+  let name = normalize_name(name);
+  match name.strip_suffix(".exe") {
+    Some(name) => name.to_string(),
+    None => name,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backoff_doubles_up_to_the_max() {
+    let first = next_backoff(Duration::ZERO);
+    assert_eq!(first, RETRY_BACKOFF);
+    assert_eq!(next_backoff(first), RETRY_BACKOFF * 2);
+    assert_eq!(next_backoff(MAX_RETRY_BACKOFF), MAX_RETRY_BACKOFF);
+  }
+}
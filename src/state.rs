@@ -0,0 +1,158 @@
+use std::{
+  collections::BTreeMap,
+  env::current_exe,
+  fs,
+  path::PathBuf,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::normalize_name;
+
+const APP_VOLUMES_VERSION: u32 = 1;
+const APP_VOLUMES_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The directory runtime state is kept in, next to the executable.
+pub fn state_dir() -> PathBuf {
+  let path = current_exe().expect("Failed to get exe path");
+  path.parent().unwrap().to_path_buf()
+}
+
+/// The volumes the user had set for each target, kept across restarts.
+///
+/// If the app exits while targets are reduced, they are restored on the next start.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppVolumes {
+  pub version: u32,
+  pub apps: BTreeMap<String, AppVolume>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppVolume {
+  pub volume: f32,
+  /// The volume the app was reduced to, while it is reduced.
+  #[serde(default)]
+  pub reduced_to: Option<f32>,
+  /// Unix timestamp, in seconds.
+  pub last_seen: u64,
+}
+
+impl AppVolumes {
+  pub fn new() -> Self {
+    Self {
+      version: APP_VOLUMES_VERSION,
+      apps: BTreeMap::new(),
+    }
+  }
+  pub fn load() -> Self {
+    let Ok(file) = fs::File::open(Self::path()) else {
+      return Self::new();
+    };
+    match serde_json::from_reader::<_, Self>(file) {
+      Ok(state) if state.version == APP_VOLUMES_VERSION => state,
+      Ok(state) => {
+        log::warn!(
+          "[state] ignoring app volumes of unknown version {}",
+          state.version
+        );
+        Self::new()
+      }
+      Err(err) => {
+        log::warn!("[state] failed to read app volumes: {}", err);
+        Self::new()
+      }
+    }
+  }
+  pub fn save(&self) {
+    let json = serde_json::to_vec(self).expect("Failed to serialize app volumes");
+    if let Err(err) = fs::write(Self::path(), json) {
+      log::warn!("[state] failed to save app volumes: {}", err);
+    }
+  }
+  pub fn path() -> PathBuf {
+    state_dir().join("app_volumes.json")
+  }
+
+  pub fn get(&self, name: &str) -> Option<f32> {
+    self.apps.get(&normalize_name(name)).map(|app| app.volume)
+  }
+  pub fn remember(&mut self, name: &str, volume: f32) {
+    let app = AppVolume {
+      volume,
+      reduced_to: None,
+      last_seen: now(),
+    };
+    self.apps.insert(normalize_name(name), app);
+  }
+  /// Record the volume a remembered app was reduced to.
+  pub fn reduced(&mut self, name: &str, volume: f32) {
+    if let Some(app) = self.apps.get_mut(&normalize_name(name)) {
+      app.reduced_to = Some(volume);
+    }
+  }
+  /// Forget which apps are reduced, returning whether any were.
+  pub fn clear_reduced(&mut self) -> bool {
+    let mut cleared = false;
+    for app in self.apps.values_mut() {
+      cleared |= app.reduced_to.take().is_some();
+    }
+    cleared
+  }
+  /// Forget apps that haven't been seen for a while.
+  pub fn prune(&mut self) {
+    let oldest = now().saturating_sub(APP_VOLUMES_MAX_AGE.as_secs());
+    let before = self.apps.len();
+    self.apps.retain(|_, app| app.last_seen >= oldest);
+    if self.apps.len() != before {
+      log::info!("[state] pruned {} app volumes", before - self.apps.len());
+    }
+  }
+}
+
+/// When updates were last checked for.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpdateState {
+  /// Unix timestamp, in seconds.
+  pub last_checked: u64,
+}
+
+impl UpdateState {
+  pub fn load() -> Self {
+    fs::File::open(Self::path())
+      .ok()
+      .and_then(|file| serde_json::from_reader(file).ok())
+      .unwrap_or_default()
+  }
+  pub fn save(&self) {
+    let json = serde_json::to_vec(self).expect("Failed to serialize update state");
+    if let Err(err) = fs::write(Self::path(), json) {
+      log::warn!("[state] failed to save update state: {}", err);
+    }
+  }
+  pub fn path() -> PathBuf {
+    state_dir().join("update_check.json")
+  }
+
+  pub fn is_due(&self, interval: Duration) -> bool {
+    now().saturating_sub(self.last_checked) >= interval.as_secs()
+  }
+  pub fn mark_checked(&mut self) {
+    self.last_checked = now();
+  }
+}
+
+impl Default for AppVolumes {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|time| time.as_secs())
+    .unwrap_or_default()
+}
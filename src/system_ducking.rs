@@ -0,0 +1,145 @@
+use std::{ffi::c_void, fs, io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use windows::{
+  core::HSTRING,
+  Win32::{
+    Foundation::{ERROR_FILE_NOT_FOUND, WIN32_ERROR},
+    System::Registry::{
+      RegDeleteKeyValueW, RegGetValueW, RegSetKeyValueW, HKEY_CURRENT_USER, REG_DWORD,
+      RRF_RT_REG_DWORD,
+    },
+  },
+};
+
+use crate::state::state_dir;
+
+// the "Communications" tab of the sound control panel
+const AUDIO_KEY: &str = r"Software\Microsoft\Multimedia\Audio";
+const PREFERENCE: &str = "UserDuckingPreference";
+// 0 mutes, 1 reduces by 80% and is the default, 2 reduces by 50%
+const DO_NOTHING: u32 = 3;
+
+/// Windows' own ducking of other sounds during calls turned off, until `restore`.
+///
+/// The user's preference is kept in the state directory while it is turned off, so a run that
+/// never got to `restore` is undone by `recover` on the next start.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SystemDucking {
+  // `None` when the user never changed the preference
+  original: Option<u32>,
+}
+
+impl SystemDucking {
+  /// Set the communications activity preference to "do nothing", remembering the user's.
+  pub fn disable() -> io::Result<Self> {
+    let original = read()?;
+    let ducking = Self { original };
+    if original != Some(DO_NOTHING) {
+      // saved first, a crash between the two writes must not lose the preference
+      ducking.save()?;
+      write(DO_NOTHING)?;
+      log::info!(
+        "[system_ducking] turned off Windows' communications ducking, was {:?}",
+        original
+      );
+    }
+    Ok(ducking)
+  }
+  /// Put the user's preference back.
+  pub fn restore(self) -> io::Result<()> {
+    match self.original {
+      Some(DO_NOTHING) => return Ok(()),
+      Some(original) => write(original)?,
+      None => check(unsafe {
+        RegDeleteKeyValueW(
+          HKEY_CURRENT_USER,
+          &HSTRING::from(AUDIO_KEY),
+          &HSTRING::from(PREFERENCE),
+        )
+      })?,
+    }
+    if let Err(err) = fs::remove_file(Self::path()) {
+      if err.kind() != io::ErrorKind::NotFound {
+        log::warn!(
+          "[system_ducking] failed to remove the saved preference: {}",
+          err
+        );
+      }
+    }
+    log::info!("[system_ducking] restored Windows' communications ducking");
+    Ok(())
+  }
+  /// Put back the preference a run left turned off, e.g. after a crash or a logoff.
+  ///
+  /// Returns `false` when nothing was left behind.
+  pub fn recover() -> io::Result<bool> {
+    let Ok(file) = fs::File::open(Self::path()) else {
+      return Ok(false);
+    };
+    let ducking: Self = serde_json::from_reader(file)?;
+    log::info!(
+      "[system_ducking] a previous run left the preference off, restoring {:?}",
+      ducking.original
+    );
+    ducking.restore()?;
+    Ok(true)
+  }
+  pub fn path() -> PathBuf {
+    state_dir().join("system_ducking.json")
+  }
+  fn save(&self) -> io::Result<()> {
+    let json = serde_json::to_vec(self).expect("Failed to serialize system ducking");
+    fs::write(Self::path(), json)
+  }
+}
+
+/// Whether Windows reduces other sounds during calls, on top of what this app does.
+pub fn is_active() -> bool {
+  read().is_ok_and(|preference| preference != Some(DO_NOTHING))
+}
+
+// the preference, `None` when it was never set
+fn read() -> io::Result<Option<u32>> {
+  let mut value = 0_u32;
+  let mut size = std::mem::size_of::<u32>() as u32;
+  let result = unsafe {
+    RegGetValueW(
+      HKEY_CURRENT_USER,
+      &HSTRING::from(AUDIO_KEY),
+      &HSTRING::from(PREFERENCE),
+      RRF_RT_REG_DWORD,
+      None,
+      Some(&mut value as *mut u32 as *mut c_void),
+      Some(&mut size),
+    )
+  };
+  if result == ERROR_FILE_NOT_FOUND {
+    return Ok(None);
+  }
+  check(result)?;
+  Ok(Some(value))
+}
+
+fn write(value: u32) -> io::Result<()> {
+  let result = unsafe {
+    RegSetKeyValueW(
+      HKEY_CURRENT_USER,
+      &HSTRING::from(AUDIO_KEY),
+      &HSTRING::from(PREFERENCE),
+      REG_DWORD.0,
+      Some(&value as *const u32 as *const c_void),
+      std::mem::size_of::<u32>() as u32,
+    )
+  };
+  check(result)
+}
+
+fn check(result: WIN32_ERROR) -> io::Result<()> {
+  if result.is_ok() {
+    Ok(())
+  } else {
+    Err(io::Error::from_raw_os_error(result.0 as i32))
+  }
+}
@@ -0,0 +1,148 @@
+use std::{
+  env, fs, io,
+  os::windows::process::CommandExt,
+  path::Path,
+  process::{Command, Output},
+};
+
+use crate::APP_NAME;
+
+// the name of the task in the Task Scheduler library
+const TASK_NAME: &str = APP_NAME;
+// keeps `schtasks` from flashing a console window when run from the tray app
+const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+/// Whether `install` created the task or replaced one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Installed {
+  Created,
+  Updated,
+}
+
+/// Register a task starting the app headless when the current user logs on.
+///
+/// Running it again replaces the task, e.g. after the exe moved. `highest` runs the app with the
+/// highest privileges the user has, which needs an elevated prompt to install.
+pub fn install(exe: &Path, highest: bool) -> io::Result<Installed> {
+  let existed = exists()?;
+
+  // schtasks only reads the definition from a file
+  let file = env::temp_dir().join("sound-priority-task.xml");
+  fs::write(&file, encode_utf16(&task_xml(exe, highest)))?;
+  let output = schtasks(&[
+    "/Create",
+    "/TN",
+    TASK_NAME,
+    "/XML",
+    &file.to_string_lossy(),
+    "/F",
+  ]);
+  let _ = fs::remove_file(&file);
+  check(output?)?;
+
+  log::info!("[task] installed for {}", exe.display());
+  Ok(if existed {
+    Installed::Updated
+  } else {
+    Installed::Created
+  })
+}
+
+/// Remove the task, returns `false` when there was none.
+pub fn uninstall() -> io::Result<bool> {
+  if !exists()? {
+    return Ok(false);
+  }
+  check(schtasks(&["/Delete", "/TN", TASK_NAME, "/F"])?)?;
+  log::info!("[task] uninstalled");
+  Ok(true)
+}
+
+pub fn exists() -> io::Result<bool> {
+  Ok(schtasks(&["/Query", "/TN", TASK_NAME])?.status.success())
+}
+
+fn schtasks(args: &[&str]) -> io::Result<Output> {
+  Command::new("schtasks")
+    .args(args)
+    .creation_flags(CREATE_NO_WINDOW)
+    .output()
+}
+
+// schtasks explains its failures on stderr
+fn check(output: Output) -> io::Result<()> {
+  if output.status.success() {
+    return Ok(());
+  }
+  let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+  Err(io::Error::other(message))
+}
+
+// the task definition, see https://learn.microsoft.com/en-us/windows/win32/taskschd/task-scheduler-schema
+fn task_xml(exe: &Path, highest: bool) -> String {
+  let user = match (env::var("USERDOMAIN"), env::var("USERNAME")) {
+    (Ok(domain), Ok(name)) => format!("{}\\{}", domain, name),
+    (_, Ok(name)) => name,
+    _ => String::new(),
+  };
+  let run_level = if highest {
+    "HighestAvailable"
+  } else {
+    "LeastPrivilege"
+  };
+  format!(
+    r#"<?xml version="1.0" encoding="UTF-16"?>
+<Task version="1.2" xmlns="http://schemas.microsoft.com/windows/2004/02/mit/task">
+  <RegistrationInfo>
+    <Description>Starts {app} without the tray icon when {user} logs on.</Description>
+  </RegistrationInfo>
+  <Triggers>
+    <LogonTrigger>
+      <Enabled>true</Enabled>
+      <UserId>{user}</UserId>
+    </LogonTrigger>
+  </Triggers>
+  <Principals>
+    <Principal id="Author">
+      <UserId>{user}</UserId>
+      <LogonType>InteractiveToken</LogonType>
+      <RunLevel>{run_level}</RunLevel>
+    </Principal>
+  </Principals>
+  <Settings>
+    <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>
+    <DisallowStartIfOnBatteries>false</DisallowStartIfOnBatteries>
+    <StopIfGoingOnBatteries>false</StopIfGoingOnBatteries>
+    <ExecutionTimeLimit>PT0S</ExecutionTimeLimit>
+    <Enabled>true</Enabled>
+  </Settings>
+  <Actions Context="Author">
+    <Exec>
+      <Command>{exe}</Command>
+      <Arguments>--headless</Arguments>
+    </Exec>
+  </Actions>
+</Task>
+"#,
+    app = escape(APP_NAME),
+    user = escape(&user),
+    run_level = run_level,
+    exe = escape(&exe.to_string_lossy()),
+  )
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+// schtasks wants UTF-16 with a byte order mark, matching the declaration
+fn encode_utf16(text: &str) -> Vec<u8> {
+  std::iter::once(0xFEFF)
+    .chain(text.encode_utf16())
+    .flat_map(u16::to_le_bytes)
+    .collect()
+}
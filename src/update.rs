@@ -0,0 +1,99 @@
+use std::{io, sync::Mutex, thread, time::Duration};
+
+use serde::Deserialize;
+use windows::{
+  core::{w, HSTRING, PCWSTR},
+  Win32::{Foundation::HWND, UI::Shell::ShellExecuteW, UI::WindowsAndMessaging::SW_SHOWNORMAL},
+};
+
+use crate::{
+  notify::{self, Category},
+  state::UpdateState,
+  APP_NAME,
+};
+
+const RELEASES_URL: &str = "https://api.github.com/repos/xiao-e-yun/sound-priority/releases/latest";
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+static AVAILABLE: Mutex<Option<Release>> = Mutex::new(None);
+
+/// The parts of a GitHub release we use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+  pub tag_name: String,
+  pub html_url: String,
+}
+
+/// Look for a newer release without blocking the caller.
+///
+/// Runs at most once a day, unless `force` is set.
+pub fn check(force: bool) {
+  thread::spawn(move || {
+    let mut state = UpdateState::load();
+    if !force && !state.is_due(CHECK_INTERVAL) {
+      return;
+    }
+    state.mark_checked();
+    state.save();
+
+    match fetch_latest() {
+      Ok(release) if is_newer(&release.tag_name, env!("CARGO_PKG_VERSION")) => {
+        log::info!("[update] {} is available", release.tag_name);
+        let message = format!("{} {} is available", APP_NAME, release.tag_name);
+        notify::show(Category::UpdateAvailable, "Update available", &message);
+        if let Ok(mut available) = AVAILABLE.lock() {
+          *available = Some(release);
+        }
+      }
+      Ok(release) => log::info!("[update] up to date, latest is {}", release.tag_name),
+      Err(err) => log::warn!("[update] failed to check for updates: {}", err),
+    }
+  });
+}
+
+/// The newer release found by the last check.
+pub fn available() -> Option<Release> {
+  AVAILABLE
+    .lock()
+    .ok()
+    .and_then(|available| available.clone())
+}
+
+/// Open the release page in the browser.
+pub fn open_page(release: &Release) {
+  let url = HSTRING::from(release.html_url.as_str());
+  unsafe {
+    ShellExecuteW(
+      HWND::default(),
+      w!("open"),
+      &url,
+      PCWSTR::null(),
+      PCWSTR::null(),
+      SW_SHOWNORMAL,
+    );
+  }
+}
+
+fn fetch_latest() -> io::Result<Release> {
+  let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+  let response = agent
+    .get(RELEASES_URL)
+    .set("User-Agent", APP_NAME)
+    .set("Accept", "application/vnd.github+json")
+    .call()
+    .map_err(io::Error::other)?;
+  serde_json::from_reader(response.into_reader()).map_err(io::Error::other)
+}
+
+// compare dotted versions, ignoring a leading `v`
+fn is_newer(tag: &str, current: &str) -> bool {
+  fn parse(version: &str) -> Vec<u64> {
+    version
+      .trim_start_matches('v')
+      .split('.')
+      .map(|part| part.parse().unwrap_or_default())
+      .collect()
+  }
+  parse(tag) > parse(current)
+}
@@ -0,0 +1,102 @@
+use std::{
+  io::{self, Write},
+  net::TcpStream,
+  sync::mpsc::{Receiver, RecvTimeoutError},
+  time::Duration,
+};
+
+use serde::Serialize;
+
+// the magic string from RFC 6455, appended to the client key
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+// a client that stops reading fills the socket buffer, and would block the push forever
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_PING: u8 = 0x9;
+
+/// Finish the upgrade handshake for the client `key` and push every event as a text frame.
+///
+/// Returns once the client is gone, `events` is closed or `stopped` says so. Client frames are
+/// never read, a ping now and then notices a client that went away without closing. A client
+/// that doesn't take a frame within a few seconds is dropped.
+pub fn push<T: Serialize>(
+  mut stream: TcpStream,
+  key: &str,
+  events: Receiver<T>,
+  stopped: impl Fn() -> bool,
+) -> io::Result<()> {
+  stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+  write!(
+    stream,
+    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+    accept_key(key)
+  )?;
+
+  let mut idle = Duration::ZERO;
+  while !stopped() {
+    match events.recv_timeout(Duration::from_secs(1)) {
+      Ok(event) => {
+        let json = serde_json::to_string(&event)?;
+        write_frame(&mut stream, OPCODE_TEXT, json.as_bytes())?;
+        idle = Duration::ZERO;
+      }
+      Err(RecvTimeoutError::Timeout) => {
+        idle += Duration::from_secs(1);
+        if idle >= PING_INTERVAL {
+          write_frame(&mut stream, OPCODE_PING, &[])?;
+          idle = Duration::ZERO;
+        }
+      }
+      Err(RecvTimeoutError::Disconnected) => break,
+    }
+  }
+  Ok(())
+}
+
+// an unmasked, unfragmented frame, as servers send them
+fn write_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) -> io::Result<()> {
+  let mut frame = vec![0x80 | opcode];
+  match payload.len() {
+    len @ 0..=125 => frame.push(len as u8),
+    len @ 126..=0xFFFF => {
+      frame.push(126);
+      frame.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    len => {
+      frame.push(127);
+      frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+  }
+  frame.extend_from_slice(payload);
+  stream.write_all(&frame)?;
+  stream.flush()
+}
+
+fn accept_key(key: &str) -> String {
+  let digest = sha1_smol::Sha1::from(format!("{}{}", key.trim(), ACCEPT_GUID)).digest();
+  base64(&digest.bytes())
+}
+
+fn base64(bytes: &[u8]) -> String {
+  const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut result = String::new();
+  for chunk in bytes.chunks(3) {
+    let buffer = [
+      chunk[0],
+      *chunk.get(1).unwrap_or(&0),
+      *chunk.get(2).unwrap_or(&0),
+    ];
+    let value = u32::from_be_bytes([0, buffer[0], buffer[1], buffer[2]]);
+    for index in 0..4 {
+      if index <= chunk.len() {
+        let sextet = (value >> (18 - index * 6)) & 0x3F;
+        result.push(ALPHABET[sextet as usize] as char);
+      } else {
+        result.push('=');
+      }
+    }
+  }
+  result
+}
@@ -1,38 +1,64 @@
 use core::slice;
 use std::{
+  cell::RefCell,
+  collections::HashMap,
   ffi::OsString,
+  ops::Deref,
   os::windows::ffi::OsStringExt,
-  sync::mpsc::{self, Receiver, SyncSender},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{self, Receiver, SyncSender},
+    Arc, RwLock,
+  },
 };
 
 use windows::{
   core::Interface,
   Win32::{
     Devices::Properties::DEVPKEY_Device_FriendlyName,
-    Foundation::{CloseHandle, MAX_PATH},
+    Foundation::{CloseHandle, LocalFree, HANDLE, HLOCAL, MAX_PATH},
     Media::Audio::{
-      EDataFlow, ERole, Endpoints::IAudioEndpointVolume, IAudioSessionControl,
-      IAudioSessionControl2, IAudioSessionEnumerator, IAudioSessionManager2,
-      IAudioSessionNotification, IAudioSessionNotification_Impl, IMMDevice, IMMNotificationClient,
-      IMMNotificationClient_Impl, ISimpleAudioVolume, DEVICE_STATE,
+      EDataFlow, ERole, EndpointFormFactor,
+      Endpoints::{
+        IAudioEndpointVolume, IAudioEndpointVolumeCallback, IAudioEndpointVolumeCallback_Impl,
+        IAudioMeterInformation,
+      },
+      Headphones, Headset, IAudioSessionControl, IAudioSessionControl2, IAudioSessionEnumerator,
+      IAudioSessionManager2, IAudioSessionNotification, IAudioSessionNotification_Impl, IMMDevice,
+      IMMNotificationClient, IMMNotificationClient_Impl, ISimpleAudioVolume,
+      PKEY_AudioEndpoint_FormFactor, Speakers, AUDIO_VOLUME_NOTIFICATION_DATA, DEVICE_STATE,
+    },
+    Security::{
+      Authorization::ConvertSidToStringSidW, GetTokenInformation, TokenUser, TOKEN_QUERY,
+      TOKEN_USER,
     },
     System::{
-      Com::{StructuredStorage, CLSCTX_ALL, STGM_READ},
+      Com::{CoTaskMemFree, StructuredStorage, CLSCTX_ALL, STGM_READ},
       ProcessStatus::GetModuleFileNameExW,
-      Threading::{OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ},
+      RemoteDesktop::ProcessIdToSessionId,
+      Threading::{
+        OpenProcess, OpenProcessToken, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+        PROCESS_QUERY_INFORMATION, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_VM_READ,
+      },
       Variant::VT_LPWSTR,
     },
   },
 };
-use windows_core::{implement, PCWSTR};
+use windows_core::{implement, PCWSTR, PWSTR};
 use windows_result::{Error, HRESULT};
 
+use crate::config::matches_name;
+
 use super::{
+  loopback::LoopbackCapture,
   session::Session,
-  volume::{EndpointVolume, SessionVolume},
-  WinMix,
+  volume::{scalar_to_db, EndpointMeter, EndpointVolume, SessionVolume},
+  MixerInfo, SessionInfo, WinMix,
 };
 
+/// Exe paths by pid, along with the session instance id to notice a reused pid.
+type ProcessPathCache = HashMap<u32, (String, String)>;
+
 #[derive(Debug)]
 pub struct Device<'a> {
   winmix: &'a WinMix,
@@ -42,12 +68,21 @@ pub struct Device<'a> {
   device_receiver: Option<Receiver<()>>,
   device_vcallback: Option<IMMNotificationClient>,
 
-  sessions: Option<Vec<Session<'a>>>,
+  // read in place on every tick, only written while syncing
+  sessions: Arc<RwLock<Vec<Session<'a>>>>,
+  sessions_synced: bool,
   sessions_receiver: Option<Receiver<()>>,
   sessions_vcallback: Option<IAudioSessionNotification>,
+
+  master_vcallback: Option<(IAudioEndpointVolume, IAudioEndpointVolumeCallback)>,
+  master_muted: Arc<AtomicBool>,
+
+  path_cache: RefCell<ProcessPathCache>,
 }
 
 impl<'a> Device<'a> {
+  // the sessions never leave the thread owning the device, the lock just hands out guards
+  #[allow(clippy::arc_with_non_send_sync)]
   pub fn new(winmix: &'a WinMix, device: IMMDevice) -> Self {
     let manager: IAudioSessionManager2 = unsafe {
       device
@@ -62,9 +97,15 @@ impl<'a> Device<'a> {
       device_receiver: None,
       device_vcallback: None,
 
-      sessions: None,
+      sessions: Arc::new(RwLock::new(vec![])),
+      sessions_synced: false,
       sessions_receiver: None,
       sessions_vcallback: None,
+
+      master_vcallback: None,
+      master_muted: Arc::new(AtomicBool::new(false)),
+
+      path_cache: RefCell::new(HashMap::new()),
     }
   }
 
@@ -75,6 +116,8 @@ impl<'a> Device<'a> {
 
       let mut has_system = false;
       let mut sessions = Vec::<Session>::new();
+      let mut path_cache = self.path_cache.borrow_mut();
+      let mut seen = Vec::new();
       for session_id in 0..session_count {
         let ctrl: IAudioSessionControl = enumerator.GetSession(session_id)?;
         let ctrl2: IAudioSessionControl2 = ctrl.cast()?;
@@ -94,35 +137,91 @@ impl<'a> Device<'a> {
           continue;
         }
 
-        let Ok(proc) = OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid) else {
+        let instance = session_instance_id(&ctrl2);
+        let Some(path) = process_path(pid, instance, &mut path_cache)? else {
           continue;
         };
+        seen.push(pid);
+
+        sessions.push(Session::new(pid, path, SessionVolume::new(vol)));
+      }
 
-        let mut path: [u16; MAX_PATH as usize] = [0; MAX_PATH as usize];
+      // forget processes that are gone
+      path_cache.retain(|pid, _| seen.contains(pid));
+
+      Ok(sessions)
+    }
+  }
 
-        let _ = GetModuleFileNameExW(proc, None, &mut path);
+  /// The sessions from the last sync, empty until the device is registered.
+  pub fn sessions_read(&self) -> impl Deref<Target = Vec<Session<'a>>> + '_ {
+    // a panic while syncing leaves the last sessions, which are still fine to read
+    self
+      .sessions
+      .read()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
 
-        CloseHandle(proc)?;
+  /// Get the first session matched by `name`, the way the config matches targets.
+  pub fn get_session_by_name(&self, name: &str) -> Option<Session<'a>> {
+    self.find_session(|session| matches_name(&session.name, name))
+  }
 
-        // Trim trailing \0
-        let mut path = String::from_utf16_lossy(&path);
-        path.truncate(path.trim_matches(char::from(0)).len());
+  pub fn get_session_by_pid(&self, pid: u32) -> Option<Session<'a>> {
+    self.find_session(|session| session.pid == pid)
+  }
 
-        sessions.push(Session::new(pid, path, SessionVolume::new(vol)));
-      }
+  pub fn get_sessions_matching(
+    &self,
+    predicate: impl Fn(&Session<'a>) -> bool,
+  ) -> Vec<Session<'a>> {
+    if self.sessions_synced {
+      // only the matches are cloned, not every session
+      self
+        .sessions_read()
+        .iter()
+        .filter(|session| predicate(session))
+        .cloned()
+        .collect()
+    } else {
+      self
+        .get_sessions()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|session| predicate(session))
+        .collect()
+    }
+  }
 
-      Ok(sessions)
+  // the first synced session matching once registered, otherwise enumerate them now
+  fn find_session(&self, predicate: impl Fn(&Session<'a>) -> bool) -> Option<Session<'a>> {
+    if self.sessions_synced {
+      self
+        .sessions_read()
+        .iter()
+        .find(|session| predicate(session))
+        .cloned()
+    } else {
+      self
+        .get_sessions()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|session| predicate(session))
     }
   }
 
-  pub fn current_sessions(&self) -> Vec<Session<'a>> {
-    match &self.sessions {
-      Some(sessions) => sessions.clone(),
-      None => vec![],
+  fn set_sessions(&mut self, sessions: Vec<Session<'a>>) {
+    match self.sessions.write() {
+      Ok(mut current) => *current = sessions,
+      Err(poisoned) => *poisoned.into_inner() = sessions,
     }
+    self.sessions_synced = true;
   }
 
-  pub fn sync(&mut self, force: bool) -> Result<(), Error> {
+  /// Sync the default device and its sessions.
+  ///
+  /// Returns `true` when the default device changed.
+  pub fn sync(&mut self, force: bool) -> Result<bool, Error> {
     let device_synced = self
       .device_receiver
       .as_ref()
@@ -142,7 +241,8 @@ impl<'a> Device<'a> {
         self.unregister_sessions()?; // unregister old sessions
       }
 
-      self.device = self.winmix.get_default_immdevice()?;
+      // only the daemon syncs, so waiting out a restarting audio service is fine
+      self.device = self.winmix.get_default_immdevice_retrying()?;
       self.manager = unsafe {
         self
           .device
@@ -154,14 +254,19 @@ impl<'a> Device<'a> {
         self.register_sessions()?; // register new sessions
         sessions_synced = false;
       }
+      if self.master_vcallback.is_some() {
+        self.unregister_master()?;
+        self.register_master()?;
+      }
     }
 
     if !sessions_synced || force {
       log::info!("syncing sessions");
-      self.sessions = Some(self.get_sessions()?);
+      let sessions = self.get_sessions()?;
+      self.set_sessions(sessions);
     }
 
-    Ok(())
+    Ok(!device_synced)
   }
 
   pub fn master(&self) -> Result<EndpointVolume, Error> {
@@ -171,6 +276,80 @@ impl<'a> Device<'a> {
     }
   }
 
+  pub fn meter(&self) -> Result<EndpointMeter, Error> {
+    unsafe {
+      let meter: IAudioMeterInformation = self.device.Activate(CLSCTX_ALL, None)?;
+      Ok(EndpointMeter::new(meter))
+    }
+  }
+
+  /// Opt this app's own session on the device out of the ducking Windows applies during calls.
+  pub fn set_ducking_opt_out(&self, opt_out: bool) -> Result<(), Error> {
+    unsafe {
+      let control: IAudioSessionControl2 = self.manager.GetAudioSessionControl(None, 0)?.cast()?;
+      control.SetDuckingPreference(opt_out)
+    }
+  }
+
+  /// Start capturing what the device plays.
+  pub fn loopback(&self) -> Result<LoopbackCapture, Error> {
+    LoopbackCapture::new(self.device.clone())
+  }
+
+  /// A raw `IAudioClient` on the device, for working with WASAPI directly.
+  ///
+  /// The client is not initialized, call `Initialize` before anything else. Only available with
+  /// the `unstable` feature, the rest of the API never hands out WASAPI interfaces.
+  #[cfg(feature = "unstable")]
+  pub fn get_audio_client(&self) -> Result<windows::Win32::Media::Audio::IAudioClient, Error> {
+    unsafe { self.device.Activate(CLSCTX_ALL, None) }
+  }
+
+  /// Read the current state of the device and its sessions.
+  pub fn describe(&self) -> Result<MixerInfo, Error> {
+    let master = self.master().ok();
+    let sessions = self
+      .get_sessions()?
+      .into_iter()
+      .map(|session| {
+        // each is read on its own, so one that fails leaves the others
+        let volume = session.volume.get_volume().ok();
+        SessionInfo {
+          volume,
+          volume_db: volume.and_then(scalar_to_db),
+          muted: session.volume.get_mute().ok(),
+          peak: session.volume.get_peak().ok(),
+          pid: session.pid,
+          name: session.name,
+          path: session.path,
+        }
+      })
+      .collect();
+
+    Ok(MixerInfo {
+      id: self.get_id()?,
+      name: self.get_name().unwrap_or_default(),
+      master_volume: master.as_ref().and_then(|master| master.get_volume().ok()),
+      master_muted: master.as_ref().and_then(|master| master.get_mute().ok()),
+      sessions,
+    })
+  }
+
+  /// Get the endpoint id, which stays the same across reboots.
+  pub fn get_id(&self) -> Result<String, Error> {
+    unsafe {
+      let id = self.device.GetId()?;
+      let result = id.to_string();
+      CoTaskMemFree(Some(id.0 as *const _));
+      result.map_err(|_| {
+        Error::new(
+          HRESULT::from_win32(0x8007000D),
+          "Endpoint id is not valid UTF-16",
+        )
+      })
+    }
+  }
+
   pub fn get_name(&self) -> Result<String, Error> {
     unsafe {
       let property_store = self.device.OpenPropertyStore(STGM_READ)?;
@@ -211,17 +390,63 @@ impl<'a> Device<'a> {
     }
   }
 
+  /// Get what kind of output the device is, e.g. headphones or speakers.
+  pub fn get_form_factor(&self) -> Result<FormFactor, Error> {
+    unsafe {
+      let property_store = self.device.OpenPropertyStore(STGM_READ)?;
+      let property_value = property_store.GetValue(&PKEY_AudioEndpoint_FormFactor)?;
+      let form_factor = u32::try_from(&property_value)? as i32;
+
+      Ok(match EndpointFormFactor(form_factor) {
+        Speakers => FormFactor::Speakers,
+        Headphones => FormFactor::Headphones,
+        Headset => FormFactor::Headset,
+        _ => FormFactor::Other,
+      })
+    }
+  }
+
   pub fn register(&mut self) -> Result<(), Error> {
     self.register_device()?;
     self.register_sessions()?;
+    self.register_master()?;
     Ok(())
   }
   pub fn unregister(&mut self) -> Result<(), Error> {
     self.unregister_device()?;
     self.unregister_sessions()?;
+    self.unregister_master()?;
     Ok(())
   }
 
+  /// Follow the mute of the master volume, for `is_master_muted`.
+  pub fn register_master(&mut self) -> Result<(), Error> {
+    if self.master_vcallback.is_none() {
+      let client = MasterClient(self.master_muted.clone());
+      unsafe {
+        let endpoint: IAudioEndpointVolume = self.device.Activate(CLSCTX_ALL, None)?;
+        let vcallback: IAudioEndpointVolumeCallback = client.into();
+        endpoint.RegisterControlChangeNotify(&vcallback)?;
+        let muted = endpoint.GetMute()?.as_bool();
+        self.master_muted.store(muted, Ordering::Relaxed);
+        self.master_vcallback = Some((endpoint, vcallback));
+      }
+    }
+    Ok(())
+  }
+  pub fn unregister_master(&mut self) -> Result<(), Error> {
+    if let Some((endpoint, vcallback)) = self.master_vcallback.take() {
+      unsafe {
+        endpoint.UnregisterControlChangeNotify(&vcallback)?;
+      }
+    }
+    Ok(())
+  }
+  /// Whether the master volume is muted, kept up to date once `register_master` was called.
+  pub fn is_master_muted(&self) -> bool {
+    self.master_muted.load(Ordering::Relaxed)
+  }
+
   pub fn register_sessions(&mut self) -> Result<(), Error> {
     if self.sessions_vcallback.is_none() {
       let (sender, receiver) = mpsc::sync_channel(1);
@@ -231,7 +456,8 @@ impl<'a> Device<'a> {
         self.manager.RegisterSessionNotification(&vcallback)?;
         self.sessions_vcallback = Some(vcallback);
         self.sessions_receiver = Some(receiver);
-        self.sessions = Some(self.get_sessions()?);
+        let sessions = self.get_sessions()?;
+        self.set_sessions(sessions);
       }
     }
 
@@ -277,6 +503,188 @@ impl<'a> Device<'a> {
   }
 }
 
+// the exe path of a process, only read again for a new session instance. a reused pid comes
+// with a new session, so the cache is checked without opening the process at all
+unsafe fn process_path(
+  pid: u32,
+  instance: Option<String>,
+  cache: &mut ProcessPathCache,
+) -> Result<Option<String>, Error> {
+  if let (Some(instance), Some((path, cached))) = (&instance, cache.get(&pid)) {
+    if instance == cached {
+      return Ok(Some(path.clone()));
+    }
+  }
+
+  // packaged apps and elevated processes can't be read, but still tell their image name
+  let path = match module_path(pid)? {
+    Some(path) => path,
+    None => match image_path(pid)? {
+      Some(path) => path,
+      None => return Ok(None),
+    },
+  };
+
+  match instance {
+    Some(instance) => cache.insert(pid, (path.clone(), instance)),
+    None => cache.remove(&pid),
+  };
+  Ok(Some(path))
+}
+
+// the path of the main module, which needs to read the memory of the process
+unsafe fn module_path(pid: u32) -> Result<Option<String>, Error> {
+  let Ok(proc) = OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid) else {
+    return Ok(None);
+  };
+
+  let mut path: [u16; MAX_PATH as usize] = [0; MAX_PATH as usize];
+
+  let len = GetModuleFileNameExW(proc, None, &mut path);
+
+  CloseHandle(proc)?;
+
+  if len == 0 {
+    return Ok(None);
+  }
+  Ok(Some(String::from_utf16_lossy(&path[..len as usize])))
+}
+
+// the path of the image, which only needs the limited access packaged apps still grant
+unsafe fn image_path(pid: u32) -> Result<Option<String>, Error> {
+  let Ok(proc) = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) else {
+    return Ok(None);
+  };
+
+  let mut path: [u16; MAX_PATH as usize] = [0; MAX_PATH as usize];
+  let mut len = path.len() as u32;
+
+  let queried =
+    QueryFullProcessImageNameW(proc, PROCESS_NAME_WIN32, PWSTR(path.as_mut_ptr()), &mut len);
+
+  CloseHandle(proc)?;
+
+  if queried.is_err() {
+    return Ok(None);
+  }
+  Ok(Some(String::from_utf16_lossy(&path[..len as usize])))
+}
+
+/// Tells the processes of the current user apart from those of other users.
+///
+/// Other users' processes usually can't be opened, so they are told apart by the Windows session
+/// they run in first, and only by their owner within the same session, e.g. for `runas`.
+/// System sounds and owners that can't be read in the same session count as the current user.
+#[derive(Debug, Default)]
+pub struct UserFilter {
+  session_id: Option<u32>,
+  sid: Option<String>,
+  cache: HashMap<u32, bool>,
+}
+
+impl UserFilter {
+  /// A filter for the user running this process.
+  pub fn current() -> Self {
+    let pid = std::process::id();
+    let session_id = process_session_id(pid)
+      .inspect_err(|err| log::warn!("[winmix] failed to read the session: {}", err))
+      .ok();
+    let sid = process_user_sid(pid)
+      .inspect_err(|err| log::warn!("[winmix] failed to read the user: {}", err))
+      .ok();
+    Self {
+      session_id,
+      sid,
+      cache: HashMap::new(),
+    }
+  }
+  /// Whether the process `pid` runs as the current user, remembered until `retain` drops it.
+  pub fn is_same_user(&mut self, pid: u32) -> bool {
+    if pid == 0 {
+      return true;
+    }
+    let (session_id, sid) = (self.session_id, self.sid.as_deref());
+    *self.cache.entry(pid).or_insert_with(|| {
+      let other_session = session_id
+        .is_some_and(|own| process_session_id(pid).is_ok_and(|session_id| session_id != own));
+      let other_user = || sid.is_some_and(|own| process_user_sid(pid).is_ok_and(|sid| sid != own));
+      let same = !other_session && !other_user();
+      if !same {
+        log::info!("[winmix] pid {} belongs to another user", pid);
+      }
+      same
+    })
+  }
+  /// Forget the processes that aren't in `pids`.
+  pub fn retain(&mut self, pids: &[u32]) {
+    self.cache.retain(|pid, _| pids.contains(pid));
+  }
+}
+
+/// The id of the Windows session a process runs in, readable without opening the process.
+pub fn process_session_id(pid: u32) -> Result<u32, Error> {
+  let mut session_id = 0;
+  unsafe { ProcessIdToSessionId(pid, &mut session_id)? };
+  Ok(session_id)
+}
+
+/// The SID of the user a process runs as, like `S-1-5-21-…`.
+pub fn process_user_sid(pid: u32) -> Result<String, Error> {
+  unsafe {
+    let proc = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)?;
+    let mut token = HANDLE::default();
+    let opened = OpenProcessToken(proc, TOKEN_QUERY, &mut token);
+    CloseHandle(proc)?;
+    opened?;
+
+    let sid = token_user_sid(token);
+    CloseHandle(token)?;
+    sid
+  }
+}
+
+unsafe fn token_user_sid(token: HANDLE) -> Result<String, Error> {
+  // the first call only reports the size
+  let mut size = 0;
+  let _ = GetTokenInformation(token, TokenUser, None, 0, &mut size);
+  // u64s keep the buffer aligned for `TOKEN_USER`
+  let mut buffer = vec![0_u64; (size as usize).div_ceil(8)];
+  GetTokenInformation(
+    token,
+    TokenUser,
+    Some(buffer.as_mut_ptr().cast()),
+    size,
+    &mut size,
+  )?;
+  let user = &*(buffer.as_ptr() as *const TOKEN_USER);
+
+  let mut sid = PWSTR::null();
+  ConvertSidToStringSidW(user.User.Sid, &mut sid)?;
+  let result = sid
+    .to_string()
+    .map_err(|_| Error::new(HRESULT::from_win32(0x8007000D), "SID is not valid UTF-16"));
+  LocalFree(HLOCAL(sid.0.cast()));
+  result
+}
+
+// unique to one session of one process, unlike the session identifier
+unsafe fn session_instance_id(control: &IAudioSessionControl2) -> Option<String> {
+  let id = control.GetSessionInstanceIdentifier().ok()?;
+  let result = id.to_string().ok();
+  CoTaskMemFree(Some(id.0 as *const _));
+  result
+}
+
+/// The kind of output an endpoint is, read from `PKEY_AudioEndpoint_FormFactor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormFactor {
+  Speakers,
+  Headphones,
+  Headset,
+  /// Line out, digital outputs, displays, or anything else.
+  Other,
+}
+
 #[allow(non_camel_case_types)]
 #[implement(IAudioSessionNotification)]
 pub struct SessionsClient(SyncSender<()>);
@@ -288,6 +696,19 @@ impl IAudioSessionNotification_Impl for SessionsClient {
   }
 }
 
+#[allow(non_camel_case_types)]
+#[implement(IAudioEndpointVolumeCallback)]
+pub struct MasterClient(Arc<AtomicBool>);
+
+impl IAudioEndpointVolumeCallback_Impl for MasterClient {
+  fn OnNotify(&self, data: *mut AUDIO_VOLUME_NOTIFICATION_DATA) -> windows_core::Result<()> {
+    if let Some(data) = unsafe { data.as_ref() } {
+      self.0.store(data.bMuted.as_bool(), Ordering::Relaxed);
+    }
+    Ok(())
+  }
+}
+
 #[allow(non_camel_case_types)]
 #[implement(IMMNotificationClient)]
 pub struct DeviceClient(SyncSender<()>);
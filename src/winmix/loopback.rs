@@ -0,0 +1,139 @@
+use std::{ptr, slice};
+
+use windows::Win32::{
+  Media::{
+    Audio::{
+      IAudioCaptureClient, IAudioClient, IMMDevice, AUDCLNT_BUFFERFLAGS_SILENT,
+      AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_LOOPBACK, WAVEFORMATEX, WAVEFORMATEXTENSIBLE,
+    },
+    Multimedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, WAVE_FORMAT_IEEE_FLOAT},
+  },
+  System::Com::{CoTaskMemFree, CLSCTX_ALL},
+};
+use windows_result::{Error, HRESULT};
+
+// Defined in the kernel streaming headers, which we don't otherwise need.
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+// Requested buffer duration, in 100ns units.
+const BUFFER_DURATION: i64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy)]
+enum SampleFormat {
+  Float32,
+  Int16,
+}
+
+/// Captures the mixed output of a render device, as it goes to the speaker.
+#[derive(Debug)]
+pub struct LoopbackCapture {
+  device: IMMDevice,
+  client: IAudioClient,
+  capture: IAudioCaptureClient,
+  format: SampleFormat,
+  channels: usize,
+}
+
+impl LoopbackCapture {
+  pub fn new(device: IMMDevice) -> Result<Self, Error> {
+    unsafe {
+      let client: IAudioClient = device.Activate(CLSCTX_ALL, None)?;
+
+      let mix_format = client.GetMixFormat()?;
+      let format = sample_format(mix_format);
+      let channels = (*mix_format).nChannels as usize;
+      let initialized = client.Initialize(
+        AUDCLNT_SHAREMODE_SHARED,
+        AUDCLNT_STREAMFLAGS_LOOPBACK,
+        BUFFER_DURATION,
+        0,
+        mix_format,
+        None,
+      );
+      CoTaskMemFree(Some(mix_format as *const _));
+      initialized?;
+
+      let Some(format) = format else {
+        return Err(Error::new(
+          HRESULT::from_win32(0x80070032),
+          "Unsupported loopback mix format",
+        ));
+      };
+
+      let capture: IAudioCaptureClient = client.GetService()?;
+      client.Start()?;
+
+      Ok(LoopbackCapture {
+        device,
+        client,
+        capture,
+        format,
+        channels,
+      })
+    }
+  }
+
+  pub fn device(&self) -> &IMMDevice {
+    &self.device
+  }
+
+  /// Get the peak sample of the audio rendered since the last call.
+  pub fn get_peak(&self) -> Result<f32, Error> {
+    let mut peak = 0.0_f32;
+    unsafe {
+      while self.capture.GetNextPacketSize()? > 0 {
+        let mut data = ptr::null_mut();
+        let mut frames = 0;
+        let mut flags = 0;
+        self
+          .capture
+          .GetBuffer(&mut data, &mut frames, &mut flags, None, None)?;
+
+        let silent = flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0;
+        if !silent && !data.is_null() {
+          let samples = frames as usize * self.channels;
+          let packet_peak = match self.format {
+            SampleFormat::Float32 => slice::from_raw_parts(data as *const f32, samples)
+              .iter()
+              .fold(0.0_f32, |peak, sample| peak.max(sample.abs())),
+            SampleFormat::Int16 => slice::from_raw_parts(data as *const i16, samples)
+              .iter()
+              .fold(0.0_f32, |peak, &sample| {
+                peak.max((sample as f32 / i16::MAX as f32).abs())
+              }),
+          };
+          peak = peak.max(packet_peak);
+        }
+
+        self.capture.ReleaseBuffer(frames)?;
+      }
+    }
+    Ok(peak.min(1.0))
+  }
+}
+
+impl Drop for LoopbackCapture {
+  fn drop(&mut self) {
+    unsafe {
+      let _ = self.client.Stop();
+    }
+  }
+}
+
+unsafe fn sample_format(format: *const WAVEFORMATEX) -> Option<SampleFormat> {
+  let tag = (*format).wFormatTag;
+  let bits = (*format).wBitsPerSample;
+
+  let is_float = if tag == WAVE_FORMAT_EXTENSIBLE {
+    let extensible = format as *const WAVEFORMATEXTENSIBLE;
+    let sub_format = (*extensible).SubFormat;
+    sub_format == KSDATAFORMAT_SUBTYPE_IEEE_FLOAT
+  } else {
+    tag as u32 == WAVE_FORMAT_IEEE_FLOAT
+  };
+
+  match (is_float, bits) {
+    (true, 32) => Some(SampleFormat::Float32),
+    (false, 16) => Some(SampleFormat::Int16),
+    _ => None,
+  }
+}
@@ -1,15 +1,27 @@
+use std::{thread, time::Duration};
+
 use device::Device;
+use serde::Serialize;
+use windows::core::{Interface, HSTRING};
 use windows::Win32::{
-  Media::Audio::{eMultimedia, eRender, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator},
+  Media::Audio::{
+    eCapture, eCommunications, eConsole, eMultimedia, eRender, EDataFlow, ERole, IMMDevice,
+    IMMDeviceCollection, IMMDeviceEnumerator, IMMEndpoint, MMDeviceEnumerator, DEVICE_STATE_ACTIVE,
+  },
   System::Com::{CoCreateInstance, CoInitialize, CoUninitialize, CLSCTX_ALL},
 };
 use windows_result::{Error, HRESULT};
 
 // WinMix: Change Windows Volume Mixer via Rust
 pub mod device;
+pub mod loopback;
 pub mod session;
 pub mod volume;
 
+// the default device can't be opened for a moment while the audio service restarts
+const DEFAULT_ATTEMPTS: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+
 #[derive(Debug)]
 pub struct WinMix {
   initialized: bool,
@@ -26,29 +38,207 @@ impl WinMix {
       enumerator.GetDefaultAudioEndpoint(eRender, eMultimedia)
     }
   }
-  // Enumerate all audio sessions from all audio endpoints via WASAPI.
-  // pub fn enumerate(&self) -> Result<Vec<Device>, Error> {
-  //   let mut result = Vec::<Device>::new();
-
-  //   unsafe {
-  //     let res: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
-
-  //     let collection: IMMDeviceCollection = res.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)?;
+  /// Like `get_default`, tried again with a growing delay while the audio service restarts.
+  ///
+  /// It sleeps between the attempts, so keep it off the event loop thread.
+  pub fn get_default_retrying<'a>(&'a self) -> Result<Device<'a>, Error> {
+    let device = self.get_default_immdevice_retrying()?;
+    Ok(Device::new(self, device))
+  }
+  /// Like `get_default_immdevice`, see `get_default_retrying`.
+  pub fn get_default_immdevice_retrying(&self) -> Result<IMMDevice, Error> {
+    let mut backoff = DEFAULT_BACKOFF;
+    let mut attempt = 1;
+    loop {
+      let err = match self.get_default_immdevice() {
+        Ok(device) => return Ok(device),
+        Err(err) => err,
+      };
+      if attempt == DEFAULT_ATTEMPTS {
+        return Err(err);
+      }
+      log::warn!(
+        "[winmix] failed to open the default device ({}/{}): {}",
+        attempt,
+        DEFAULT_ATTEMPTS,
+        err
+      );
+      thread::sleep(backoff);
+      backoff *= 2;
+      attempt += 1;
+    }
+  }
+  /// Count the active render endpoints, without opening them.
+  pub fn get_device_count(&self) -> Result<u32, Error> {
+    unsafe {
+      self
+        .get_device_enumerator()?
+        .EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)?
+        .GetCount()
+    }
+  }
+  /// Whether there is a default output device at all.
+  pub fn has_default_device(&self) -> bool {
+    self.get_default_immdevice().is_ok()
+  }
+  /// Get the default recording device, the one calls use when it differs from the multimedia one.
+  pub fn get_default_capture<'a>(&'a self) -> Result<Device<'a>, Error> {
+    let enumerator = self.get_device_enumerator()?;
+    let device = Device::new(self, unsafe {
+      enumerator.GetDefaultAudioEndpoint(eCapture, eMultimedia)?
+    });
+    match device.get_id().and_then(|id| self.get_device_role(&id)) {
+      Ok(role) if role.is_communications => return Ok(device),
+      Ok(_) => {}
+      Err(err) => {
+        log::warn!(
+          "[winmix] failed to read the role of the default microphone: {}",
+          err
+        );
+        return Ok(device);
+      }
+    }
+    // there may be no communications default, keep the multimedia one then
+    match unsafe { enumerator.GetDefaultAudioEndpoint(eCapture, eCommunications) } {
+      Ok(communications) => Ok(Device::new(self, communications)),
+      Err(_) => Ok(device),
+    }
+  }
+  /// Which roles the endpoint with the given id is the default for, within its direction.
+  pub fn get_device_role(&self, id: &str) -> Result<DeviceRole, Error> {
+    let enumerator = self.get_device_enumerator()?;
+    let flow = unsafe {
+      enumerator
+        .GetDevice(&HSTRING::from(id))?
+        .cast::<IMMEndpoint>()?
+        .GetDataFlow()?
+    };
+    Ok(self.get_roles(&enumerator, flow).of(id))
+  }
+  // the default endpoint ids of a direction, fetched once for all of its devices
+  fn get_roles(&self, enumerator: &IMMDeviceEnumerator, flow: EDataFlow) -> DefaultIds {
+    // there may be no default endpoint, e.g. without any microphone
+    let default_id = |role: ERole| unsafe {
+      enumerator
+        .GetDefaultAudioEndpoint(flow, role)
+        .ok()
+        .and_then(|device| Device::new(self, device).get_id().ok())
+    };
+    DefaultIds {
+      console: default_id(eConsole),
+      multimedia: default_id(eMultimedia),
+      communications: default_id(eCommunications),
+    }
+  }
+  /// Get an endpoint by the id from `Device::get_id`.
+  pub fn get_device<'a>(&'a self, id: &str) -> Result<Device<'a>, Error> {
+    let device = unsafe {
+      self
+        .get_device_enumerator()?
+        .GetDevice(&HSTRING::from(id))?
+    };
+    Ok(Device::new(self, device))
+  }
+  /// List the active render and capture endpoints.
+  pub fn enumerate(&self) -> Result<Vec<DeviceInfo>, Error> {
+    let mut result = self.enumerate_flow(eRender)?;
+    result.extend(self.enumerate_flow(eCapture)?);
+    Ok(result)
+  }
+  fn enumerate_flow(&self, flow: EDataFlow) -> Result<Vec<DeviceInfo>, Error> {
+    unsafe {
+      let enumerator = self.get_device_enumerator()?;
+      let collection: IMMDeviceCollection =
+        enumerator.EnumAudioEndpoints(flow, DEVICE_STATE_ACTIVE)?;
 
-  //     let device_count = collection.GetCount()?;
+      let roles = self.get_roles(&enumerator, flow);
 
-  //     for device_id in 0..device_count {
-  //       let device = collection.Item(device_id)?;
-  //       result.push(Device::from_immdevice(device)?);
-  //     }
-  //   }
-  //   Ok(result)
-  // }
+      let device_count = collection.GetCount()?;
+      let mut result = Vec::new();
+      for device_id in 0..device_count {
+        let device = Device::new(self, collection.Item(device_id)?);
+        let id = device.get_id()?;
+        let role = roles.of(&id);
+        result.push(DeviceInfo {
+          name: device.get_name().unwrap_or_default(),
+          capture: flow == eCapture,
+          default: role.is_multimedia,
+          role,
+          id,
+        });
+      }
+      Ok(result)
+    }
+  }
   pub fn get_device_enumerator(&self) -> Result<IMMDeviceEnumerator, Error> {
     unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }
   }
 }
 
+/// An audio endpoint, as listed by `WinMix::enumerate`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+  pub id: String,
+  pub name: String,
+  /// A recording device rather than an output.
+  pub capture: bool,
+  /// The default endpoint for its direction.
+  pub default: bool,
+  pub role: DeviceRole,
+}
+
+/// The roles a device is the default endpoint for, see `WinMix::get_device_role`.
+///
+/// One device can hold any of them, e.g. a headset is often only the communications device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceRole {
+  /// Games and system sounds.
+  pub is_console: bool,
+  /// Music and movies, what Windows calls the default device.
+  pub is_multimedia: bool,
+  /// Voice chat and calls.
+  pub is_communications: bool,
+}
+
+struct DefaultIds {
+  console: Option<String>,
+  multimedia: Option<String>,
+  communications: Option<String>,
+}
+
+impl DefaultIds {
+  fn of(&self, id: &str) -> DeviceRole {
+    let is = |default: &Option<String>| default.as_deref() == Some(id);
+    DeviceRole {
+      is_console: is(&self.console),
+      is_multimedia: is(&self.multimedia),
+      is_communications: is(&self.communications),
+    }
+  }
+}
+
+/// The state of a device and its sessions, as printed by `--list-sessions`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MixerInfo {
+  pub id: String,
+  pub name: String,
+  pub master_volume: Option<f32>,
+  pub master_muted: Option<bool>,
+  pub sessions: Vec<SessionInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+  pub pid: u32,
+  pub name: String,
+  pub path: String,
+  pub volume: Option<f32>,
+  /// `volume` in decibels, `None` when silent or unreadable.
+  pub volume_db: Option<f32>,
+  pub muted: Option<bool>,
+  pub peak: Option<f32>,
+}
+
 impl Default for WinMix {
   /// Create a default instance of WinMix.
   fn default() -> WinMix {
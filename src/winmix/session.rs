@@ -1,5 +1,7 @@
 use std::{hash::Hash, path::PathBuf};
 
+use windows_result::Error;
+
 use super::volume::SessionVolume;
 
 #[derive(Debug, Clone)]
@@ -29,6 +31,16 @@ impl<'a> Session<'a> {
       volume,
     }
   }
+  /// The session identifier, like `{endpoint}|{app}|{instance}`.
+  pub fn get_session_id(&self) -> Result<String, Error> {
+    self.volume.get_session_identifier()
+  }
+  /// The endpoint and app parts of the session identifier, which survive a restart of the app
+  /// unlike the pid.
+  pub fn get_stable_id(&self) -> Result<String, Error> {
+    let id = self.get_session_id()?;
+    Ok(id.split('|').take(2).collect::<Vec<_>>().join("|"))
+  }
 }
 
 impl<'a> Hash for Session<'a> {
@@ -43,4 +55,4 @@ impl<'a> PartialEq for Session<'a> {
   }
 }
 
-impl<'a> Eq for Session<'a> {}
\ No newline at end of file
+impl<'a> Eq for Session<'a> {}
@@ -1,13 +1,21 @@
-use std::{marker::PhantomData, ptr};
+use std::{
+  marker::PhantomData,
+  ptr,
+  thread::{self, JoinHandle},
+  time::Duration,
+};
 
 use windows::{
   core::Interface,
-  Win32::Media::Audio::{
-    Endpoints::{IAudioEndpointVolume, IAudioMeterInformation},
-    ISimpleAudioVolume,
+  Win32::{
+    Media::Audio::{
+      Endpoints::{IAudioEndpointVolume, IAudioMeterInformation},
+      IAudioSessionControl2, ISimpleAudioVolume,
+    },
+    System::Com::CoTaskMemFree,
   },
 };
-use windows_result::Error;
+use windows_result::{Error, HRESULT};
 
 #[derive(Debug)]
 pub struct EndpointVolume<'a> {
@@ -38,6 +46,7 @@ impl<'a> EndpointVolume<'a> {
   /// # Safety
   /// This function calls [ISimpleAudioVolume.SetMasterVolume](https://learn.microsoft.com/en-us/windows/win32/api/audioclient/nf-audioclient-isimpleaudiovolume-setmastervolume) which is unsafe.
   pub fn set_volume(&self, level: f32) -> Result<(), Error> {
+    check_level(level)?;
     unsafe {
       self
         .audio_endpoint_volume
@@ -45,6 +54,78 @@ impl<'a> EndpointVolume<'a> {
     }
   }
 
+  /// Like `set_volume`, but clamps `level` into range instead of rejecting it.
+  pub fn set_volume_clamped(&self, level: f32) -> Result<(), Error> {
+    self.set_volume(level.clamp(0.0, 1.0))
+  }
+
+  /// The volume in decibels.
+  pub fn get_volume_db(&self) -> Result<f32, Error> {
+    unsafe { self.audio_endpoint_volume.GetMasterVolumeLevel() }
+  }
+
+  /// Set the volume in decibels, within `get_volume_range`.
+  pub fn set_volume_db(&self, level_db: f32) -> Result<(), Error> {
+    unsafe {
+      self
+        .audio_endpoint_volume
+        .SetMasterVolumeLevel(level_db, ptr::null())
+    }
+  }
+
+  /// The minimum, maximum and increment of the volume in decibels.
+  pub fn get_volume_range(&self) -> Result<(f32, f32, f32), Error> {
+    let (mut min, mut max, mut increment) = (0.0, 0.0, 0.0);
+    unsafe {
+      self
+        .audio_endpoint_volume
+        .GetVolumeRange(&mut min, &mut max, &mut increment)?
+    };
+    Ok((min, max, increment))
+  }
+
+  /// The current step and the number of steps.
+  pub fn get_volume_step_info(&self) -> Result<(u32, u32), Error> {
+    let (mut step, mut count) = (0, 0);
+    unsafe {
+      self
+        .audio_endpoint_volume
+        .GetVolumeStepInfo(&mut step, &mut count)?
+    };
+    Ok((step, count))
+  }
+
+  /// Move the volume by `steps` hardware steps, up when positive and down when negative.
+  ///
+  /// Stops at the first and last step. Without step info, each step is one increment of
+  /// `get_volume_range` instead.
+  pub fn increment(&self, steps: i32) -> Result<(), Error> {
+    let Ok((step, count)) = self.get_volume_step_info() else {
+      let (min, max, increment) = self.get_volume_range()?;
+      // some drivers report no increment, use a hundredth of the range then
+      let increment = if increment > 0.0 {
+        increment
+      } else {
+        (max - min) / 100.0
+      };
+      let level_db = self.get_volume_db()? + increment * steps as f32;
+      return self.set_volume_db(level_db.clamp(min, max));
+    };
+
+    let last = count.saturating_sub(1) as i64;
+    let moves = (step as i64 + steps as i64).clamp(0, last) - step as i64;
+    for _ in 0..moves.abs() {
+      unsafe {
+        if moves > 0 {
+          self.audio_endpoint_volume.VolumeStepUp(ptr::null())?;
+        } else {
+          self.audio_endpoint_volume.VolumeStepDown(ptr::null())?;
+        }
+      }
+    }
+    Ok(())
+  }
+
   /// Check if this session is muted.
   ///
   /// # Safety
@@ -69,6 +150,24 @@ impl<'a> EndpointVolume<'a> {
   }
 }
 
+/// The peak meter of a whole endpoint, every session mixed together.
+#[derive(Debug, Clone)]
+pub struct EndpointMeter {
+  audio_meter_information: IAudioMeterInformation,
+}
+
+impl EndpointMeter {
+  pub fn new(audio_meter_information: IAudioMeterInformation) -> Self {
+    EndpointMeter {
+      audio_meter_information,
+    }
+  }
+
+  pub fn get_peak(&self) -> Result<f32, Error> {
+    unsafe { self.audio_meter_information.GetPeakValue() }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct SessionVolume<'a> {
   simple_audio_volume: ISimpleAudioVolume,
@@ -101,9 +200,15 @@ impl<'a> SessionVolume<'a> {
   /// # Safety
   /// This function calls [ISimpleAudioVolume.SetMasterVolume](https://learn.microsoft.com/en-us/windows/win32/api/audioclient/nf-audioclient-isimpleaudiovolume-setmastervolume) which is unsafe.
   pub fn set_volume(&self, level: f32) -> Result<(), Error> {
+    check_level(level)?;
     unsafe { self.simple_audio_volume.SetMasterVolume(level, ptr::null()) }
   }
 
+  /// Like `set_volume`, but clamps `level` into range instead of rejecting it.
+  pub fn set_volume_clamped(&self, level: f32) -> Result<(), Error> {
+    self.set_volume(level.clamp(0.0, 1.0))
+  }
+
   /// Check if this session is muted.
   ///
   /// # Safety
@@ -130,4 +235,172 @@ impl<'a> SessionVolume<'a> {
   pub fn get_peak(&self) -> Result<f32, Error> {
     unsafe { self.audio_meter_information.GetPeakValue() }
   }
+
+  /// The identifier of the session, from `IAudioSessionControl2::GetSessionIdentifier`.
+  pub fn get_session_identifier(&self) -> Result<String, Error> {
+    unsafe {
+      let control: IAudioSessionControl2 = self.simple_audio_volume.cast()?;
+      let id = control.GetSessionIdentifier()?;
+      let result = id.to_string();
+      CoTaskMemFree(Some(id.0 as *const _));
+      result.map_err(|_| {
+        Error::new(
+          HRESULT::from_win32(0x8007000D),
+          "Session identifier is not valid UTF-16",
+        )
+      })
+    }
+  }
+
+  /// Fade to `level` in `steps` even steps on a background thread, sleeping `interval` between them.
+  ///
+  /// The fade keeps going if the session is dropped before it's done.
+  pub fn try_set_volume_smooth(
+    &self,
+    level: f32,
+    steps: u32,
+    interval: Duration,
+  ) -> JoinHandle<Result<(), Error>> {
+    let volume = AgileVolume(self.simple_audio_volume.clone());
+    thread::spawn(move || {
+      check_level(level)?;
+      // the interface is free-threaded, but COM must be up on this thread
+      let _winmix = super::WinMix::default();
+      // move the whole wrapper in, not just the interface inside it
+      let volume = volume;
+      let start = unsafe { volume.0.GetMasterVolume()? };
+      let steps = steps.max(1);
+      for step in 1..=steps {
+        let value = start + (level - start) * step as f32 / steps as f32;
+        unsafe { volume.0.SetMasterVolume(value, ptr::null())? };
+        if step < steps {
+          thread::sleep(interval);
+        }
+      }
+      Ok(())
+    })
+  }
+}
+
+/// The volume of a session or a whole endpoint, so the logic driving it can be tested without a
+/// device.
+pub trait VolumeControl {
+  fn get_volume(&self) -> Result<f32, Error>;
+  /// Set the volume, clamped between `0.0` and `1.0`.
+  fn set_volume_clamped(&self, level: f32) -> Result<(), Error>;
+}
+
+impl VolumeControl for SessionVolume<'_> {
+  fn get_volume(&self) -> Result<f32, Error> {
+    SessionVolume::get_volume(self)
+  }
+  fn set_volume_clamped(&self, level: f32) -> Result<(), Error> {
+    SessionVolume::set_volume_clamped(self, level)
+  }
+}
+
+impl VolumeControl for EndpointVolume<'_> {
+  fn get_volume(&self) -> Result<f32, Error> {
+    EndpointVolume::get_volume(self)
+  }
+  fn set_volume_clamped(&self, level: f32) -> Result<(), Error> {
+    EndpointVolume::set_volume_clamped(self, level)
+  }
+}
+
+/// The mute of a session or a whole endpoint.
+pub trait MuteControl {
+  fn get_mute(&self) -> Result<bool, Error>;
+  fn set_mute(&self, mute: bool) -> Result<(), Error>;
+  /// Flip the mute, returning whether it is muted now.
+  fn set_mute_toggle(&self) -> Result<bool, Error> {
+    let muted = !self.get_mute()?;
+    self.set_mute(muted)?;
+    Ok(muted)
+  }
+}
+
+impl MuteControl for SessionVolume<'_> {
+  fn get_mute(&self) -> Result<bool, Error> {
+    SessionVolume::get_mute(self)
+  }
+  fn set_mute(&self, mute: bool) -> Result<(), Error> {
+    SessionVolume::set_mute(self, mute)
+  }
+}
+
+impl MuteControl for EndpointVolume<'_> {
+  fn get_mute(&self) -> Result<bool, Error> {
+    EndpointVolume::get_mute(self)
+  }
+  fn set_mute(&self, mute: bool) -> Result<(), Error> {
+    EndpointVolume::set_mute(self, mute)
+  }
+}
+
+// audio session interfaces can be called from any thread
+struct AgileVolume(ISimpleAudioVolume);
+unsafe impl Send for AgileVolume {}
+
+/// A scalar volume in decibels, `None` for silence, which has no finite value.
+///
+/// This is the amplitude ratio, sessions have no volume curve of their own to read.
+pub fn scalar_to_db(level: f32) -> Option<f32> {
+  (level > 0.0).then(|| 20.0 * level.log10())
+}
+
+fn check_level(level: f32) -> Result<(), Error> {
+  if (0.0..=1.0).contains(&level) {
+    return Ok(());
+  }
+  Err(Error::new(
+    HRESULT::from_win32(0x80070057),
+    format!("Volume {} is out of range [0.0, 1.0]", level),
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+
+  use super::*;
+
+  // a mute that fails to change while `locked` is set
+  #[derive(Default)]
+  struct FakeMute {
+    muted: Cell<bool>,
+    locked: bool,
+  }
+
+  impl MuteControl for FakeMute {
+    fn get_mute(&self) -> Result<bool, Error> {
+      Ok(self.muted.get())
+    }
+    fn set_mute(&self, mute: bool) -> Result<(), Error> {
+      if self.locked {
+        return Err(Error::new(HRESULT::from_win32(0x80070005), "access denied"));
+      }
+      self.muted.set(mute);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn toggle_flips_the_mute() {
+    let mute = FakeMute::default();
+    assert!(mute.set_mute_toggle().unwrap());
+    assert!(mute.muted.get());
+    assert!(!mute.set_mute_toggle().unwrap());
+    assert!(!mute.muted.get());
+  }
+
+  #[test]
+  fn toggle_keeps_the_mute_when_it_fails() {
+    let mute = FakeMute {
+      locked: true,
+      ..Default::default()
+    };
+    assert!(mute.set_mute_toggle().is_err());
+    assert!(!mute.muted.get());
+  }
 }
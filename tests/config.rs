@@ -0,0 +1,130 @@
+use sound_priority::config::{AudioCategory, Config, ConfigIssue, PartialConfig};
+
+const SPEAKERS_ID: &str = "{0.0.0.00000000}.{1a2b3c4d-0000-0000-0000-000000000001}";
+const SPEAKERS: &str = "Speakers (Realtek High Definition Audio)";
+
+fn reduce_to(volume: f32) -> PartialConfig {
+  PartialConfig {
+    reduce_volume: Some(volume),
+    ..Default::default()
+  }
+}
+
+#[test]
+fn device_id_wins_over_name() {
+  let mut config = Config::default();
+  config.devices.insert("Realtek".to_string(), reduce_to(0.3));
+  config
+    .devices
+    .insert(SPEAKERS_ID.to_string(), reduce_to(0.2));
+
+  let (resolved, section) = config.for_device(SPEAKERS_ID, SPEAKERS);
+  assert_eq!(section, Some(SPEAKERS_ID));
+  assert_eq!(resolved.reduce_volume, 0.2);
+}
+
+#[test]
+fn device_name_matches_a_part() {
+  let mut config = Config::default();
+  config.devices.insert("Realtek".to_string(), reduce_to(0.3));
+
+  let (resolved, section) = config.for_device(SPEAKERS_ID, SPEAKERS);
+  assert_eq!(section, Some("Realtek"));
+  assert_eq!(resolved.reduce_volume, 0.3);
+  // fields without an override keep the global value
+  assert_eq!(resolved.sensitivity, config.sensitivity);
+}
+
+#[test]
+fn other_devices_keep_the_global_config() {
+  let mut config = Config::default();
+  config
+    .devices
+    .insert("Headphones".to_string(), reduce_to(0.3));
+  config.devices.insert(String::new(), reduce_to(0.1));
+
+  let (resolved, section) = config.for_device(SPEAKERS_ID, SPEAKERS);
+  assert_eq!(section, None);
+  assert_eq!(resolved.reduce_volume, config.reduce_volume);
+}
+
+#[test]
+fn empty_file_is_the_default() {
+  let config: Config = serde_json::from_str("{}").unwrap();
+  assert_eq!(
+    serde_json::to_value(config).unwrap(),
+    serde_json::to_value(Config::default()).unwrap()
+  );
+}
+
+fn issue_ids(config: &Config) -> Vec<&'static str> {
+  config.issues().iter().map(ConfigIssue::id).collect()
+}
+
+fn with_target() -> Config {
+  let mut config = Config::default();
+  config.targets = vec!["spotify".to_string()];
+  config
+}
+
+#[test]
+fn working_config_has_no_issues() {
+  assert!(with_target().issues().is_empty());
+}
+
+#[test]
+fn no_targets() {
+  let mut config = Config::default();
+  assert_eq!(issue_ids(&config), ["no_targets"]);
+
+  config.apply_to_all_sessions = true;
+  assert!(config.issues().is_empty());
+}
+
+#[test]
+fn reduce_above_restore() {
+  let mut config = with_target();
+  config.resotre_volume = 0.4;
+  config.reduce_volume = 0.6;
+  assert_eq!(issue_ids(&config), ["reduce_above_restore"]);
+}
+
+#[test]
+fn zero_sensitivity() {
+  let mut config = with_target();
+  config.sensitivity = 0.0;
+  assert_eq!(issue_ids(&config), ["zero_sensitivity"]);
+}
+
+#[test]
+fn normalize_keeps_a_target_that_is_excluded() {
+  let mut config = with_target();
+  config.exclude = vec!["Spotify ".to_string(), "discord".to_string()];
+  config.normalize();
+  assert_eq!(config.targets, ["spotify"]);
+  assert_eq!(config.exclude, ["discord"]);
+}
+
+#[test]
+fn exclude_self_is_never_a_target() {
+  let exe = std::env::current_exe().unwrap();
+  let own = exe.file_stem().unwrap().to_string_lossy().to_string();
+
+  let mut config = Config::default();
+  config.targets = vec![own.clone()];
+  assert!(!config.is_target(&own));
+  config.apply_to_all_sessions = true;
+  assert!(!config.is_target(&own));
+
+  config.exclude_self = false;
+  assert!(config.is_target(&own));
+}
+
+#[test]
+fn category_needs_the_whole_name() {
+  assert_eq!(AudioCategory::of("Discord"), AudioCategory::Chat);
+  assert_eq!(AudioCategory::of("vlc"), AudioCategory::Media);
+  assert_eq!(AudioCategory::of("steamwebhelper"), AudioCategory::Other);
+  assert_eq!(AudioCategory::of("zoomit"), AudioCategory::Other);
+  assert_eq!(AudioCategory::of("$system"), AudioCategory::System);
+}